@@ -14,6 +14,22 @@ pub struct Config {
     #[structopt(long, default_value = "localhost:21150")]
     pub daemon_address: String,
 
+    /// Address of the daemon's WebSocket server, to restart mining as soon as the tail changes
+    #[structopt(long)]
+    pub daemon_ws_address: Option<String>,
+
+    /// Connect to the daemon over TLS, verifying its certificate through the web PKI
+    #[structopt(long)]
+    pub daemon_ssl: bool,
+
+    /// Connect to the daemon over TLS, only accepting a certificate with this SHA-256 fingerprint
+    #[structopt(long)]
+    pub daemon_ssl_fingerprint: Option<String>,
+
+    /// Send this token to the daemon in an `Authorization: Bearer <token>` header
+    #[structopt(long)]
+    pub daemon_access_token: Option<String>,
+
     #[structopt(long)]
     pub miner_address: String,
 }
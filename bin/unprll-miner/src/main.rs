@@ -28,7 +28,8 @@ fn main() {
         )
     );
 
-    match MinerStateMachine::new(config) {
+    // Connecting to the daemon over TLS spawns a tunnel on the runtime
+    match runtime.enter(|| MinerStateMachine::new(config)) {
         Ok(miner_state_machine) => {
             let fut = miner_state_machine.into_future();
             if let Err(err) = runtime.block_on(fut) {
@@ -1,100 +1,85 @@
 use std::{
-    convert::TryFrom,
     future::Future,
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use jsonrpsee::{raw::RawClient, transport::http::HttpTransportClient};
+use futures::StreamExt;
+use jsonrpsee::raw::RawClient;
 
-use coin_specific::{emission::EmissionCurve, Unprll};
-use common::{Block, TXExtra, TXIn, TXOut, TXOutTarget};
-use crypto::{Hash256, KeyPair};
-use rpc::api_definitions::DaemonRPC;
-use transaction_util::{address::Address, Derivation};
+use coin_specific::Unprll;
+use crypto::Hash256;
+use rpc::{
+    api_definitions::{DaemonRPC, NewBlockNotification},
+    ClientTransport, HexBlob, SubscriptionEvent,
+};
+use transaction_util::address::Address;
 
 use crate::config::Config;
 use crate::miner::Miner;
 
 pub struct MinerStateMachine {
     check_interval: Duration,
+    daemon_access_token: Option<String>,
+    daemon_ws_url: Option<String>,
     last_checked: Instant,
-    last_prev_id: Option<String>,
+    last_prev_id: Option<Hash256>,
     miner: Miner,
-    miner_address: Address,
-    rpc_client: RawClient<HttpTransportClient>,
+    miner_address: String,
+    rpc_client: RawClient<ClientTransport>,
+    tail_changed: Arc<AtomicBool>,
 }
 
 impl MinerStateMachine {
     pub fn new(config: Config) -> Result<Self, anyhow::Error> {
+        // Make sure the address is valid before asking the daemon for blocks paying it
+        Address::from_address_string::<Unprll>(config.miner_address.as_str())?;
+
+        let daemon_transport = rpc::client_transport(
+            &config.daemon_address,
+            config.daemon_ssl,
+            config.daemon_ssl_fingerprint.as_deref(),
+            config.daemon_access_token.as_deref(),
+        )?;
+        let daemon_ws_url = config
+            .daemon_ws_address
+            .as_ref()
+            .map(|address| {
+                rpc::client_address(
+                    address,
+                    config.daemon_ssl,
+                    config.daemon_ssl_fingerprint.as_deref(),
+                )
+            })
+            .transpose()?
+            .map(|address| format!("ws://{}", address));
+
         Ok(MinerStateMachine {
             check_interval: Duration::from_secs(config.check_interval),
+            daemon_access_token: config.daemon_access_token,
+            daemon_ws_url,
             last_checked: Instant::now(),
             last_prev_id: None,
             miner: Miner::new(),
-            miner_address: Address::from_address_string::<Unprll>(config.miner_address.as_str())?,
-            rpc_client: RawClient::new(HttpTransportClient::new(&format!(
-                "http://{}",
-                config.daemon_address
-            ))),
+            miner_address: config.miner_address,
+            rpc_client: RawClient::new(daemon_transport),
+            tail_changed: Arc::new(AtomicBool::new(false)),
         })
     }
 
-    fn construct_block_template(&self, current_height: u64, prev_id: Hash256) -> Block {
-        let mut block = Block::default();
-
-        // Header
-        block.header.major_version = 9;
-        block.header.minor_version = 9;
-        block.header.timestamp = {
-            let mut t = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            t = t % 600 + 300;
-            t
-        };
-        block.header.prev_id = prev_id;
-        block.header.miner_specific = self.miner_address.spend_public_key;
-
-        // Miner transaction
-        block
-            .miner_tx
-            .prefix
-            .inputs
-            .push(TXIn::Gen(current_height + 1));
-
-        // This code is similar to transaction_util::construct_tx but is
-        // much simpler since there are fewer cases to deal with and the
-        // RingCT signature is unnecessary
-
-        // Generate a random secret key for this output
-        let random_scalar = KeyPair::generate().secret_key;
-        // Set the transaction public key
-        let tx_pub_key = &random_scalar * &crypto::ecc::BASEPOINT_TABLE;
-
-        // Generate the transaction derivation and hence the keypair
-        let tx_target_keypair =
-            Derivation::from(&random_scalar, &self.miner_address.view_public_key)
-                .unwrap()
-                .to_keypair(0, self.miner_address.spend_public_key);
-
-        block.miner_tx.prefix.outputs.push(TXOut {
-            amount: Unprll.get_block_reward(block.header.major_version),
-            target: TXOutTarget::ToKey {
-                key: tx_target_keypair.public_key,
-            },
-        });
-        block
-            .miner_tx
-            .prefix
-            .extra
-            .push(TXExtra::TxPublicKey(tx_pub_key));
-
-        block
-    }
-
     pub fn into_future(mut self) -> impl Future<Output = Result<(), anyhow::Error>> {
         async move {
+            if let Some(daemon_ws_url) = self.daemon_ws_url.take() {
+                tokio::spawn(watch_tail(
+                    daemon_ws_url,
+                    self.daemon_access_token.clone(),
+                    self.tail_changed.clone(),
+                ));
+            }
+
             loop {
                 // Check if we need to check the daemon for a new chain tail
                 let stats = DaemonRPC::get_stats(&mut self.rpc_client).await?;
@@ -103,7 +88,7 @@ impl MinerStateMachine {
                 // Check if the tail changed
                 let reset = if let Some(last_prev_id) = &self.last_prev_id {
                     // Tail has changed if not equal, reset
-                    *last_prev_id != stats.tail.1
+                    *last_prev_id != *stats.tail.1
                 } else {
                     // Fresh start, reset anyway
                     log::info!("Starting miner...");
@@ -113,26 +98,29 @@ impl MinerStateMachine {
                 if reset {
                     log::info!("New block was added to the chain. Resetting miner...");
 
-                    // Create a new block template and reset the miner
-                    let (height, prev_id) = stats.tail;
-                    self.miner.set_block(Some(self.construct_block_template(
-                        height,
-                        Hash256::try_from(prev_id.as_str()).unwrap(),
-                    )));
-                    self.miner.set_difficulty(stats.difficulty.into());
+                    // Get a new block template and reset the miner
+                    let template = DaemonRPC::get_block_template(
+                        &mut self.rpc_client,
+                        self.miner_address.clone(),
+                        0,
+                    )
+                    .await?;
+                    self.miner.set_block(Some(template.block.into_inner()));
+                    self.miner.set_difficulty(template.difficulty.parse()?);
 
                     // Update our last seen tail
-                    self.last_prev_id = Some(prev_id);
+                    self.last_prev_id = Some(template.prev_id.into_inner());
                 }
 
                 while self.last_checked.elapsed() < self.check_interval {
+                    if self.tail_changed.swap(false, Ordering::Relaxed) {
+                        break;
+                    }
                     if self.miner.run_pow_step() {
                         log::info!("Block found!");
                         DaemonRPC::submit_block(
                             &mut self.rpc_client,
-                            hex::encode(
-                                bincode::serialize(&self.miner.take_block().unwrap()).unwrap(),
-                            ),
+                            HexBlob(self.miner.take_block().unwrap()),
                         )
                         .await?;
                         break;
@@ -142,3 +130,27 @@ impl MinerStateMachine {
         }
     }
 }
+
+/// Flags the tail as changed whenever the daemon announces a new block, so the miner doesn't
+/// have to wait for the next check to move on to it
+///
+/// Blocks announced while not subscribed are picked up by the regular checks
+async fn watch_tail(
+    daemon_ws_url: String,
+    daemon_access_token: Option<String>,
+    tail_changed: Arc<AtomicBool>,
+) {
+    let events = rpc::subscribe_with_retry::<NewBlockNotification>(
+        daemon_ws_url,
+        "subscribe_new_blocks",
+        daemon_access_token,
+    );
+    futures::pin_mut!(events);
+
+    while let Some(event) = events.next().await {
+        if let SubscriptionEvent::Notification(notification) = event {
+            log::debug!("New block at height {}", notification.height);
+            tail_changed.store(true, Ordering::Relaxed);
+        }
+    }
+}
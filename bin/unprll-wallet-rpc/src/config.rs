@@ -1,3 +1,5 @@
+use std::{net::IpAddr, path::PathBuf};
+
 use structopt::StructOpt;
 
 use bin_common::Config as BinCommonConfig;
@@ -6,15 +8,51 @@ use bin_common::Config as BinCommonConfig;
 #[structopt(rename_all = "kebab-case", name = "Unprll")]
 pub struct Config {
     // RPC
+    #[structopt(long, default_value = "127.0.0.1")]
+    pub rpc_bind_address: IpAddr,
+
     #[structopt(long)]
     pub rpc_bind_port: u16,
 
+    /// Require clients to send this token in an `Authorization: Bearer <token>` header
+    #[structopt(long)]
+    pub rpc_access_token: Option<String>,
+
+    /// Serve the RPC server over TLS
+    #[structopt(long)]
+    pub rpc_ssl: bool,
+
+    /// Path to the PEM encoded TLS certificate. Enables TLS, generating a self-signed
+    /// certificate if neither the certificate nor the private key exist
+    #[structopt(long)]
+    pub rpc_ssl_certificate: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key of the TLS certificate
+    #[structopt(long)]
+    pub rpc_ssl_private_key: Option<PathBuf>,
+
     #[structopt(long)]
-    pub wallet_dir: std::path::PathBuf,
+    pub wallet_dir: PathBuf,
 
     #[structopt(long, default_value = "localhost:21150")]
     pub daemon_address: String,
 
+    /// Address of the daemon's WebSocket server, to refresh wallets as soon as blocks arrive
+    #[structopt(long)]
+    pub daemon_ws_address: Option<String>,
+
+    /// Connect to the daemon over TLS, verifying its certificate through the web PKI
+    #[structopt(long)]
+    pub daemon_ssl: bool,
+
+    /// Connect to the daemon over TLS, only accepting a certificate with this SHA-256 fingerprint
+    #[structopt(long)]
+    pub daemon_ssl_fingerprint: Option<String>,
+
+    /// Send this token to the daemon in an `Authorization: Bearer <token>` header
+    #[structopt(long)]
+    pub daemon_access_token: Option<String>,
+
     #[structopt(flatten)]
     pub bin_common_config: BinCommonConfig,
 }
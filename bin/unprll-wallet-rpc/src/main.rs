@@ -1,7 +1,8 @@
-use std::sync::{Arc, RwLock};
+use std::{net::SocketAddr, sync::Arc};
 
-use jsonrpsee::{raw::RawServer, transport::http::HttpTransportServer};
+use jsonrpsee::raw::RawServer;
 use structopt::StructOpt;
+use tokio::sync::RwLock;
 
 pub mod api_definitions;
 mod config;
@@ -16,17 +17,60 @@ use wallet_store::WalletStore;
 async fn main() {
     let config = Config::from_args();
 
-    let addr = format!("127.0.0.1:{}", config.rpc_bind_port)
-        .parse()
-        .unwrap();
+    let addr = SocketAddr::new(config.rpc_bind_address, config.rpc_bind_port);
     bin_common::logger::init(&config.bin_common_config, "unprll-wallet-rpc").unwrap();
 
-    let transport_server = HttpTransportServer::bind(&addr).await.unwrap();
+    let tls_config = if config.rpc_ssl || config.rpc_ssl_certificate.is_some() {
+        Some(
+            rpc::load_server_config(
+                config.rpc_ssl_certificate.as_deref(),
+                config.rpc_ssl_private_key.as_deref(),
+            )
+            .unwrap(),
+        )
+    } else {
+        None
+    };
+
+    let transport_server = rpc::bind_transport(&addr, config.rpc_access_token.clone(), tls_config)
+        .await
+        .unwrap();
     let server = RawServer::new(transport_server);
 
-    let wallet_rpc_server =
-        WalletRPCServer::new(server, Arc::from(RwLock::from(WalletStore::new(config))));
+    let daemon_ws_url = config
+        .daemon_ws_address
+        .as_ref()
+        .map(|address| {
+            rpc::client_address(
+                address,
+                config.daemon_ssl,
+                config.daemon_ssl_fingerprint.as_deref(),
+            )
+        })
+        .transpose()
+        .unwrap()
+        .map(|address| format!("ws://{}", address));
+
+    let daemon_access_token = config.daemon_access_token.clone();
+    let wallet_store = WalletStore::new(config).unwrap();
+    let daemon_transport = wallet_store.daemon_transport();
+    let wallet_store = Arc::new(RwLock::new(wallet_store));
+    let wallet_rpc_server = WalletRPCServer::new(server, wallet_store.clone());
 
     log::info!("RPC server listening on {}", addr);
-    wallet_rpc_server.run().await;
+    match daemon_ws_url {
+        Some(daemon_ws_url) => {
+            futures::future::join(
+                wallet_rpc_server.run(),
+                wallet_store::refresh_on_new_blocks(
+                    wallet_store,
+                    daemon_transport,
+                    daemon_ws_url,
+                    daemon_access_token,
+                ),
+            )
+            .await;
+        }
+        None => wallet_rpc_server.run().await,
+    }
 }
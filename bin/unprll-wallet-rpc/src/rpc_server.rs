@@ -2,6 +2,7 @@ use std::sync::{Arc, RwLock};
 
 use anyhow::Context;
 use jsonrpsee::{common::Error, raw::RawServer, transport::TransportServer};
+use tokio::sync::RwLock as AsyncRwLock;
 
 use crypto::KeyPair;
 use transaction_util::subaddress::SubAddressIndex;
@@ -15,7 +16,7 @@ where
     I: Clone + Eq + std::hash::Hash + Send + Sync,
 {
     server: Arc<RwLock<RawServer<R, I>>>,
-    wallet_store: Arc<RwLock<WalletStore>>,
+    wallet_store: Arc<AsyncRwLock<WalletStore>>,
 }
 
 impl<R, I> WalletRPCServer<R, I>
@@ -23,7 +24,7 @@ where
     R: TransportServer<RequestId = I>,
     I: Clone + Eq + std::hash::Hash + Send + Sync,
 {
-    pub fn new(server: RawServer<R, I>, wallet_store: Arc<RwLock<WalletStore>>) -> Self {
+    pub fn new(server: RawServer<R, I>, wallet_store: Arc<AsyncRwLock<WalletStore>>) -> Self {
         Self {
             server: Arc::from(RwLock::from(server)),
             wallet_store,
@@ -46,7 +47,7 @@ where
                     respond.respond(
                         self.wallet_store
                             .write()
-                            .unwrap()
+                            .await
                             .add_wallet(wallet_name.clone(), w)
                             .map(|_| "")
                             .map_err(|e| Error::invalid_params(e.to_string())),
@@ -62,7 +63,7 @@ where
                     respond.respond(
                         self.wallet_store
                             .write()
-                            .unwrap()
+                            .await
                             .load_wallet(wallet_name.clone())
                             .map(|_| "")
                             .map_err(|e| Error::invalid_params(e.to_string())),
@@ -74,7 +75,7 @@ where
                     respond.respond(
                         self.wallet_store
                             .write()
-                            .unwrap()
+                            .await
                             .refresh_wallets()
                             .await
                             .map(|_| "")
@@ -86,8 +87,8 @@ where
                 WalletRPC::SaveWallets { respond } => {
                     respond.respond(
                         self.wallet_store
-                            .write()
-                            .unwrap()
+                            .read()
+                            .await
                             .save_wallets()
                             .await
                             .map(|_| "")
@@ -111,8 +112,8 @@ where
 
                         let wallet = self
                             .wallet_store
-                            .write()
-                            .unwrap()
+                            .read()
+                            .await
                             .get_wallet(&wallet_name)
                             .with_context(|| "Wallet not found")?;
                         let wallet = wallet.read().unwrap();
@@ -148,8 +149,8 @@ where
 
                         let wallet = self
                             .wallet_store
-                            .write()
-                            .unwrap()
+                            .read()
+                            .await
                             .get_wallet(&wallet_name)
                             .with_context(|| "Wallet not found")?;
                         let wallet = wallet.read().unwrap();
@@ -3,45 +3,73 @@ use std::fs::File;
 use std::sync::{Arc, RwLock};
 
 use anyhow::Error;
-use jsonrpsee::{raw::RawClient, transport::http::HttpTransportClient};
+use futures::StreamExt;
+use jsonrpsee::raw::RawClient;
+use tokio::sync::{Mutex, RwLock as AsyncRwLock};
 
-use common::{GetHash, Transaction};
 use ensure_macro::ensure;
-use rpc::api_definitions::DaemonRPC;
+use rpc::{
+    api_definitions::{ChainReorgNotification, DaemonRPC, NewBlockNotification},
+    ClientTransport, HexBlob, SubscriptionEvent,
+};
 use wallet::Wallet;
 
 use crate::config::Config;
 
+/// A loaded wallet
+#[derive(Clone)]
+struct StoredWallet {
+    wallet: Arc<RwLock<Wallet>>,
+    /// Held while the wallet is refreshed, so concurrent refreshes don't scan the same blocks
+    /// twice
+    refresh_lock: Arc<Mutex<()>>,
+}
+
 pub struct WalletStore {
     // refresh_interval: Interval,
-    rpc_client: RawClient<HttpTransportClient>,
+    daemon_transport: ClientTransport,
+    rpc_client: RawClient<ClientTransport>,
     wallet_dir: std::path::PathBuf,
-    wallets: HashMap<String, Arc<RwLock<Wallet>>>,
+    wallets: HashMap<String, StoredWallet>,
 }
 
 impl WalletStore {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let daemon_transport = rpc::client_transport(
+            &config.daemon_address,
+            config.daemon_ssl,
+            config.daemon_ssl_fingerprint.as_deref(),
+            config.daemon_access_token.as_deref(),
+        )?;
+
         let ws = WalletStore {
             // refresh_interval: Interval::new_interval(Duration::from_secs(10)),
-            rpc_client: RawClient::new(HttpTransportClient::new(&format!(
-                "http://{}",
-                config.daemon_address
-            ))),
+            rpc_client: RawClient::new(daemon_transport.clone()),
+            daemon_transport,
             wallet_dir: config.wallet_dir,
             wallets: HashMap::new(),
         };
 
-        std::fs::create_dir_all(&ws.wallet_dir).unwrap();
+        std::fs::create_dir_all(&ws.wallet_dir)?;
 
-        ws
+        Ok(ws)
+    }
+    pub fn daemon_transport(&self) -> ClientTransport {
+        self.daemon_transport.clone()
     }
+
     pub fn add_wallet(&mut self, wallet_name: String, wallet: Wallet) -> Result<(), Error> {
         ensure!(
             !self.wallets.contains_key(&wallet_name),
             anyhow::format_err!("Wallet {} exists in memory", wallet_name)
         );
-        self.wallets
-            .insert(wallet_name, Arc::from(RwLock::new(wallet)));
+        self.wallets.insert(
+            wallet_name,
+            StoredWallet {
+                wallet: Arc::from(RwLock::new(wallet)),
+                refresh_lock: Arc::new(Mutex::new(())),
+            },
+        );
         Ok(())
     }
 
@@ -56,11 +84,13 @@ impl WalletStore {
     }
 
     pub fn get_wallet(&self, wallet_name: &str) -> Option<Arc<RwLock<Wallet>>> {
-        self.wallets.get(wallet_name).cloned()
+        self.wallets
+            .get(wallet_name)
+            .map(|stored| stored.wallet.clone())
     }
 
     pub async fn save_wallets(&self) -> Result<(), Error> {
-        for (wallet_name, wallet) in &self.wallets {
+        for (wallet_name, stored) in &self.wallets {
             let mut wallet_path = self.wallet_dir.clone();
             wallet_path.push(wallet_name);
 
@@ -68,46 +98,144 @@ impl WalletStore {
             let file = File::create(wallet_path)?;
 
             // TODO: Add file encryption before release
-            bincode::serialize_into(file, &*wallet.read().unwrap())?;
+            bincode::serialize_into(file, &*stored.wallet.read().unwrap())?;
         }
         Ok(())
     }
 
     pub async fn refresh_wallets(&mut self) -> Result<(), Error> {
-        for (wallet_name, wallet) in &self.wallets {
+        for (wallet_name, stored) in &self.wallets {
             log::debug!("Refreshing {}", wallet_name);
 
-            let last_checked_height = {
-                let wallet = wallet.read().unwrap();
-                let last_check = wallet.get_last_checked_block();
-                *last_check.0
-            };
-            let wallet = wallet.clone();
-
-            let response =
-                DaemonRPC::get_blocks(&mut self.rpc_client, last_checked_height, None).await?;
-
-            // TODO: Move this to a #[serde(with)] method
-            let blocks: Vec<common::Block> = response
-                .blocks
-                .into_iter()
-                .flat_map(hex::decode)
-                .flat_map(|block_blob| bincode::deserialize(&block_blob))
-                .collect();
-
-            let transactions: HashMap<_, _> = response
-                .transactions
-                .into_iter()
-                .flat_map(hex::decode)
-                .flat_map(|tx_blob| bincode::deserialize(&tx_blob))
-                .map(|tx: Transaction| (tx.get_hash(), tx))
-                .collect();
-
-            blocks.iter().for_each(|block| {
-                wallet.write().unwrap().scan_block(block, &transactions);
-            });
+            refresh_wallet(&mut self.rpc_client, stored).await?;
         }
 
         Ok(())
     }
 }
+
+async fn refresh_wallet(
+    rpc_client: &mut RawClient<ClientTransport>,
+    stored: &StoredWallet,
+) -> Result<(), Error> {
+    let _refresh_guard = stored.refresh_lock.lock().await;
+    let wallet = &stored.wallet;
+
+    let last_checked_height = {
+        let wallet = wallet.read().unwrap();
+        let last_check = wallet.get_last_checked_block();
+        *last_check.0
+    };
+
+    // The daemon limits each response, so keep asking until we've caught up
+    let mut start_height = last_checked_height;
+    loop {
+        let response = DaemonRPC::get_blocks(rpc_client, start_height, None, Some(true)).await?;
+        if response.blocks.is_empty() {
+            break;
+        }
+
+        // Pruned transactions are matched to their IDs by their order in the blocks
+        let transactions: HashMap<_, _> = response
+            .blocks
+            .iter()
+            .flat_map(|block| block.tx_hashes.iter().cloned())
+            .zip(response.transactions.into_iter().map(HexBlob::into_inner))
+            .collect();
+
+        response.blocks.iter().for_each(|block| {
+            wallet.write().unwrap().scan_block(block, &transactions);
+        });
+
+        start_height = response.next_height;
+    }
+
+    Ok(())
+}
+
+/// Notifications of changes to the daemon's chain
+enum ChainNotification {
+    NewBlock(SubscriptionEvent<NewBlockNotification>),
+    Reorg(SubscriptionEvent<ChainReorgNotification>),
+}
+
+/// Refreshes all wallets whenever the daemon announces a new block through its WebSocket server
+///
+/// When the daemon switches over to an alternative chain, the wallets forget the outputs received
+/// above the split first. The wallets are also refreshed after each (re)subscription and each
+/// failed attempt, so they keep up with the chain by polling until the WebSocket server is back
+pub async fn refresh_on_new_blocks(
+    wallet_store: Arc<AsyncRwLock<WalletStore>>,
+    daemon_transport: ClientTransport,
+    daemon_ws_url: String,
+    daemon_access_token: Option<String>,
+) {
+    // Use a client of our own, since the RPC server keeps the store locked while refreshing
+    let mut rpc_client = RawClient::new(daemon_transport);
+
+    let new_blocks = rpc::subscribe_with_retry(
+        daemon_ws_url.clone(),
+        "subscribe_new_blocks",
+        daemon_access_token.clone(),
+    )
+    .map(ChainNotification::NewBlock);
+    let reorgs =
+        rpc::subscribe_with_retry(daemon_ws_url, "subscribe_chain_reorg", daemon_access_token)
+            .map(ChainNotification::Reorg);
+    let notifications = futures::stream::select(new_blocks, reorgs);
+    futures::pin_mut!(notifications);
+
+    while let Some(notification) = notifications.next().await {
+        match notification {
+            ChainNotification::NewBlock(SubscriptionEvent::Notification(notification)) => {
+                log::debug!("New block at height {}", notification.height);
+            }
+            ChainNotification::Reorg(SubscriptionEvent::Notification(notification)) => {
+                log::info!(
+                    "Daemon switched to an alternative chain splitting at height {}",
+                    notification.split_height
+                );
+                rewind_all(&wallet_store, notification.split_height).await;
+            }
+            _ => {}
+        }
+
+        refresh_all(&wallet_store, &mut rpc_client).await;
+    }
+}
+
+/// Makes all wallets forget the blocks above the given height, along with their outputs
+async fn rewind_all(wallet_store: &AsyncRwLock<WalletStore>, split_height: u64) {
+    let wallets = wallet_store
+        .read()
+        .await
+        .wallets
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for stored in wallets {
+        let _refresh_guard = stored.refresh_lock.lock().await;
+        stored.wallet.write().unwrap().rewind(split_height);
+    }
+}
+
+async fn refresh_all(
+    wallet_store: &AsyncRwLock<WalletStore>,
+    rpc_client: &mut RawClient<ClientTransport>,
+) {
+    // The store isn't kept locked while refreshing, so the RPC server can keep serving requests
+    let wallets = wallet_store
+        .read()
+        .await
+        .wallets
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for stored in wallets {
+        if let Err(error) = refresh_wallet(rpc_client, &stored).await {
+            log::warn!("Failed to refresh wallet: {}", error);
+        }
+    }
+}
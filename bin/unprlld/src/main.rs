@@ -36,11 +36,18 @@ async fn run(config: Config) -> Result<(), anyhow::Error> {
     let core = Arc::new(RwLock::new(CryptonoteCore::new(
         coin_specific::Unprll,
         &config.cryptonote_core_config,
-    )));
+    )?));
+
+    // P2P state shared with the RPC server
+    let network = core.read().unwrap().network();
+    let network_state = Arc::new(RwLock::new(p2p::NetworkState::new(
+        &config.p2p_config,
+        network,
+    )?));
 
     futures::join!(
-        p2p::init(&config.p2p_config, core.clone())?,
-        rpc::init(&config.rpc_config, core)?,
+        p2p::init(&config.p2p_config, core.clone(), network_state.clone())?,
+        rpc::init(&config.rpc_config, core, network_state)?,
     );
 
     log::info!("Exiting");
@@ -1,5 +1,7 @@
 use blockchain_db::Error as BlockchainDBError;
 
+use crate::TXPoolError;
+
 /// Type alias for Blockchain operations that may result in an error
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -18,7 +20,34 @@ pub enum Error {
     #[error("Block contains an extraneous transaction")]
     ExtraneousTransaction,
 
+    /// Returned when a transaction received along with a block is not part of the block
+    #[error("Transaction is not part of the block")]
+    UnexpectedTransaction,
+
+    /// Returned when the transaction pool rejects a transaction
+    #[error(transparent)]
+    TXPoolError(#[from] TXPoolError),
+
     /// Returned when the blockchain DB returns an error
     #[error(transparent)]
     DBError(#[from] BlockchainDBError),
 }
+
+impl Error {
+    /// Checks if the error means the block or transaction breaks consensus rules, as opposed
+    /// to it being unusable in the current state of the chain (such as alt chain blocks or
+    /// transactions that are already known)
+    pub fn is_invalid(&self) -> bool {
+        match self {
+            // Pool policy and conflicts with other unconfirmed transactions don't break consensus
+            Error::TXPoolError(TXPoolError::DoubleSpend)
+            | Error::TXPoolError(TXPoolError::FeeTooLow { .. })
+            | Error::TXPoolError(TXPoolError::TooBig { .. }) => false,
+            Error::InvalidTransaction | Error::UnexpectedTransaction | Error::TXPoolError(_) => {
+                true
+            }
+            Error::DBError(BlockchainDBError::InvalidHeight) => true,
+            Error::AltChainBlock | Error::ExtraneousTransaction | Error::DBError(_) => false,
+        }
+    }
+}
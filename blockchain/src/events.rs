@@ -0,0 +1,27 @@
+use common::{Block, Transaction};
+use crypto::Hash256;
+
+/// Changes to the blockchain and transaction pool, sent to subscribers
+#[derive(Clone, Debug)]
+pub enum ChainEvent {
+    /// A block was added to the main chain at the given height
+    NewBlock {
+        /// Height of the block
+        height: u64,
+        /// The block itself
+        block: Block,
+    },
+
+    /// The main chain switched over to an alternative chain
+    ///
+    /// Followed by a `NewBlock` event for each block of the new main chain above the split
+    Reorganized {
+        /// Height of the last block both chains have in common
+        split_height: u64,
+        /// IDs of the blocks removed from the main chain, from the old tail down
+        disconnected: Vec<Hash256>,
+    },
+
+    /// A transaction was added to the transaction pool
+    NewTransaction(Transaction),
+}
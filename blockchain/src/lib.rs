@@ -4,26 +4,64 @@
 //! This crate handles the blockchain
 
 use std::{
-    collections::VecDeque,
-    pin::Pin,
-    task::{Context, Poll, Waker},
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use futures::Stream;
+use tokio::sync::broadcast;
 
-use blockchain_db::{BlockchainDB, Error as BlockchainDBError};
+use blockchain_db::{BlockchainDB, Error as BlockchainDBError, OutputEntry};
 use common::{Block, GetHash, PreliminaryChecks, Transaction};
-use crypto::Hash256;
+use crypto::{Hash256, KeyImage};
+use transaction_util::{address::Address, tx_construction};
 
 mod config;
 mod error;
+mod events;
 mod traits;
 mod txpool;
 
 pub use config::Config;
 pub use error::{Error, Result};
+pub use events::ChainEvent;
 pub use traits::EmissionCurve;
-pub use txpool::TXPool;
+pub use txpool::{Error as TXPoolError, TXPool};
+
+/// Number of most recent blocks included one by one in a block locator
+const DENSE_LOCATOR_LENGTH: usize = 10;
+
+/// Maximum number of alternative blocks kept. The oldest ones are dropped first
+const MAX_ALTERNATIVE_BLOCKS: usize = 1000;
+
+/// Number of chain events kept for subscribers that fall behind
+const EVENT_CAPACITY: usize = 1024;
+
+/// Maximum total size of the transactions included in a block template in bytes
+const MAX_TEMPLATE_TRANSACTIONS_SIZE: usize = 300_000;
+
+/// Gets the heights of the blocks included in the block locator of a chain
+///
+/// The most recent blocks are included one by one, after which the distance between
+/// blocks doubles each time. The genesis block is always included last
+fn locator_heights(tail_height: u64) -> Vec<u64> {
+    let mut heights = Vec::new();
+    let mut height = tail_height;
+    let mut step = 1;
+
+    loop {
+        heights.push(height);
+        if height == 0 {
+            break;
+        }
+
+        if heights.len() >= DENSE_LOCATOR_LENGTH {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+
+    heights
+}
 
 /// An interface to the stored blockchain
 pub struct Blockchain<TCoin>
@@ -32,10 +70,10 @@ where
     TCoin: EmissionCurve,
 {
     alternative_blocks: Vec<Block>,
+    alternative_transactions: HashMap<Hash256, Vec<Transaction>>,
     blockchain_db: BlockchainDB,
     coin_definition: TCoin,
-    events: VecDeque<Block>,
-    pending_wake: Option<Waker>,
+    events: broadcast::Sender<ChainEvent>,
     tx_pool: TXPool,
 }
 
@@ -47,16 +85,31 @@ where
     pub fn new(coin_definition: TCoin, config: &Config) -> Result<Self> {
         let mut blockchain = Blockchain {
             alternative_blocks: Vec::new(),
-            blockchain_db: BlockchainDB::new(&config.blockchain_db_config),
+            alternative_transactions: HashMap::new(),
+            blockchain_db: BlockchainDB::new(&config.blockchain_db_config)?,
             coin_definition,
-            events: VecDeque::new(),
-            pending_wake: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
             tx_pool: TXPool::new(),
         };
+        let legacy_chain = blockchain.blockchain_db.take_legacy_chain();
         if blockchain.blockchain_db.get_block_by_height(0).is_none() {
             // Add the genesis block
             blockchain.add_new_block(Block::genesis())?;
         }
+
+        // Rebuild databases stored in an older format. Blocks that no longer verify are left
+        // to be synced again
+        for (height, (block, transactions)) in legacy_chain.into_iter().enumerate().skip(1) {
+            if let Err(error) = blockchain.add_block_with_transactions(block, transactions) {
+                log::warn!(
+                    "Stopped rebuilding the database at height {}: {}",
+                    height,
+                    error
+                );
+                break;
+            }
+        }
+
         Ok(blockchain)
     }
 
@@ -86,6 +139,14 @@ where
         &self.alternative_blocks
     }
 
+    /// Gets an alternative block received from other peers
+    pub fn get_alternative_block(&self, id: &Hash256) -> Option<Block> {
+        self.alternative_blocks
+            .iter()
+            .find(|block| block.get_hash() == *id)
+            .cloned()
+    }
+
     /// Adds a new block to the main chain
     ///
     /// The block must satisfy the blockchain database's preliminary checks (another block doesn't
@@ -93,45 +154,70 @@ where
     /// connect to the current chain's tail) and further it must have a valid proof-of-work (as
     /// determined by the coin)
     ///
+    /// Blocks that don't connect to the tail are kept as alternative blocks. If they make an
+    /// alternative chain heavier than the main chain, the blockchain switches over to it
+    ///
     /// # Returns
     /// An empty tuple if the block was added successfully
     ///
     /// # Errors
-    /// If any of the pre-checks fail
+    /// If any of the pre-checks fail. `AltChainBlock` if the block was kept as an alternative
+    /// block without becoming part of the main chain
     pub fn add_new_block(&mut self, block: Block) -> Result<()> {
-        // Do all prechecks
-        let check = self.check(&block);
-
-        // If they fail, it could be considered an alt chain block
-        if let Err(Error::DBError(error)) = check {
-            if let BlockchainDBError::DoesNotConnect = error {
-                // Block does not connect to our chain. Store as an alt chain block
-                // TODO: Add any alt chain blocks if the connect to our chain eventually
-                log::warn!("Potential alt chain block received");
-                self.alternative_blocks.push(block.clone());
-
-                Err(Error::AltChainBlock)
-            } else {
-                Err(Error::DBError(error))
+        match self.check(&block) {
+            Err(Error::DBError(BlockchainDBError::DoesNotConnect)) => {
+                self.add_alternative_block(block, Vec::new())
             }
-        } else {
-            check
-        }?;
+            check => {
+                check?;
+                self.add_main_chain_block(block.clone())?;
+                self.notify_new_block(block);
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Subscribes to events of the blockchain
+    ///
+    /// Subscribers that fall behind by more than a fixed number of events miss the oldest ones
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    fn notify(&self, event: ChainEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    fn notify_new_block(&self, block: Block) {
+        let height = self.get_tail().expect("Main chain tail does not exist").0;
 
+        self.notify(ChainEvent::NewBlock { height, block });
+    }
+
+    /// Adds a block that passed the pre-checks to the main chain
+    fn add_main_chain_block(&mut self, block: Block) -> Result<()> {
         let transactions = block
             .tx_hashes
             .iter()
-            .map(|txid| self.tx_pool.take_transaction(txid).unwrap())
-            .collect::<Vec<_>>();
-
-        // Add the block
-        self.blockchain_db.add_block(block.clone(), transactions)?;
+            .map(|txid| {
+                self.tx_pool
+                    .get_transaction(txid)
+                    .or_else(|| self.tx_pool.get_stem_transaction(txid))
+                    .cloned()
+                    .ok_or(Error::ExtraneousTransaction)
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        // Notify any pending futures
-        if let Some(waker) = self.pending_wake.take() {
-            waker.wake();
-            self.events.push_back(block);
+        // Add the block. The transactions only leave the pool once they're confirmed
+        let difficulty = self.get_difficulty();
+        self.blockchain_db
+            .add_block(block.clone(), transactions.clone(), difficulty)?;
+        for transaction in &transactions {
+            self.tx_pool.take_transaction(&transaction.get_hash());
         }
+        self.remove_double_spends(&transactions);
 
         // Print a log message for confirmation
         let (height, block) = self.get_tail().expect("Main chain tail does not exist");
@@ -143,11 +229,419 @@ where
         Ok(())
     }
 
+    /// Keeps a block that doesn't connect to the main chain's tail, switching over to its chain
+    /// if it became heavier than the main chain
+    ///
+    /// The block's transactions are taken from the given ones, the transaction pool or the main
+    /// chain, and are kept along with the block. Transactions that aren't known yet are only
+    /// verified on their own, as they may conflict with the main chain. Their key images are
+    /// checked against the alternative chain once it becomes heavier
+    fn add_alternative_block(
+        &mut self,
+        block: Block,
+        transactions: Vec<Transaction>,
+    ) -> Result<()> {
+        let block_id = block.get_hash();
+        if self.has_block(&block_id) {
+            return Err(Error::DBError(BlockchainDBError::Exists));
+        }
+
+        let unknown = transactions
+            .iter()
+            .filter(|transaction| {
+                let txid = transaction.get_hash();
+                !self.has_transaction(&txid) && !self.tx_pool.has_stem_transaction(&txid)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            TXPool::verify(&unknown)?;
+        }
+
+        let transactions = block
+            .tx_hashes
+            .iter()
+            .map(|txid| {
+                unknown
+                    .iter()
+                    .find(|transaction| transaction.get_hash() == *txid)
+                    .cloned()
+                    .or_else(|| {
+                        self.tx_pool
+                            .get_transaction(txid)
+                            .or_else(|| self.tx_pool.get_stem_transaction(txid))
+                            .cloned()
+                    })
+                    .or_else(|| self.blockchain_db.get_transaction(txid))
+                    .ok_or(Error::ExtraneousTransaction)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let fees = transactions.iter().map(Transaction::fee).sum();
+        self.check_miner_transaction(&block, fees)?;
+
+        log::info!("Received alternative chain block {}", block_id);
+        self.keep_alternative_block(block, transactions);
+
+        let (split_height, chain) = match self.get_alternative_chain(&block_id) {
+            Some(alternative_chain) => alternative_chain,
+            None => return Err(Error::AltChainBlock),
+        };
+
+        // Each block adds the difficulty it had to satisfy on top of its own parent
+        let split_block = self
+            .get_block_by_height(split_height)
+            .expect("Alternative chain split block does not exist");
+        let cumulative_difficulty = self
+            .blockchain_db
+            .get_cumulative_difficulty_at(split_height)
+            .unwrap_or(0)
+            + std::iter::once(&split_block)
+                .chain(chain.iter())
+                .take(chain.len())
+                .map(|parent| self.get_next_difficulty(parent))
+                .sum::<u128>();
+        if cumulative_difficulty <= self.get_cumulative_difficulty() {
+            return Err(Error::AltChainBlock);
+        }
+
+        self.reorganize(split_height, chain)
+    }
+
+    /// Keeps a block along with its transactions as an alternative block, dropping the oldest
+    /// alternative block if there are too many
+    fn keep_alternative_block(&mut self, block: Block, transactions: Vec<Transaction>) {
+        if self.alternative_blocks.len() >= MAX_ALTERNATIVE_BLOCKS {
+            let oldest = self.alternative_blocks.remove(0);
+            self.alternative_transactions.remove(&oldest.get_hash());
+        }
+
+        self.alternative_transactions
+            .insert(block.get_hash(), transactions);
+        self.alternative_blocks.push(block);
+    }
+
+    /// Gets the alternative chain going through the given alternative block, extended with any
+    /// alternative blocks that build on it
+    ///
+    /// # Returns
+    /// The height of the main chain block the alternative chain splits from, along with the
+    /// alternative chain's blocks in height order. `None` if the chain doesn't connect to the
+    /// main chain
+    fn get_alternative_chain(&self, id: &Hash256) -> Option<(u64, Vec<Block>)> {
+        let mut chain = Vec::new();
+        let mut id = id.clone();
+
+        let split_height = loop {
+            if let Some(block) = self.get_block(&id) {
+                break block.height()?;
+            }
+
+            let block = self.get_alternative_block(&id)?;
+            id = block.header.prev_id.clone();
+            chain.push(block);
+        };
+        chain.reverse();
+
+        // Alternative blocks received out of order
+        let mut tail_id = chain.last()?.get_hash();
+        while let Some(block) = self
+            .alternative_blocks
+            .iter()
+            .find(|block| block.header.prev_id == tail_id)
+        {
+            tail_id = block.get_hash();
+            chain.push(block.clone());
+        }
+
+        let heights_match = chain
+            .iter()
+            .enumerate()
+            .all(|(i, block)| block.height() == Some(split_height + 1 + i as u64));
+
+        if heights_match {
+            Some((split_height, chain))
+        } else {
+            None
+        }
+    }
+
+    /// Switches the main chain over to an alternative chain splitting from the given height
+    ///
+    /// The main chain blocks above the split become alternative blocks, and their transactions
+    /// go back to the transaction pool. The alternative blocks' transactions are added to the
+    /// pool block by block, replacing those spending the same key images. If any alternative
+    /// block turns out to be invalid, the original main chain is restored
+    fn reorganize(&mut self, split_height: u64, chain: Vec<Block>) -> Result<()> {
+        log::info!(
+            "Switching to alternative chain splitting at height {} with {} blocks",
+            split_height,
+            chain.len()
+        );
+
+        let chain_ids = chain.iter().map(GetHash::get_hash).collect::<HashSet<_>>();
+        self.alternative_blocks
+            .retain(|block| !chain_ids.contains(&block.get_hash()));
+        let chain_transactions = chain
+            .iter()
+            .map(|block| {
+                self.alternative_transactions
+                    .remove(&block.get_hash())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+
+        let disconnected = self.pop_blocks_above(split_height)?;
+
+        let mut result = Ok(());
+        let mut connected = Vec::new();
+        for (block, transactions) in chain.into_iter().zip(chain_transactions) {
+            result = self
+                .add_block_transactions(transactions)
+                .and_then(|_| self.check(&block))
+                .and_then(|_| self.add_main_chain_block(block.clone()));
+            if result.is_err() {
+                break;
+            }
+            connected.push(block);
+        }
+
+        if let Err(error) = result {
+            log::warn!(
+                "Alternative chain is invalid, restoring main chain: {}",
+                error
+            );
+            self.restore_blocks(split_height, disconnected);
+
+            return Err(error);
+        }
+
+        self.notify(ChainEvent::Reorganized {
+            split_height,
+            disconnected: disconnected
+                .iter()
+                .map(|(block, _)| block.get_hash())
+                .collect(),
+        });
+        for (i, block) in connected.into_iter().enumerate() {
+            self.notify(ChainEvent::NewBlock {
+                height: split_height + 1 + i as u64,
+                block,
+            });
+        }
+        for (block, transactions) in disconnected {
+            self.keep_alternative_block(block, transactions);
+        }
+
+        Ok(())
+    }
+
+    /// Puts main chain blocks removed by `pop_blocks_above` back on top of the given height
+    ///
+    /// The blocks are added along with the transactions they were removed with, so they don't
+    /// depend on what happened to the transaction pool in the meantime
+    fn restore_blocks(&mut self, height: u64, blocks: Vec<(Block, Vec<Transaction>)>) {
+        if let Err(error) = self.pop_blocks_above(height) {
+            log::error!("Failed to remove the alternative chain: {}", error);
+        }
+
+        for (block, transactions) in blocks.into_iter().rev() {
+            let block_id = block.get_hash();
+            for transaction in &transactions {
+                self.tx_pool.take_transaction(&transaction.get_hash());
+            }
+
+            let difficulty = self.get_difficulty();
+            if let Err(error) =
+                self.blockchain_db
+                    .add_block(block, transactions.clone(), difficulty)
+            {
+                log::error!("Failed to restore block {}: {}", block_id, error);
+                break;
+            }
+            self.remove_double_spends(&transactions);
+        }
+    }
+
+    /// Drops the transactions of the transaction pool that spend the same key images as the
+    /// given confirmed transactions
+    fn remove_double_spends(&mut self, transactions: &[Transaction]) {
+        for transaction in self.tx_pool.remove_double_spends(transactions) {
+            log::info!(
+                "Dropped transaction {} double spending a confirmed transaction",
+                transaction.get_hash()
+            );
+        }
+    }
+
+    /// Puts transactions back into the transaction pool one by one, dropping those that are
+    /// rejected
+    fn return_to_pool(&mut self, transactions: &[Transaction]) {
+        for transaction in transactions {
+            if let Err(error) = self
+                .tx_pool
+                .add_transactions(std::slice::from_ref(transaction))
+            {
+                log::warn!("Dropped transaction {}: {}", transaction.get_hash(), error);
+            }
+        }
+    }
+
+    /// Removes the main chain blocks above the given height, returning their transactions to
+    /// the transaction pool
+    ///
+    /// # Returns
+    /// The removed blocks along with their transactions, from the tail down
+    fn pop_blocks_above(&mut self, height: u64) -> Result<Vec<(Block, Vec<Transaction>)>> {
+        let mut blocks = Vec::new();
+
+        while self
+            .get_tail()
+            .map_or(false, |(tail_height, _)| tail_height > height)
+        {
+            let (block, transactions) = self.blockchain_db.pop_block()?;
+
+            // Transactions are added one by one so a rejected one doesn't take the others with it
+            self.return_to_pool(&transactions);
+            blocks.push((block, transactions));
+        }
+
+        Ok(blocks)
+    }
+
+    /// Adds a new block received along with its transactions, such as when syncing from peers
+    ///
+    /// Transactions that aren't in the transaction pool yet are added to it before the block.
+    /// They replace any pool transactions spending the same key images. Transactions of
+    /// alternative blocks are kept with the block instead, until its chain becomes the main chain
+    ///
+    /// # Errors
+    /// If a transaction isn't part of the block or fails verification, or if any of the block
+    /// pre-checks fail
+    pub fn add_block_with_transactions(
+        &mut self,
+        block: Block,
+        transactions: Vec<Transaction>,
+    ) -> Result<()> {
+        for transaction in &transactions {
+            if !block.tx_hashes.contains(&transaction.get_hash()) {
+                return Err(Error::UnexpectedTransaction);
+            }
+        }
+
+        // Transactions of alternative blocks stay out of the pool, as they may conflict with
+        // the main chain
+        let (_, tail) = self.get_tail().expect("Main chain tail does not exist");
+        if block.header.prev_id != tail.get_hash() {
+            return self.add_alternative_block(block, transactions);
+        }
+
+        self.add_block_transactions(transactions)?;
+        self.add_new_block(block)
+    }
+
+    /// Adds the transactions of a block that's about to be added to the main chain to the
+    /// transaction pool, replacing the pool transactions spending the same key images
+    ///
+    /// Transactions that are known already are skipped
+    fn add_block_transactions(&mut self, transactions: Vec<Transaction>) -> Result<()> {
+        for transaction in transactions {
+            let txid = transaction.get_hash();
+            if self.has_transaction(&txid) || self.tx_pool.has_stem_transaction(&txid) {
+                continue;
+            }
+
+            let replaced = self
+                .tx_pool
+                .remove_double_spends(std::slice::from_ref(&transaction));
+            if let Err(error) = self.add_to_pool(transaction) {
+                self.return_to_pool(&replaced);
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets the IDs of the transactions of a block that aren't in the transaction pool
+    ///
+    /// The block can't be added until these transactions are received
+    pub fn get_missing_transactions(&self, block: &Block) -> Vec<Hash256> {
+        block
+            .tx_hashes
+            .iter()
+            .filter(|txid| !self.has_transaction(txid) && !self.tx_pool.has_stem_transaction(txid))
+            .cloned()
+            .collect()
+    }
+
+    /// Checks if a block is known, either on the main chain or as an alternative block
+    pub fn has_block(&self, id: &Hash256) -> bool {
+        self.get_block(id).is_some()
+            || self
+                .alternative_blocks
+                .iter()
+                .any(|block| block.get_hash() == *id)
+    }
+
+    /// Gets a sparse list of main chain block IDs, starting at the tail and ending at the
+    /// genesis block
+    ///
+    /// Sent to peers so they can find the point where their chain splits from ours
+    pub fn get_block_locator(&self) -> Vec<Hash256> {
+        let (tail_height, _) = self.get_tail().expect("Main chain tail does not exist");
+
+        locator_heights(tail_height)
+            .into_iter()
+            .filter_map(|height| self.get_block_by_height(height))
+            .map(|block| block.get_hash())
+            .collect()
+    }
+
+    /// Finds the most recent main chain block in a peer's block locator
+    ///
+    /// # Returns
+    /// The height of the block, if any of the blocks are on the main chain
+    pub fn find_common_ancestor(&self, locator: &[Hash256]) -> Option<u64> {
+        locator
+            .iter()
+            .find_map(|id| self.get_block(id))
+            .and_then(|block| block.height())
+    }
+
     /// Gets a block from the blockchain
     pub fn get_block(&self, id: &Hash256) -> Option<Block> {
         self.blockchain_db.get_block_by_hash(id)
     }
 
+    /// Gets the main chain block at the given height
+    pub fn get_block_by_height(&self, height: u64) -> Option<Block> {
+        self.blockchain_db.get_block_by_height(height)
+    }
+
+    /// Gets the difficulty the next block on the main chain has to satisfy
+    pub fn get_difficulty(&self) -> u128 {
+        // The genesis block has no parent to build on
+        self.get_tail()
+            .map_or(1, |(_, tail)| self.get_next_difficulty(&tail))
+    }
+
+    /// Gets the difficulty a block building on the given block has to satisfy
+    fn get_next_difficulty(&self, _parent: &Block) -> u128 {
+        // TODO: Implement difficulty adjustment over the chain ending at the parent. The network
+        // currently runs at a fixed difficulty
+        1
+    }
+
+    /// Gets the cumulative difficulty of the main chain
+    pub fn get_cumulative_difficulty(&self) -> u128 {
+        self.blockchain_db.get_cumulative_difficulty()
+    }
+
+    /// Gets the size of the blockchain database in bytes
+    pub fn get_db_size(&self) -> u64 {
+        self.blockchain_db.size()
+    }
+
     /// Gets the main chain's tail
     ///
     /// # Returns
@@ -156,61 +650,478 @@ where
         self.blockchain_db.get_tail()
     }
 
+    // Outputs
+    /// Gets the output with the given global output index
+    pub fn get_output(&self, index: u64) -> Option<OutputEntry> {
+        self.blockchain_db.get_output(index)
+    }
+
+    /// Gets the number of outputs in the main chain
+    pub fn get_output_count(&self) -> u64 {
+        self.blockchain_db.get_output_count()
+    }
+
+    /// Gets the number of outputs in the main chain up to and including the given height
+    pub fn get_cumulative_output_count(&self, height: u64) -> u64 {
+        self.blockchain_db.get_cumulative_output_count(height)
+    }
+
     // Transactions
     /// Gets a transaction with the given txid from confirmed transactions
     pub fn get_transaction(&self, txid: &Hash256) -> Option<Transaction> {
         self.blockchain_db.get_transaction(txid)
     }
+
+    /// Gets the height of the block that confirmed the transaction with the given txid
+    pub fn get_transaction_height(&self, txid: &Hash256) -> Option<u64> {
+        self.blockchain_db.get_transaction_height(txid)
+    }
+
+    /// Checks if the given key image was spent in the main chain
+    pub fn is_key_image_spent(&self, key_image: &KeyImage) -> bool {
+        self.blockchain_db.has_key_image(key_image)
+    }
+
+    /// Checks if a transaction with the given txid is known, either as a confirmed
+    /// transaction or as an unconfirmed one in the transaction pool
+    pub fn has_transaction(&self, txid: &Hash256) -> bool {
+        self.tx_pool.has_transaction(txid) || self.blockchain_db.get_transaction(txid).is_some()
+    }
+
+    /// Adds an unconfirmed transaction to the transaction pool
+    ///
+    /// The transaction must not exist in the main chain already and none of its key
+    /// images may have been spent. Semantic verification is done by the transaction pool,
+    /// which also applies its relay policy
+    ///
+    /// # Errors
+    /// If the transaction exists already, double spends, fails verification or doesn't follow
+    /// the relay policy
+    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        TXPool::check_policy(&transaction)?;
+        self.add_to_pool(transaction)
+    }
+
+    /// Adds an unconfirmed transaction to the transaction pool without applying the relay
+    /// policy, such as for transactions received along with a block
+    fn add_to_pool(&mut self, transaction: Transaction) -> Result<()> {
+        self.blockchain_db.check(&transaction)?;
+        self.tx_pool
+            .add_transactions(std::slice::from_ref(&transaction))?;
+        self.notify(ChainEvent::NewTransaction(transaction));
+
+        Ok(())
+    }
+
+    /// Adds an unconfirmed transaction in the Dandelion++ stem phase to the transaction pool
+    ///
+    /// Stem transactions are verified the same way as transactions added using
+    /// `add_transaction`, but are kept out of the main pool until they're fluffed
+    ///
+    /// # Errors
+    /// If the transaction exists already, double spends, or fails verification
+    pub fn add_stem_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        TXPool::check_policy(&transaction)?;
+        self.blockchain_db.check(&transaction)?;
+        self.tx_pool.add_stem_transaction(transaction)?;
+
+        Ok(())
+    }
+
+    /// Moves a transaction in the stem phase into the main transaction pool
+    ///
+    /// # Returns
+    /// `true` if the transaction was in the stem phase
+    pub fn fluff_transaction(&mut self, txid: &Hash256) -> bool {
+        if !self.tx_pool.fluff_transaction(txid) {
+            return false;
+        }
+
+        if let Some(transaction) = self.tx_pool.get_transaction(txid) {
+            self.notify(ChainEvent::NewTransaction(transaction.clone()));
+        }
+        true
+    }
+
+    /// Get a reference to the transaction pool
+    pub fn tx_pool(&self) -> &TXPool {
+        &self.tx_pool
+    }
 }
 
-impl<TCoin: EmissionCurve> PreliminaryChecks<Block> for Blockchain<TCoin> {
-    type Error = Error;
+impl<TCoin: EmissionCurve> Blockchain<TCoin> {
+    /// Creates a block to be mined on top of the main chain's tail
+    ///
+    /// The block includes the transactions of the transaction pool with the highest fees
+    /// first, up to a fixed total size, and a coinbase transaction paying the block reward
+    /// plus fees to the miner's address. Transactions that can't be added to the main chain
+    /// are left out. `reserve_size` bytes are reserved in the coinbase transaction's extra for
+    /// the miner to fill in
+    pub fn create_block_template(&self, miner_address: &Address, reserve_size: usize) -> Block {
+        let (tail_height, tail) = self.get_tail().expect("Main chain tail does not exist");
 
-    fn check(&self, block: &Block) -> Result<()> {
-        // Do the blockchain DB prechecks
-        self.blockchain_db.check(block)?;
+        let mut transactions = Vec::new();
+        let mut size = 0;
+        for transaction in self.tx_pool.get_transactions_by_fee() {
+            if size + transaction.size() > MAX_TEMPLATE_TRANSACTIONS_SIZE
+                || self.blockchain_db.check(transaction).is_err()
+            {
+                continue;
+            }
+
+            size += transaction.size();
+            transactions.push(transaction);
+        }
+        let fees = transactions
+            .iter()
+            .map(|transaction| transaction.fee())
+            .sum::<u64>();
+
+        let mut block = Block::default();
+
+        // Header
+        // There are no hard forks yet, so the next block keeps the tail's versions
+        block.header.major_version = tail.header.major_version;
+        block.header.minor_version = tail.header.minor_version;
+        block.header.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time is before the UNIX epoch")
+            .as_secs();
+        block.header.prev_id = tail.get_hash();
+        block.header.miner_specific = miner_address.spend_public_key;
+
+        // Transactions
+        block.miner_tx = tx_construction::construct_miner_tx(
+            tail_height + 1,
+            self.coin_definition
+                .get_block_reward(block.header.major_version)
+                + fees,
+            miner_address,
+            reserve_size,
+        );
+        block.tx_hashes = transactions.iter().map(|tx| tx.get_hash()).collect();
+
+        block
+    }
 
+    /// Gets the sum of the fees paid by the transactions of a block
+    ///
+    /// All transactions of the block must be in the transaction pool
+    fn get_fees(&self, block: &Block) -> u64 {
+        block
+            .tx_hashes
+            .iter()
+            .filter_map(|txid| {
+                self.tx_pool
+                    .get_transaction(txid)
+                    .or_else(|| self.tx_pool.get_stem_transaction(txid))
+                    .map(Transaction::fee)
+            })
+            .sum()
+    }
+
+    /// Checks the coinbase transaction of a block paying the given fees
+    fn check_miner_transaction(&self, block: &Block, fees: u64) -> Result<()> {
         // The coinbase transaction must have only one input and output
         if block.miner_tx.prefix.inputs.len() != 1 || block.miner_tx.prefix.outputs.len() != 1 {
             return Err(Error::InvalidTransaction);
         }
 
-        // The coinbase amount must match the coin's emission curve
+        // The coinbase amount must match the coin's emission curve plus the fees of the block
         if block.miner_tx.prefix.outputs[0].amount
             != self
                 .coin_definition
                 .get_block_reward(block.header.major_version)
+                + fees
         {
             return Err(Error::InvalidTransaction);
         }
 
+        Ok(())
+    }
+}
+
+impl<TCoin: EmissionCurve> PreliminaryChecks<Block> for Blockchain<TCoin> {
+    type Error = Error;
+
+    fn check(&self, block: &Block) -> Result<()> {
+        // Do the blockchain DB prechecks
+        self.blockchain_db.check(block)?;
+
         // The block must contain transactions that we've got in our mempool
         for txid in &block.tx_hashes {
-            if !self.tx_pool.has_transaction(txid) {
+            if !self.tx_pool.has_transaction(txid) && !self.tx_pool.has_stem_transaction(txid) {
                 return Err(Error::ExtraneousTransaction);
             }
         }
 
+        // The fees of the transactions are needed to check the coinbase amount
+        self.check_miner_transaction(block, self.get_fees(block))?;
+
         // Transactions can only be added to the chain if they're in mempool, hence TXPool handles transaction verification
         Ok(())
     }
 }
 
-impl<TCoin> Stream for Blockchain<TCoin>
-where
-    TCoin: EmissionCurve + Unpin,
-{
-    type Item = Block;
-
-    // TODO: This has to become a read-only reference to blockchain to make
-    //       sure we don't block any other readers. We are only ever going
-    //       to use read-only methods on the actual blockchain
-    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Self::Item>> {
-        if let Some(event) = self.as_mut().events.pop_front() {
-            Poll::Ready(Some(event))
-        } else {
-            self.as_mut().pending_wake = Some(context.waker().clone());
-            Poll::Pending
+#[cfg(test)]
+mod tests {
+    use common::{TXIn, TransactionPrefix};
+    use crypto::KeyPair;
+    use ringct::{DestinationCommitmentPair, Matrix, RingCTBase, RingCTSignature, RingCTType};
+
+    use super::*;
+
+    struct TestCoin;
+
+    impl EmissionCurve for TestCoin {
+        fn get_block_reward(&self, _version: u8) -> u64 {
+            Block::genesis().miner_tx.prefix.outputs[0].amount
+        }
+    }
+
+    /// Creates a blockchain backed by a database that is never stored on disk
+    fn ephemeral_blockchain() -> Blockchain<TestCoin> {
+        let config = Config {
+            blockchain_db_config: blockchain_db::Config {
+                db_type: "ephemeral".to_string(),
+                db_data_directory: None,
+            },
+        };
+
+        Blockchain::new(TestCoin, &config).unwrap()
+    }
+
+    fn next_block(parent: &Block, timestamp: u64) -> Block {
+        let mut block = Block::genesis();
+        block.header.prev_id = parent.get_hash();
+        block.header.timestamp = timestamp;
+        block.miner_tx.prefix.inputs = vec![TXIn::Gen(parent.height().unwrap() + 1)];
+
+        block
+    }
+
+    /// Creates a transaction paying the given fee. Its signature doesn't verify, so it can only
+    /// be added to the chain directly
+    fn fee_paying_transaction(fee: u64) -> Transaction {
+        let key = KeyPair::generate().public_key;
+
+        Transaction {
+            prefix: TransactionPrefix {
+                version: 2,
+                inputs: vec![TXIn::FromKey {
+                    key_offsets: vec![0],
+                    key_image: KeyPair::generate().public_key,
+                }],
+                ..TransactionPrefix::default()
+            },
+            rct_signature: Some(RingCTSignature {
+                base: RingCTBase {
+                    signature_type: RingCTType::Bulletproof2,
+                    message_hash: Hash256::null_hash(),
+                    mix_ring: Matrix::from_fn(1, 1, |_, _| DestinationCommitmentPair {
+                        destination: key,
+                        commitment: key,
+                    })
+                    .unwrap(),
+                    output_commitments: Vec::new(),
+                    ecdh_exchange: Vec::new(),
+                    fee,
+                },
+                bulletproofs: Vec::new(),
+                input_commitments: Vec::new(),
+                mlsag: Vec::new(),
+            }),
+        }
+    }
+
+    #[test]
+    fn locators_are_dense_near_the_tail() {
+        assert_eq!(locator_heights(0), vec![0]);
+        assert_eq!(locator_heights(5), vec![5, 4, 3, 2, 1, 0]);
+        assert_eq!(
+            locator_heights(100),
+            vec![100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 89, 85, 77, 61, 29, 0]
+        );
+    }
+
+    #[test]
+    fn switches_to_heavier_alternative_chain() {
+        let mut blockchain = ephemeral_blockchain();
+        let genesis = blockchain.get_block_by_height(0).unwrap();
+
+        let main_1 = next_block(&genesis, 1000);
+        let main_2 = next_block(&main_1, 1001);
+        blockchain.add_new_block(main_1.clone()).unwrap();
+        blockchain.add_new_block(main_2.clone()).unwrap();
+
+        // An alternative chain as heavy as the main chain doesn't replace it
+        let alt_1 = next_block(&genesis, 2000);
+        let alt_2 = next_block(&alt_1, 2001);
+        let alt_3 = next_block(&alt_2, 2002);
+        assert!(matches!(
+            blockchain.add_new_block(alt_1.clone()),
+            Err(Error::AltChainBlock)
+        ));
+        assert!(matches!(
+            blockchain.add_new_block(alt_2.clone()),
+            Err(Error::AltChainBlock)
+        ));
+        assert_eq!(
+            blockchain.get_tail().unwrap().1.get_hash(),
+            main_2.get_hash()
+        );
+
+        let mut events = blockchain.subscribe();
+        blockchain.add_new_block(alt_3.clone()).unwrap();
+        let (tail_height, tail) = blockchain.get_tail().unwrap();
+        assert_eq!(tail_height, 3);
+        assert_eq!(tail.get_hash(), alt_3.get_hash());
+        assert_eq!(
+            blockchain.get_block_by_height(1).unwrap().get_hash(),
+            alt_1.get_hash()
+        );
+        assert_eq!(blockchain.get_cumulative_difficulty(), 4);
+
+        // The previous main chain is kept in case it becomes heavier again
+        assert!(blockchain.get_block(&main_2.get_hash()).is_none());
+        assert!(blockchain
+            .get_alternative_block(&main_1.get_hash())
+            .is_some());
+        assert!(blockchain
+            .get_alternative_block(&main_2.get_hash())
+            .is_some());
+
+        // The outputs of the previous main chain are replaced by those of the new one
+        assert_eq!(blockchain.get_output_count(), 4);
+        assert_eq!(blockchain.get_cumulative_output_count(0), 1);
+        assert_eq!(blockchain.get_cumulative_output_count(2), 3);
+        assert_eq!(
+            blockchain.get_output(3).unwrap().txid,
+            alt_3.miner_tx.get_hash()
+        );
+
+        // Subscribers see the switch followed by the blocks of the new main chain
+        match events.try_recv().unwrap() {
+            ChainEvent::Reorganized {
+                split_height,
+                disconnected,
+            } => {
+                assert_eq!(split_height, 0);
+                assert_eq!(disconnected, vec![main_2.get_hash(), main_1.get_hash()]);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        for (expected_height, expected_block) in vec![(1, &alt_1), (2, &alt_2), (3, &alt_3)] {
+            match events.try_recv().unwrap() {
+                ChainEvent::NewBlock { height, block } => {
+                    assert_eq!(height, expected_height);
+                    assert_eq!(block.get_hash(), expected_block.get_hash());
+                }
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn subscribers_miss_the_oldest_events_when_falling_behind() {
+        let mut blockchain = ephemeral_blockchain();
+        let mut events = blockchain.subscribe();
+
+        let mut tail = blockchain.get_block_by_height(0).unwrap();
+        for timestamp in 0..EVENT_CAPACITY as u64 + 1 {
+            tail = next_block(&tail, 1000 + timestamp);
+            blockchain.add_new_block(tail.clone()).unwrap();
         }
+
+        // The event of the first block was dropped to make room for the last one
+        assert!(matches!(
+            events.try_recv(),
+            Err(broadcast::TryRecvError::Lagged(1))
+        ));
+        for expected_height in 2..=EVENT_CAPACITY as u64 + 1 {
+            match events.try_recv().unwrap() {
+                ChainEvent::NewBlock { height, block } => {
+                    assert_eq!(height, expected_height);
+                    assert_eq!(block.height(), Some(expected_height));
+                }
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        assert_eq!(blockchain.get_tail().unwrap().1.get_hash(), tail.get_hash());
+        assert!(matches!(
+            events.try_recv(),
+            Err(broadcast::TryRecvError::Empty)
+        ));
+    }
+
+    #[test]
+    fn block_templates_extend_the_tail() {
+        let mut blockchain = ephemeral_blockchain();
+        let miner_address = Address::standard(
+            KeyPair::generate().public_key,
+            KeyPair::generate().public_key,
+        );
+
+        let template = blockchain.create_block_template(&miner_address, 0);
+        assert_eq!(template.height(), Some(1));
+        assert_eq!(
+            template.header.prev_id,
+            blockchain.get_tail().unwrap().1.get_hash()
+        );
+        assert_eq!(
+            template.header.miner_specific,
+            miner_address.spend_public_key
+        );
+
+        blockchain.add_new_block(template).unwrap();
+        assert_eq!(blockchain.get_tail().unwrap().0, 1);
+
+        // The coinbase amount must include exactly the block's fees
+        let mut template = blockchain.create_block_template(&miner_address, 0);
+        template.miner_tx.prefix.outputs[0].amount += 1;
+        assert!(matches!(
+            blockchain.add_new_block(template),
+            Err(Error::InvalidTransaction)
+        ));
+    }
+
+    #[test]
+    fn alternative_blocks_pay_fees_of_confirmed_transactions() {
+        let mut blockchain = ephemeral_blockchain();
+        let genesis = blockchain.get_block_by_height(0).unwrap();
+
+        // Confirm a fee-paying transaction on the main chain
+        let transaction = fee_paying_transaction(1000);
+        let mut main_1 = next_block(&genesis, 1000);
+        main_1.tx_hashes = vec![transaction.get_hash()];
+        main_1.miner_tx.prefix.outputs[0].amount += 1000;
+        blockchain
+            .blockchain_db
+            .add_block(main_1, vec![transaction.clone()], 1)
+            .unwrap();
+
+        // An alternative block confirming it again must pay its fee to the miner
+        let mut alt_1 = next_block(&genesis, 2000);
+        alt_1.tx_hashes = vec![transaction.get_hash()];
+        assert!(matches!(
+            blockchain.add_new_block(alt_1.clone()),
+            Err(Error::InvalidTransaction)
+        ));
+
+        alt_1.miner_tx.prefix.outputs[0].amount += 1000;
+        assert!(matches!(
+            blockchain.add_new_block(alt_1.clone()),
+            Err(Error::AltChainBlock)
+        ));
+
+        // The transaction is kept with the alternative block in case the main chain drops it
+        assert_eq!(
+            blockchain.alternative_transactions[&alt_1.get_hash()]
+                .iter()
+                .map(GetHash::get_hash)
+                .collect::<Vec<_>>(),
+            vec![transaction.get_hash()]
+        );
     }
 }
@@ -1,23 +1,72 @@
 use std::collections::HashMap;
 
 use common::{GetHash, PreliminaryChecks, TXExtra, TXIn, TXNonce, Transaction};
-use crypto::Hash256;
+use crypto::{Hash256, KeyImage};
 use ensure_macro::ensure;
 use ringct::Error as RingCTError;
 
+/// Maximum size of a relayed transaction in bytes
+pub const MAX_TRANSACTION_SIZE: usize = 100_000;
+
+/// Minimum fee a relayed transaction has to pay for each of its bytes
+pub const MIN_FEE_PER_BYTE: u64 = 2_000;
+
 type Result<T> = std::result::Result<T, Error>;
 
+/// Gets the key images spent by the inputs of a transaction
+fn key_images(transaction: &Transaction) -> impl Iterator<Item = &KeyImage> {
+    transaction
+        .prefix
+        .inputs
+        .iter()
+        .filter_map(|input| match input {
+            TXIn::FromKey { key_image, .. } => Some(key_image),
+            TXIn::Gen(_) => None,
+        })
+}
+
+/// Error type for transaction pool operations
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    /// Returned when a transaction has an unsupported version
     #[error("Wrong transaction version. Expected {}", expected)]
-    WrongTransactionVersion { expected: u16 },
+    WrongTransactionVersion {
+        /// Transaction version expected by the pool
+        expected: u16,
+    },
 
+    /// Returned when a transaction does not have exactly one payment ID
     #[error("Incorrect payment ID count")]
     IncorrectPaymentIDCount,
 
+    /// Returned when a transaction contains an input that isn't allowed outside coinbase transactions
     #[error("Invalid transaction input")]
     InvalidTransactionInput,
 
+    /// Returned when a transaction spends a key image already spent by another transaction
+    /// in the pool
+    #[error("Transaction double spends a key image in the pool")]
+    DoubleSpend,
+
+    /// Returned when a transaction pays less than the minimum fee for its size
+    #[error("Transaction fee is too low. Expected at least {}", minimum)]
+    FeeTooLow {
+        /// Minimum fee for the transaction
+        minimum: u64,
+    },
+
+    /// Returned when a transaction is larger than the maximum transaction size
+    #[error("Transaction is larger than {} bytes", limit)]
+    TooBig {
+        /// Maximum size of a transaction
+        limit: usize,
+    },
+
+    /// Returned when a transaction doesn't have a RingCT signature
+    #[error("Transaction is missing its RingCT signature")]
+    MissingRingCTSignature,
+
+    /// Returned when the RingCT signature of a transaction fails verification
     #[error(transparent)]
     RingCT(#[from] RingCTError),
 }
@@ -26,9 +75,13 @@ pub enum Error {
 ///
 /// Handles transaction verification as transactions can only
 /// be confirmed if they are in the transaction pool
+///
+/// Transactions in the Dandelion++ stem phase are kept in a separate stem pool. They
+/// are verified like any other transaction but are not served to peers until fluffed
 #[derive(Default)]
 pub struct TXPool {
     transactions: HashMap<Hash256, Transaction>,
+    stem_transactions: HashMap<Hash256, Transaction>,
 }
 
 impl TXPool {
@@ -36,6 +89,7 @@ impl TXPool {
     pub fn new() -> Self {
         TXPool {
             transactions: HashMap::new(),
+            stem_transactions: HashMap::new(),
         }
     }
 
@@ -55,9 +109,142 @@ impl TXPool {
         self.transactions.contains_key(txid)
     }
 
+    /// Gets a reference to the transaction with the given txid
+    pub fn get_transaction(&self, txid: &Hash256) -> Option<&Transaction> {
+        self.transactions.get(txid)
+    }
+
+    /// Gets the transactions of this TXPool, ordered by decreasing fee
+    ///
+    /// Transactions in the stem phase aren't included as they must not be mined yet
+    pub fn get_transactions_by_fee(&self) -> Vec<&Transaction> {
+        let mut transactions = self.transactions.values().collect::<Vec<_>>();
+        transactions.sort_by_key(|transaction| std::cmp::Reverse(transaction.fee()));
+
+        transactions
+    }
+
+    /// Gets the number of transactions in this TXPool
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Gets the total size of the transactions in this TXPool in bytes
+    pub fn get_weight(&self) -> usize {
+        self.transactions.values().map(Transaction::size).sum()
+    }
+
     /// Takes the transaction, removing it from the TXPool in the process
+    ///
+    /// Transactions still in the stem phase are taken from the stem pool
     pub fn take_transaction(&mut self, txid: &Hash256) -> Option<Transaction> {
-        self.transactions.remove(txid)
+        self.transactions
+            .remove(txid)
+            .or_else(|| self.stem_transactions.remove(txid))
+    }
+
+    /// Checks if a key image is spent by a transaction in the TXPool
+    ///
+    /// Transactions in the stem phase aren't included so they aren't revealed before being
+    /// fluffed
+    pub fn has_key_image(&self, key_image: &KeyImage) -> bool {
+        self.transactions
+            .values()
+            .flat_map(key_images)
+            .any(|existing| existing == key_image)
+    }
+
+    /// Checks if a key image is spent by a transaction in the TXPool or the stem pool
+    fn is_double_spend(&self, key_image: &KeyImage) -> bool {
+        self.transactions
+            .values()
+            .chain(self.stem_transactions.values())
+            .flat_map(key_images)
+            .any(|existing| existing == key_image)
+    }
+
+    /// Removes the transactions of the TXPool and the stem pool that spend any of the key
+    /// images spent by the given transactions
+    ///
+    /// # Returns
+    /// The removed transactions
+    pub fn remove_double_spends(&mut self, transactions: &[Transaction]) -> Vec<Transaction> {
+        let spent = transactions.iter().flat_map(key_images).collect::<Vec<_>>();
+        let conflicting = self
+            .transactions
+            .iter()
+            .chain(self.stem_transactions.iter())
+            .filter(|(_, transaction)| {
+                key_images(transaction).any(|key_image| spent.contains(&key_image))
+            })
+            .map(|(txid, _)| txid.clone())
+            .collect::<Vec<_>>();
+
+        conflicting
+            .iter()
+            .filter_map(|txid| self.take_transaction(txid))
+            .collect()
+    }
+
+    /// Verifies transactions on their own, regardless of the transactions in the TXPool
+    ///
+    /// Used for transactions kept outside of the pool, such as those of alternative blocks
+    pub fn verify(transactions: &[Transaction]) -> Result<()> {
+        TXPool::new().check(&transactions)
+    }
+
+    /// Checks that a transaction follows the relay policy, which limits its size and sets a
+    /// minimum fee
+    ///
+    /// The policy only applies to transactions relayed on their own. Transactions confirmed
+    /// by a block are accepted regardless of it
+    pub fn check_policy(transaction: &Transaction) -> Result<()> {
+        let size = transaction.size();
+        ensure!(
+            size <= MAX_TRANSACTION_SIZE,
+            Error::TooBig {
+                limit: MAX_TRANSACTION_SIZE
+            }
+        );
+
+        let minimum = size as u64 * MIN_FEE_PER_BYTE;
+        ensure!(transaction.fee() >= minimum, Error::FeeTooLow { minimum });
+
+        Ok(())
+    }
+
+    // Stem pool
+    /// Add an unconfirmed transaction in the stem phase to the stem pool
+    pub fn add_stem_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        self.check(&std::slice::from_ref(&transaction))?;
+
+        self.stem_transactions
+            .insert(transaction.get_hash(), transaction);
+
+        Ok(())
+    }
+
+    /// Check if the stem pool contains the given transaction using the txid
+    pub fn has_stem_transaction(&self, txid: &Hash256) -> bool {
+        self.stem_transactions.contains_key(txid)
+    }
+
+    /// Gets a reference to the transaction in the stem pool with the given txid
+    pub fn get_stem_transaction(&self, txid: &Hash256) -> Option<&Transaction> {
+        self.stem_transactions.get(txid)
+    }
+
+    /// Moves a transaction from the stem pool to the main pool
+    ///
+    /// # Returns
+    /// `true` if the transaction was in the stem pool
+    pub fn fluff_transaction(&mut self, txid: &Hash256) -> bool {
+        if let Some(transaction) = self.stem_transactions.remove(txid) {
+            self.transactions.insert(txid.clone(), transaction);
+            true
+        } else {
+            false
+        }
     }
 }
 
@@ -65,7 +252,7 @@ impl PreliminaryChecks<&[Transaction]> for TXPool {
     type Error = Error;
 
     fn check(&self, transactions: &&[Transaction]) -> Result<()> {
-        for tx in transactions.iter() {
+        for (i, tx) in transactions.iter().enumerate() {
             // All transactions must be v2 (RingCT enabled)
             ensure!(
                 tx.prefix.version == 2,
@@ -97,8 +284,19 @@ impl PreliminaryChecks<&[Transaction]> for TXPool {
                 }
             }
 
-            // TODO: Check transaction fee
-            // TODO: Check transaction weight
+            // Key images must not be spent by other transactions in the pool or in this batch
+            for key_image in key_images(tx) {
+                ensure!(!self.is_double_spend(key_image), Error::DoubleSpend);
+                ensure!(
+                    !transactions[..i]
+                        .iter()
+                        .flat_map(key_images)
+                        .any(|existing| existing == key_image),
+                    Error::DoubleSpend
+                );
+            }
+
+            ensure!(tx.rct_signature.is_some(), Error::MissingRingCTSignature);
         }
 
         let signatures = transactions
@@ -111,3 +309,80 @@ impl PreliminaryChecks<&[Transaction]> for TXPool {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use common::TransactionPrefix;
+    use crypto::{Hash8, KeyPair};
+
+    use super::*;
+
+    fn spending_transaction(key_image: KeyImage) -> Transaction {
+        Transaction {
+            prefix: TransactionPrefix {
+                version: 2,
+                inputs: vec![TXIn::FromKey {
+                    key_offsets: vec![0],
+                    key_image,
+                }],
+                extra: vec![TXExtra::TxNonce(TXNonce::EncryptedPaymentId(
+                    Hash8::null_hash(),
+                ))],
+                ..TransactionPrefix::default()
+            },
+            rct_signature: None,
+        }
+    }
+
+    #[test]
+    fn it_rejects_double_spends_and_low_fees() {
+        let tx_pool = TXPool::new();
+        let key_image = KeyPair::generate().public_key;
+
+        let transactions = [
+            spending_transaction(key_image),
+            spending_transaction(key_image),
+        ];
+        assert!(matches!(
+            tx_pool.check(&&transactions[..]),
+            Err(Error::DoubleSpend)
+        ));
+
+        let minimum = transactions[0].size() as u64 * MIN_FEE_PER_BYTE;
+        assert!(matches!(
+            TXPool::check_policy(&transactions[0]),
+            Err(Error::FeeTooLow { minimum: expected }) if expected == minimum
+        ));
+    }
+
+    #[test]
+    fn it_removes_double_spends_from_both_pools() {
+        let mut tx_pool = TXPool::new();
+        let pool_key_image = KeyPair::generate().public_key;
+        let stem_key_image = KeyPair::generate().public_key;
+
+        let pool_transaction = spending_transaction(pool_key_image);
+        let stem_transaction = spending_transaction(stem_key_image);
+        tx_pool
+            .transactions
+            .insert(pool_transaction.get_hash(), pool_transaction);
+        tx_pool
+            .stem_transactions
+            .insert(stem_transaction.get_hash(), stem_transaction);
+
+        // Stem transactions still count as double spends, but aren't revealed
+        assert!(tx_pool.has_key_image(&pool_key_image));
+        assert!(!tx_pool.has_key_image(&stem_key_image));
+        assert!(tx_pool.is_double_spend(&stem_key_image));
+
+        let mut block_transaction = spending_transaction(pool_key_image);
+        block_transaction.prefix.inputs.push(TXIn::FromKey {
+            key_offsets: vec![0],
+            key_image: stem_key_image,
+        });
+        let removed = tx_pool.remove_double_spends(&[block_transaction]);
+        assert_eq!(removed.len(), 2);
+        assert!(!tx_pool.is_double_spend(&pool_key_image));
+        assert!(!tx_pool.is_double_spend(&stem_key_image));
+    }
+}
@@ -7,7 +7,8 @@ use structopt::StructOpt;
 #[structopt(rename_all = "kebab-case", name = "Unprll")]
 pub struct Config {
     // Blockchain DB
-    /// Type of database
+    /// Type of database: `memory`, or `ephemeral` for a memory database that is never stored
+    /// on disk
     #[structopt(long, default_value = "memory")]
     pub db_type: String,
 
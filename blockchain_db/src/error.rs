@@ -16,6 +16,10 @@ pub enum Error {
     #[error("Object exists in main chain")]
     Exists,
 
+    /// Returned when a transaction spends a key image that was spent in the chain already
+    #[error("Key image was spent in main chain")]
+    KeyImageSpent,
+
     /// Returned when a block/transaction/key image does not exist in the chain when it should
     #[error("Object does not exist in main chain")]
     DoesNotExist,
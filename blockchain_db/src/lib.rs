@@ -19,9 +19,12 @@ mod config;
 mod error;
 // mod lmdb;
 mod mem;
+mod output;
 
 pub use config::Config;
 pub use error::{Error, Result};
+pub use mem::LegacyChain;
+pub use output::OutputEntry;
 
 /// Manages communication between the database and the rest of the application.
 trait BlockchainDBDriver {
@@ -34,7 +37,7 @@ trait BlockchainDBDriver {
     fn fixup(&self);
 
     // Block
-    fn add_block(&mut self, block: Block) -> Result<()>;
+    fn add_block(&mut self, block: Block, cumulative_difficulty: u128) -> Result<()>;
     fn get_block_by_height(&self, height: u64) -> Option<Block>;
     fn get_block_by_hash(&self, block_id: &Hash256) -> Option<Block>;
 
@@ -42,15 +45,24 @@ trait BlockchainDBDriver {
     fn get_tail(&self) -> Option<(u64, Block)>;
     fn pop_block(&mut self) -> Option<Block>;
 
-    fn get_cumulative_difficulty(&self) -> u64;
+    fn get_cumulative_difficulty(&self, height: u64) -> Option<u128>;
 
     // Confirmed Transactions
-    fn add_transaction(&mut self, transaction: Transaction) -> Result<()>;
+    fn add_transaction(&mut self, transaction: Transaction, height: u64) -> Result<()>;
     fn get_transaction(&self, id: &Hash256) -> Option<Transaction>;
+    fn get_transaction_height(&self, id: &Hash256) -> Option<u64>;
+    fn remove_transaction(&mut self, id: &Hash256) -> Option<Transaction>;
 
     // Key Image
     fn add_key_image(&mut self, key_image: KeyImage) -> Result<()>;
     fn has_key_image(&self, key_image: &KeyImage) -> bool;
+    fn remove_key_image(&mut self, key_image: &KeyImage);
+
+    // Outputs
+    fn add_output(&mut self, output: OutputEntry) -> Result<()>;
+    fn get_output(&self, index: u64) -> Option<OutputEntry>;
+    fn get_output_count(&self) -> u64;
+    fn pop_output(&mut self) -> Option<OutputEntry>;
 }
 
 /// # Blockchain database
@@ -63,17 +75,34 @@ trait BlockchainDBDriver {
 /// 3. Confirmed transactions can only be added via blocks
 pub struct BlockchainDB {
     db: Box<dyn BlockchainDBDriver + Sync + Send>,
+    legacy_chain: LegacyChain,
 }
 
 impl BlockchainDB {
     /// Creates a new BlockchainDB with the specified configuration
-    pub fn new(config: &Config) -> Self {
-        BlockchainDB {
-            db: match config.db_type.as_ref() {
-                "memory" => Box::new(mem::BlockchainMemDB::new(config)),
-                _ => panic!(),
-            },
-        }
+    pub fn new(config: &Config) -> Result<Self> {
+        let (db, legacy_chain): (Box<dyn BlockchainDBDriver + Sync + Send>, _) =
+            match config.db_type.as_ref() {
+                "memory" => {
+                    let (db, legacy_chain) = mem::BlockchainMemDB::new(config)?;
+                    (Box::new(db), legacy_chain)
+                }
+                "ephemeral" => (Box::new(mem::BlockchainMemDB::ephemeral()), Vec::new()),
+                db_type => {
+                    return Err(Error::Internal(
+                        format!("Unknown database type {}", db_type).into(),
+                    ))
+                }
+            };
+
+        Ok(BlockchainDB { db, legacy_chain })
+    }
+
+    /// Takes the blocks of a database that was stored in an older format
+    ///
+    /// Such databases are started over, so their blocks have to be verified and added again
+    pub fn take_legacy_chain(&mut self) -> LegacyChain {
+        std::mem::take(&mut self.legacy_chain)
     }
 
     /// Adds a new block to the chain
@@ -83,24 +112,86 @@ impl BlockchainDB {
     /// 2. That block doesn't exist already
     /// 3. All transactions in the block don't exist already
     /// 4. All key images in the block don't exist already
-    pub fn add_block(&mut self, block: Block, transactions: Vec<Transaction>) -> Result<()> {
+    ///
+    /// `difficulty` is the difficulty the block satisfied, and is added to the chain's
+    /// cumulative difficulty
+    pub fn add_block(
+        &mut self,
+        block: Block,
+        transactions: Vec<Transaction>,
+        difficulty: u128,
+    ) -> Result<()> {
         // Do preliminary checks
         self.check(&block)?;
         for tx in transactions.iter() {
             self.check(tx)?;
         }
         // Then insert everything
-        self.db.add_transaction(block.miner_tx.clone())?;
+        let height = block.height().ok_or(Error::InvalidHeight)?;
+        let outputs = std::iter::once(&block.miner_tx)
+            .chain(transactions.iter())
+            .flat_map(|tx| OutputEntry::from_transaction(tx, height))
+            .collect::<Vec<_>>();
+        for output in outputs {
+            self.db.add_output(output)?;
+        }
+
+        self.db.add_transaction(block.miner_tx.clone(), height)?;
         for tx in transactions.into_iter() {
             for input in tx.prefix.inputs.iter() {
                 if let TXIn::FromKey { key_image, .. } = input {
                     self.db.add_key_image(*key_image)?;
                 }
             }
-            self.db.add_transaction(tx)?;
+            self.db.add_transaction(tx, height)?;
+        }
+
+        let cumulative_difficulty = self.get_cumulative_difficulty() + difficulty;
+        self.db.add_block(block, cumulative_difficulty)
+    }
+
+    /// Removes the tail block from the chain, along with its transactions, their key images
+    /// and outputs
+    ///
+    /// The genesis block can't be removed
+    ///
+    /// # Returns
+    /// The removed block and its transactions, in the order of its transaction hashes
+    pub fn pop_block(&mut self) -> Result<(Block, Vec<Transaction>)> {
+        match self.db.get_tail() {
+            Some((height, _)) if height > 0 => {}
+            _ => return Err(Error::DoesNotExist),
+        }
+
+        let block = self.db.pop_block().ok_or(Error::DoesNotExist)?;
+        self.db.remove_transaction(&block.miner_tx.get_hash());
+
+        // Outputs are stored in chain order, so those of the tail block come last
+        while self
+            .get_output_count()
+            .checked_sub(1)
+            .and_then(|index| self.db.get_output(index))
+            .map_or(false, |output| Some(output.height) == block.height())
+        {
+            self.db.pop_output();
+        }
+
+        let transactions = block
+            .tx_hashes
+            .iter()
+            .filter_map(|txid| self.db.remove_transaction(txid))
+            .collect::<Vec<_>>();
+        for tx in transactions.iter() {
+            for input in tx.prefix.inputs.iter() {
+                if let TXIn::FromKey { key_image, .. } = input {
+                    self.db.remove_key_image(key_image);
+                }
+            }
         }
 
-        self.db.add_block(block)
+        self.db.sync()?;
+
+        Ok((block, transactions))
     }
 
     // Passthrough
@@ -113,14 +204,61 @@ impl BlockchainDB {
     pub fn get_block_by_hash(&self, hash: &Hash256) -> Option<Block> {
         self.db.get_block_by_hash(hash)
     }
+    /// Gets the size of the database in bytes
+    pub fn size(&self) -> u64 {
+        self.db.size()
+    }
     /// Gets the current chain tail
     pub fn get_tail(&self) -> Option<(u64, Block)> {
         self.db.get_tail()
     }
+    /// Gets the cumulative difficulty of the main chain up to its tail
+    ///
+    /// Returns 0 for an empty chain
+    pub fn get_cumulative_difficulty(&self) -> u128 {
+        self.db
+            .get_tail()
+            .and_then(|(height, _)| self.db.get_cumulative_difficulty(height))
+            .unwrap_or(0)
+    }
+    /// Gets the cumulative difficulty of the main chain up to the given height
+    pub fn get_cumulative_difficulty_at(&self, height: u64) -> Option<u128> {
+        self.db.get_cumulative_difficulty(height)
+    }
     /// Gets the transaction with the given txid
     pub fn get_transaction(&self, txid: &Hash256) -> Option<Transaction> {
         self.db.get_transaction(txid)
     }
+    /// Gets the height of the block that confirmed the transaction with the given txid
+    pub fn get_transaction_height(&self, txid: &Hash256) -> Option<u64> {
+        self.db.get_transaction_height(txid)
+    }
+    /// Checks if the given key image was spent in the main chain
+    pub fn has_key_image(&self, key_image: &KeyImage) -> bool {
+        self.db.has_key_image(key_image)
+    }
+    /// Gets the output with the given global output index
+    pub fn get_output(&self, index: u64) -> Option<OutputEntry> {
+        self.db.get_output(index)
+    }
+    /// Gets the number of outputs in the main chain
+    pub fn get_output_count(&self) -> u64 {
+        self.db.get_output_count()
+    }
+    /// Gets the number of outputs in the main chain up to and including the given height
+    pub fn get_cumulative_output_count(&self, height: u64) -> u64 {
+        // Outputs are stored in chain order, so search for the first one above the height
+        let (mut low, mut high) = (0, self.get_output_count());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.db.get_output(middle) {
+                Some(output) if output.height <= height => low = middle + 1,
+                _ => high = middle,
+            }
+        }
+
+        low
+    }
 }
 
 impl PreliminaryChecks<Block> for BlockchainDB {
@@ -181,7 +319,7 @@ impl PreliminaryChecks<Transaction> for BlockchainDB {
             if let TXIn::FromKey { key_image, .. } = input {
                 // 6. We don't have any of the key images already
                 if self.db.has_key_image(key_image) {
-                    return Err(Error::Exists);
+                    return Err(Error::KeyImageSpent);
                 }
             }
         }
@@ -1,29 +1,115 @@
-use std::{collections::HashMap, convert::TryInto, fs::File, io::ErrorKind, path::PathBuf};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    fs::File,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
 
 use log::{debug, info, warn};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use common::{Block, GetHash, Transaction};
 use crypto::{Hash256, KeyImage};
 
 use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::BlockchainDBDriver;
+use crate::{BlockchainDBDriver, OutputEntry};
+
+/// Marks MemDB files stored along with their format version
+///
+/// Files from before the format was versioned start with the length of the DB path instead,
+/// which is never this large
+const MEMDB_MAGIC: [u8; 8] = *b"UNPRLLDB";
+
+/// Version of the MemDB file format. Bump it whenever the fields of `BlockchainMemDB` change
+const MEMDB_VERSION: u32 = 3;
+
+/// Blocks of a database stored in an older format, in height order along with their transactions
+pub type LegacyChain = Vec<(Block, Vec<Transaction>)>;
+
+/// Layout of MemDB files from before the format was versioned
+#[derive(Deserialize)]
+struct LegacyMemDB {
+    _db_path: PathBuf,
+    blocks: HashMap<Hash256, Block>,
+    block_heights: HashMap<u64, Hash256>,
+    transactions: HashMap<Hash256, Transaction>,
+}
+
+impl LegacyMemDB {
+    fn into_chain(self) -> LegacyChain {
+        chain_by_height(self.blocks, &self.block_heights, self.transactions)
+    }
+}
+
+/// Leading fields of MemDB files of every older version, which are enough to rebuild the chain
+#[derive(Deserialize)]
+struct VersionedLegacyMemDB {
+    _db_path: PathBuf,
+    blocks: HashMap<Hash256, Block>,
+    block_heights: HashMap<u64, Hash256>,
+    _cumulative_difficulties: HashMap<u64, u128>,
+    transactions: HashMap<Hash256, Transaction>,
+}
+
+impl VersionedLegacyMemDB {
+    fn into_chain(self) -> LegacyChain {
+        chain_by_height(self.blocks, &self.block_heights, self.transactions)
+    }
+}
+
+/// Puts the stored blocks in height order along with their transactions
+fn chain_by_height(
+    mut blocks: HashMap<Hash256, Block>,
+    block_heights: &HashMap<u64, Hash256>,
+    mut transactions: HashMap<Hash256, Transaction>,
+) -> LegacyChain {
+    let mut chain = Vec::new();
+
+    for height in 0..block_heights.len() as u64 {
+        let block = match block_heights
+            .get(&height)
+            .and_then(|block_id| blocks.remove(block_id))
+        {
+            Some(block) => block,
+            None => break,
+        };
+        let transactions = block
+            .tx_hashes
+            .iter()
+            .filter_map(|txid| transactions.remove(txid))
+            .collect();
+
+        chain.push((block, transactions));
+    }
+
+    chain
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct BlockchainMemDB {
     db_path: PathBuf,
+    #[serde(skip)]
+    ephemeral: bool,
 
     blocks: HashMap<Hash256, Block>,
     block_heights: HashMap<u64, Hash256>,
+    cumulative_difficulties: HashMap<u64, u128>,
 
     transactions: HashMap<Hash256, Transaction>,
+    transaction_heights: HashMap<Hash256, u64>,
     unconfirmed_transactions: HashMap<Hash256, Transaction>,
     key_images: Vec<KeyImage>,
+    outputs: Vec<OutputEntry>,
 }
 
 impl BlockchainMemDB {
-    pub fn new(config: &Config) -> BlockchainMemDB {
+    /// Opens the database in the data directory, or creates an empty one if it doesn't exist
+    ///
+    /// Databases stored in an older format are moved aside and started over. Their blocks are
+    /// returned so they can be verified and added again
+    pub fn new(config: &Config) -> Result<(BlockchainMemDB, LegacyChain)> {
         let mut db_path = config
             .db_data_directory
             .clone()
@@ -42,25 +128,95 @@ impl BlockchainMemDB {
         db_path.push("blockchain");
         db_path.set_extension("dat");
 
-        let file = File::open(&db_path);
-
-        if let Ok(file) = file {
-            info!("MemDB file found. Loading...");
-            bincode::deserialize_from(file).unwrap()
-        } else {
-            warn!("MemDB file doesn't exist. Generating new database...");
-            let db = BlockchainMemDB {
-                db_path,
-                blocks: HashMap::new(),
-                block_heights: HashMap::new(),
-                key_images: Vec::new(),
-                transactions: HashMap::new(),
-                unconfirmed_transactions: HashMap::new(),
-            };
-            db.sync().unwrap();
-            db
+        let mut file = match File::open(&db_path) {
+            Ok(file) => file,
+            Err(_) => {
+                warn!("MemDB file doesn't exist. Generating new database...");
+                let db = BlockchainMemDB::empty(db_path, false);
+                db.sync()?;
+                return Ok((db, Vec::new()));
+            }
+        };
+
+        info!("MemDB file found. Loading...");
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic).map_err(internal_error)?;
+        if magic != MEMDB_MAGIC {
+            let file = File::open(&db_path).map_err(internal_error)?;
+            let legacy: LegacyMemDB = read_legacy(&db_path, file)?;
+            return Self::start_over(db_path, "an unversioned format", legacy.into_chain());
         }
+
+        let version: u32 = bincode::deserialize_from(&mut file).map_err(internal_error)?;
+        if version < MEMDB_VERSION {
+            let legacy: VersionedLegacyMemDB = read_legacy(&db_path, file)?;
+            let format = format!("version {} of the format", version);
+            return Self::start_over(db_path, &format, legacy.into_chain());
+        }
+        if version > MEMDB_VERSION {
+            return Err(internal_error(format!(
+                "MemDB file version {} is not supported. Expected version {}",
+                version, MEMDB_VERSION
+            )));
+        }
+
+        let db = bincode::deserialize_from(file).map_err(internal_error)?;
+        Ok((db, Vec::new()))
     }
+
+    /// Moves a database stored in an older format aside and starts over
+    fn start_over(
+        db_path: PathBuf,
+        format: &str,
+        legacy_chain: LegacyChain,
+    ) -> Result<(BlockchainMemDB, LegacyChain)> {
+        let backup_path = db_path.with_extension("dat.legacy");
+        std::fs::rename(&db_path, &backup_path).map_err(internal_error)?;
+        warn!(
+            "MemDB file is in {}. Moved it to {} and rebuilding the database",
+            format,
+            backup_path.display()
+        );
+
+        let db = BlockchainMemDB::empty(db_path, false);
+        db.sync()?;
+        Ok((db, legacy_chain))
+    }
+
+    /// Creates an empty database that is never stored on disk
+    pub fn ephemeral() -> BlockchainMemDB {
+        BlockchainMemDB::empty(PathBuf::new(), true)
+    }
+
+    fn empty(db_path: PathBuf, ephemeral: bool) -> BlockchainMemDB {
+        BlockchainMemDB {
+            db_path,
+            ephemeral,
+            blocks: HashMap::new(),
+            block_heights: HashMap::new(),
+            cumulative_difficulties: HashMap::new(),
+            key_images: Vec::new(),
+            outputs: Vec::new(),
+            transactions: HashMap::new(),
+            transaction_heights: HashMap::new(),
+            unconfirmed_transactions: HashMap::new(),
+        }
+    }
+}
+
+/// Reads the chain stored in a MemDB file of an older format
+fn read_legacy<T: DeserializeOwned>(db_path: &Path, file: File) -> Result<T> {
+    bincode::deserialize_from(file).map_err(|error| {
+        internal_error(format!(
+            "MemDB file {} is corrupt or in an unknown format ({}). Remove it to sync again",
+            db_path.display(),
+            error
+        ))
+    })
+}
+
+fn internal_error(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::Internal(error.into())
 }
 
 impl BlockchainDBDriver for BlockchainMemDB {
@@ -68,9 +224,15 @@ impl BlockchainDBDriver for BlockchainMemDB {
         false
     }
     fn sync(&self) -> Result<()> {
-        let file = File::create(&self.db_path).map_err(|err| Error::Internal(err.into()))?;
+        if self.ephemeral {
+            return Ok(());
+        }
+
+        let mut file = File::create(&self.db_path).map_err(internal_error)?;
 
-        bincode::serialize_into(file, &self).map_err(|err| Error::Internal(err.into()))?;
+        bincode::serialize_into(&mut file, &MEMDB_MAGIC).map_err(internal_error)?;
+        bincode::serialize_into(&mut file, &MEMDB_VERSION).map_err(internal_error)?;
+        bincode::serialize_into(file, &self).map_err(internal_error)?;
         debug!("Saved MemDB file");
 
         Ok(())
@@ -79,27 +241,31 @@ impl BlockchainDBDriver for BlockchainMemDB {
     fn reset(&mut self) {
         self.blocks.clear();
         self.block_heights.clear();
+        self.cumulative_difficulties.clear();
         self.key_images.clear();
+        self.outputs.clear();
         self.transactions.clear();
+        self.transaction_heights.clear();
         self.unconfirmed_transactions.clear();
     }
     fn size(&self) -> u64 {
-        std::mem::size_of_val(&self)
-            .try_into()
-            .unwrap_or(u64::max_value())
+        // Ephemeral databases are never stored
+        std::fs::metadata(&self.db_path).map_or(0, |metadata| metadata.len())
     }
     fn fixup(&self) {}
 
     // Block
-    fn add_block(&mut self, block: Block) -> Result<()> {
+    fn add_block(&mut self, block: Block, cumulative_difficulty: u128) -> Result<()> {
         let block_id = block.get_hash();
+        let height = self
+            .get_tail()
+            .map(|(current_height, _)| current_height + 1)
+            .unwrap_or(0);
+
         self.blocks.insert(block_id.clone(), block);
-        self.block_heights.insert(
-            self.get_tail()
-                .map(|(current_height, _)| current_height + 1)
-                .unwrap_or(0),
-            block_id,
-        );
+        self.block_heights.insert(height, block_id);
+        self.cumulative_difficulties
+            .insert(height, cumulative_difficulty);
         self.sync()
     }
     fn get_block_by_height(&self, height: u64) -> Option<Block> {
@@ -109,9 +275,8 @@ impl BlockchainDBDriver for BlockchainMemDB {
     fn get_block_by_hash(&self, block_id: &Hash256) -> Option<Block> {
         self.blocks.get(&block_id).cloned()
     }
-    fn get_cumulative_difficulty(&self) -> u64 {
-        // TODO:
-        100
+    fn get_cumulative_difficulty(&self, height: u64) -> Option<u128> {
+        self.cumulative_difficulties.get(&height).cloned()
     }
     fn get_tail(&self) -> Option<(u64, Block)> {
         let mut height: u64 = self.block_heights.iter().count().try_into().unwrap();
@@ -123,24 +288,34 @@ impl BlockchainDBDriver for BlockchainMemDB {
     }
     fn pop_block(&mut self) -> Option<Block> {
         let (height, _) = self.get_tail()?;
-        let block_id = self.block_heights.get(&height)?;
+        let block_id = self.block_heights.remove(&height)?;
+        self.cumulative_difficulties.remove(&height);
 
         // At this point, it can be assumed the block exists on both tables
         let (_, block) = self
             .blocks
-            .remove_entry(block_id)
+            .remove_entry(&block_id)
             .expect("Inconsistent state");
         Some(block)
     }
 
-    fn add_transaction(&mut self, transaction: Transaction) -> Result<()> {
-        self.transactions
-            .insert(transaction.get_hash(), transaction);
+    fn add_transaction(&mut self, transaction: Transaction, height: u64) -> Result<()> {
+        let txid = transaction.get_hash();
+
+        self.transaction_heights.insert(txid.clone(), height);
+        self.transactions.insert(txid, transaction);
         Ok(())
     }
     fn get_transaction(&self, id: &Hash256) -> Option<Transaction> {
         self.transactions.get(id).cloned()
     }
+    fn get_transaction_height(&self, id: &Hash256) -> Option<u64> {
+        self.transaction_heights.get(id).cloned()
+    }
+    fn remove_transaction(&mut self, id: &Hash256) -> Option<Transaction> {
+        self.transaction_heights.remove(id);
+        self.transactions.remove(id)
+    }
 
     fn add_key_image(&mut self, key_image: KeyImage) -> Result<()> {
         self.key_images.push(key_image);
@@ -149,6 +324,23 @@ impl BlockchainDBDriver for BlockchainMemDB {
     fn has_key_image(&self, key_image: &KeyImage) -> bool {
         self.key_images.contains(key_image)
     }
+    fn remove_key_image(&mut self, key_image: &KeyImage) {
+        self.key_images.retain(|existing| existing != key_image);
+    }
+
+    fn add_output(&mut self, output: OutputEntry) -> Result<()> {
+        self.outputs.push(output);
+        Ok(())
+    }
+    fn get_output(&self, index: u64) -> Option<OutputEntry> {
+        self.outputs.get(usize::try_from(index).ok()?).cloned()
+    }
+    fn get_output_count(&self) -> u64 {
+        self.outputs.len() as u64
+    }
+    fn pop_output(&mut self) -> Option<OutputEntry> {
+        self.outputs.pop()
+    }
 }
 
 impl Drop for BlockchainMemDB {
@@ -156,3 +348,132 @@ impl Drop for BlockchainMemDB {
         self.sync().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Layout of the whole MemDB file from before the format was versioned
+    #[derive(Serialize)]
+    struct UnversionedMemDB {
+        db_path: PathBuf,
+        blocks: HashMap<Hash256, Block>,
+        block_heights: HashMap<u64, Hash256>,
+        transactions: HashMap<Hash256, Transaction>,
+        unconfirmed_transactions: HashMap<Hash256, Transaction>,
+        key_images: Vec<KeyImage>,
+    }
+
+    /// Layout of the whole MemDB file of version 1, following the magic and version
+    #[derive(Serialize)]
+    struct MemDBVersion1 {
+        db_path: PathBuf,
+        blocks: HashMap<Hash256, Block>,
+        block_heights: HashMap<u64, Hash256>,
+        cumulative_difficulties: HashMap<u64, u128>,
+        transactions: HashMap<Hash256, Transaction>,
+        unconfirmed_transactions: HashMap<Hash256, Transaction>,
+        key_images: Vec<KeyImage>,
+    }
+
+    /// Creates a config with a data directory of its own, and returns the path of its DB file
+    fn config(name: &str) -> (Config, PathBuf) {
+        let config = Config {
+            db_type: "memory".to_string(),
+            db_data_directory: Some(std::env::temp_dir().join(format!(
+                "unprll_memdb_{}_{}",
+                name,
+                std::process::id()
+            ))),
+        };
+        let db_path = config
+            .db_data_directory
+            .clone()
+            .unwrap()
+            .join("memory")
+            .join("blockchain.dat");
+        std::fs::create_dir_all(db_path.parent().unwrap()).unwrap();
+
+        (config, db_path)
+    }
+
+    fn genesis_chain() -> (HashMap<Hash256, Block>, HashMap<u64, Hash256>) {
+        let genesis = Block::genesis();
+        (
+            vec![(genesis.get_hash(), genesis.clone())]
+                .into_iter()
+                .collect(),
+            vec![(0, genesis.get_hash())].into_iter().collect(),
+        )
+    }
+
+    #[test]
+    fn migrates_unversioned_files() {
+        let (config, db_path) = config("unversioned");
+
+        let genesis = Block::genesis();
+        let (blocks, block_heights) = genesis_chain();
+        let legacy = UnversionedMemDB {
+            db_path: db_path.clone(),
+            blocks,
+            block_heights,
+            transactions: HashMap::new(),
+            unconfirmed_transactions: HashMap::new(),
+            key_images: Vec::new(),
+        };
+        bincode::serialize_into(File::create(&db_path).unwrap(), &legacy).unwrap();
+
+        let (db, legacy_chain) = BlockchainMemDB::new(&config).unwrap();
+        assert_eq!(legacy_chain.len(), 1);
+        assert_eq!(legacy_chain[0].0.get_hash(), genesis.get_hash());
+        assert!(db.get_tail().is_none());
+        assert!(db_path.with_extension("dat.legacy").exists());
+        drop(db);
+
+        // The rebuilt database is stored in the current format
+        let (_, legacy_chain) = BlockchainMemDB::new(&config).unwrap();
+        assert!(legacy_chain.is_empty());
+
+        std::fs::write(&db_path, b"corrupt").unwrap();
+        assert!(BlockchainMemDB::new(&config).is_err());
+
+        std::fs::remove_dir_all(config.db_data_directory.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn migrates_older_versions() {
+        let (config, db_path) = config("older");
+
+        let (blocks, block_heights) = genesis_chain();
+        let legacy = MemDBVersion1 {
+            db_path: db_path.clone(),
+            blocks,
+            block_heights,
+            cumulative_difficulties: vec![(0, 1)].into_iter().collect(),
+            transactions: HashMap::new(),
+            unconfirmed_transactions: HashMap::new(),
+            key_images: Vec::new(),
+        };
+        let mut file = File::create(&db_path).unwrap();
+        bincode::serialize_into(&mut file, &MEMDB_MAGIC).unwrap();
+        bincode::serialize_into(&mut file, &1u32).unwrap();
+        bincode::serialize_into(file, &legacy).unwrap();
+
+        let (db, legacy_chain) = BlockchainMemDB::new(&config).unwrap();
+        assert_eq!(legacy_chain.len(), 1);
+        assert_eq!(legacy_chain[0].0.get_hash(), Block::genesis().get_hash());
+        assert!(db.get_tail().is_none());
+        assert!(db_path.with_extension("dat.legacy").exists());
+        drop(db);
+
+        // Files from newer versions are left alone
+        let mut file = File::create(&db_path).unwrap();
+        bincode::serialize_into(&mut file, &MEMDB_MAGIC).unwrap();
+        bincode::serialize_into(&mut file, &(MEMDB_VERSION + 1)).unwrap();
+        drop(file);
+        assert!(BlockchainMemDB::new(&config).is_err());
+        assert!(db_path.exists());
+
+        std::fs::remove_dir_all(config.db_data_directory.unwrap()).unwrap();
+    }
+}
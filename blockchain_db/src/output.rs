@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+use common::{GetHash, TXOutTarget, Transaction};
+use crypto::{ecc::Scalar, Hash256};
+use ringct::{Commitment, DestinationCommitmentPair};
+
+/// An output of a confirmed transaction, indexed by its global output index
+///
+/// These are the outputs wallets pick ring members from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutputEntry {
+    /// Destination key and amount commitment of the output
+    pub pair: DestinationCommitmentPair,
+    /// Height of the block that confirmed the output
+    pub height: u64,
+    /// Number of blocks after `height` the output stays locked for
+    pub unlock_delta: u16,
+    /// ID of the transaction the output belongs to
+    pub txid: Hash256,
+}
+
+impl OutputEntry {
+    /// Gets the entries for the outputs of a transaction confirmed at the given height
+    ///
+    /// Outputs without a RingCT signature (such as those of coinbase transactions) have their
+    /// amount in the clear. They're given a commitment with a mask of 1 so that they can be
+    /// used in rings like any other output
+    pub(crate) fn from_transaction(transaction: &Transaction, height: u64) -> Vec<Self> {
+        let txid = transaction.get_hash();
+
+        transaction
+            .prefix
+            .outputs
+            .iter()
+            .enumerate()
+            .map(|(i, output)| {
+                let TXOutTarget::ToKey { key: destination } = output.target;
+                let commitment = transaction
+                    .rct_signature
+                    .as_ref()
+                    .and_then(|signature| signature.base.output_commitments.get(i))
+                    .map(|pair| pair.commitment)
+                    .unwrap_or_else(|| {
+                        Commitment {
+                            value: Scalar::from(output.amount),
+                            mask: Scalar::one(),
+                        }
+                        .into_public()
+                    });
+
+                OutputEntry {
+                    pair: DestinationCommitmentPair {
+                        destination,
+                        commitment,
+                    },
+                    height,
+                    unlock_delta: transaction.prefix.unlock_delta,
+                    txid: txid.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Checks if the output can be spent on top of a chain with the given tail height
+    pub fn is_unlocked(&self, tail_height: u64) -> bool {
+        self.height + u64::from(self.unlock_delta) <= tail_height
+    }
+}
@@ -2,7 +2,7 @@ use std::convert::TryFrom;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{GetHash, TXExtra, TXIn, TXOut, TXOutTarget, Transaction, TransactionPrefix};
+use crate::{GetHash, TXExtra, TXIn, TXNonce, TXOut, TXOutTarget, Transaction, TransactionPrefix};
 use crypto::{ecc::PointExt, Hash256, PublicKey};
 
 /// Block Header
@@ -86,6 +86,26 @@ impl Block {
         }
     }
 
+    /// Gets the height of the block from the input of its coinbase transaction
+    pub fn height(&self) -> Option<u64> {
+        match self.miner_tx.prefix.inputs.first() {
+            Some(TXIn::Gen(height)) => Some(*height),
+            _ => None,
+        }
+    }
+
+    /// Gets the offset of the last `size` bytes of the coinbase transaction's prefix within the
+    /// serialized block
+    ///
+    /// Coinbase transactions of block templates keep the bytes reserved for miners at the end
+    /// of their extra, which is the last field of the prefix
+    pub fn get_reserved_offset(&self, size: usize) -> bincode::Result<usize> {
+        let prefix_end = bincode::serialized_size(&self.header)?
+            + bincode::serialized_size(&self.miner_tx.prefix)?;
+
+        Ok(prefix_end as usize - size)
+    }
+
     /// Gets the "mining blob" for a given block
     ///
     /// Used to generate the proof-of-work and thus doesn't serialize a few fields (notably
@@ -174,4 +194,24 @@ mod tests {
             "7d491759c7534ca5a8be62ec7fa34dc939659f5afd4b4f1da2c671a84773cedc"
         );
     }
+
+    #[test]
+    fn reserved_offset_points_at_the_extra_nonce() {
+        let mut block = Block::genesis();
+        block
+            .miner_tx
+            .prefix
+            .extra
+            .push(TXExtra::TxNonce(TXNonce::Extra(vec![0; 8])));
+        let offset = block.get_reserved_offset(8).unwrap();
+
+        // Filling in the reserved bytes of the blob fills in the nonce
+        let mut blob = bincode::serialize(&block).unwrap();
+        blob[offset..offset + 8].copy_from_slice(&[0xff; 8]);
+        let block: Block = bincode::deserialize(&blob).unwrap();
+        match block.miner_tx.prefix.extra.last() {
+            Some(TXExtra::TxNonce(TXNonce::Extra(nonce))) => assert_eq!(nonce, &vec![0xff; 8]),
+            extra => panic!("Unexpected extra {:?}", extra),
+        }
+    }
 }
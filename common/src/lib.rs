@@ -9,8 +9,10 @@ pub mod data_dir;
 
 /// Functions for proof-of-work difficulty verification
 pub mod difficulty;
+mod network;
 mod transaction;
 
 pub use block::{Block, BlockHeader};
+pub use network::Network;
 pub use traits::{GetHash, PreliminaryChecks};
 pub use transaction::{TXExtra, TXIn, TXNonce, TXOut, TXOutTarget, Transaction, TransactionPrefix};
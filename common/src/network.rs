@@ -0,0 +1,87 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Networks a node can be a part of
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum Network {
+    /// The main network, where coins have real value
+    Mainnet,
+    /// Network for testing new features before they're released on mainnet
+    Testnet,
+    /// Network mirroring mainnet's features, for testing integrations
+    Stagenet,
+}
+
+impl Network {
+    /// Gets the unique ID of this network
+    ///
+    /// Peers exchange it during the P2P handshake to make sure they're on the same network
+    pub fn network_id(self) -> [u8; 16] {
+        match self {
+            Network::Mainnet => [
+                0x55, 0x4e, 0x50, 0x52, 0x4c, 0x4c, 0x2d, 0x4d, 0x41, 0x49, 0x4e, 0x4e, 0x45, 0x54,
+                0x21, 0x01,
+            ],
+            Network::Testnet => [
+                0x55, 0x4e, 0x50, 0x52, 0x4c, 0x4c, 0x2d, 0x54, 0x45, 0x53, 0x54, 0x4e, 0x45, 0x54,
+                0x21, 0x02,
+            ],
+            Network::Stagenet => [
+                0x55, 0x4e, 0x50, 0x52, 0x4c, 0x4c, 0x2d, 0x53, 0x54, 0x41, 0x47, 0x45, 0x4e, 0x45,
+                0x54, 0x03,
+            ],
+        }
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Stagenet => "stagenet",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(network: &str) -> Result<Self, Self::Err> {
+        match network {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "stagenet" => Ok(Network::Stagenet),
+            _ => Err(format!("Unknown network {}", network)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_strings() {
+        for network in &[Network::Mainnet, Network::Testnet, Network::Stagenet] {
+            assert_eq!(Network::from_str(&network.to_string()), Ok(*network));
+        }
+        assert!(Network::from_str("regtest").is_err());
+    }
+
+    #[test]
+    fn network_ids_are_unique() {
+        assert_ne!(Network::Mainnet.network_id(), Network::Testnet.network_id());
+        assert_ne!(
+            Network::Mainnet.network_id(),
+            Network::Stagenet.network_id()
+        );
+        assert_ne!(
+            Network::Testnet.network_id(),
+            Network::Stagenet.network_id()
+        );
+    }
+}
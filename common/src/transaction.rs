@@ -41,14 +41,14 @@ pub struct TXOut {
 }
 
 /// Nonces added to the transaction
-///
-/// Currently stores only the encrypted payment ID
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum TXNonce {
     /// The Encrypted Payment ID for this transaction
     ///
     /// Useful for identifying transactions by the receiver
     EncryptedPaymentId(Hash8),
+    /// Arbitrary data, such as the space reserved for mining pools in coinbase transactions
+    Extra(Vec<u8>),
 }
 
 /// Extra information added to the transaction
@@ -87,6 +87,35 @@ pub struct Transaction {
     pub rct_signature: Option<RingCTSignature>,
 }
 
+impl Transaction {
+    /// Gets the fee paid by this transaction
+    ///
+    /// Transactions without a RingCT signature (such as coinbase transactions) don't pay a fee
+    pub fn fee(&self) -> u64 {
+        self.rct_signature
+            .as_ref()
+            .map_or(0, |signature| signature.base.fee)
+    }
+
+    /// Gets the size of this transaction in bytes, as sent over the network
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).expect("Transactions can always be serialized") as usize
+    }
+
+    /// Strips the parts of the RingCT signature that are only needed for verification
+    ///
+    /// Pruned transactions keep their prefix and the RingCT base, which holds the output
+    /// commitments and ECDH information a wallet needs to find and decode its outputs. They can
+    /// no longer be verified
+    pub fn prune(&mut self) {
+        if let Some(signature) = &mut self.rct_signature {
+            signature.bulletproofs.clear();
+            signature.input_commitments.clear();
+            signature.mlsag.clear();
+        }
+    }
+}
+
 impl GetHash for TransactionPrefix {
     fn get_hash_blob(&self) -> Vec<u8> {
         let mut vec = Vec::new();
@@ -176,6 +205,11 @@ impl GetHash for TransactionPrefix {
                             // Payment ID
                             extra_buf.extend_from_slice(payment_id.data());
                         }
+                        TXNonce::Extra(data) => {
+                            // Data
+                            extra_buf.extend_from_slice(&varint::serialize(data.len() as u64));
+                            extra_buf.extend_from_slice(data);
+                        }
                     }
                 }
             }
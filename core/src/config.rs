@@ -1,11 +1,16 @@
 use structopt::StructOpt;
 
 use blockchain::Config as BlockchainConfig;
+use common::Network;
 
 /// CryptonoteCore configuration
 #[derive(StructOpt, Debug)]
 #[structopt(rename_all = "kebab-case")]
 pub struct Config {
+    /// Network to run on (mainnet, testnet or stagenet)
+    #[structopt(long, default_value = "mainnet")]
+    pub network: Network,
+
     // We can't document flattened structs
     #[allow(missing_docs)]
     #[structopt(flatten)]
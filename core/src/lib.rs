@@ -2,7 +2,8 @@
 //! Core module to bind all components of a Cryptonote coin
 
 use blockchain::Blockchain;
-pub use blockchain::EmissionCurve;
+pub use blockchain::{ChainEvent, EmissionCurve};
+use common::Network;
 
 mod config;
 pub use config::Config;
@@ -16,6 +17,7 @@ where
     TCoin: EmissionCurve,
 {
     blockchain: Blockchain<TCoin>,
+    network: Network,
 }
 
 impl<TCoin> CryptonoteCore<TCoin>
@@ -23,10 +25,16 @@ where
     TCoin: EmissionCurve,
 {
     /// Creates a new CryptonoteCore with the given configuration
-    pub fn new(coin_definition: TCoin, config: &Config) -> Self {
-        let blockchain = Blockchain::new(coin_definition, &config.blockchain_config)
-            .expect("Failed to initialize Blockchain");
-        CryptonoteCore { blockchain }
+    pub fn new(coin_definition: TCoin, config: &Config) -> blockchain::Result<Self> {
+        let blockchain = Blockchain::new(coin_definition, &config.blockchain_config)?;
+        Ok(CryptonoteCore {
+            blockchain,
+            network: config.network,
+        })
+    }
+    /// Get the network this core is running on
+    pub fn network(&self) -> Network {
+        self.network
     }
     /// Get a reference to the underlying blockchain
     pub fn blockchain(&self) -> &Blockchain<TCoin> {
@@ -0,0 +1,272 @@
+//! # Ban list
+//!
+//! Keeps track of the IP addresses of peers we refuse to talk to. Bans are either temporary,
+//! expiring at a given time, or permanent until lifted manually. Addresses can also be blocked
+//! for the lifetime of the node from a file listing one address per line
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::peer_list::unix_timestamp;
+
+/// A banned IP address
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    /// Banned IP address
+    pub address: IpAddr,
+    /// UNIX timestamp of when the ban expires, if it isn't permanent
+    pub until: Option<u64>,
+}
+
+impl Ban {
+    /// Time left until the ban expires, if it isn't permanent
+    pub fn remaining(&self) -> Option<Duration> {
+        self.until
+            .map(|until| Duration::from_secs(until.saturating_sub(unix_timestamp())))
+    }
+}
+
+/// Banned IP addresses, mapped to when their ban expires (`None` for permanent bans)
+#[derive(Default, Serialize, Deserialize)]
+pub struct BanList {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    changed: bool,
+    #[serde(skip)]
+    blocked: HashSet<IpAddr>,
+
+    bans: HashMap<IpAddr, Option<u64>>,
+}
+
+impl BanList {
+    /// Loads the ban list stored at the given path, or creates an empty one if it doesn't exist
+    pub fn load(path: &Path) -> Self {
+        let ban_list = File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| bincode::deserialize_from(file).map_err(anyhow::Error::from));
+
+        let mut ban_list = match ban_list {
+            Ok(ban_list) => {
+                info!("Loaded ban list from {}", path.display());
+                ban_list
+            }
+            Err(error) => {
+                warn!(
+                    "Could not load ban list ({}). Starting with an empty one",
+                    error
+                );
+                BanList::default()
+            }
+        };
+        ban_list.path = Some(path.to_path_buf());
+
+        ban_list
+    }
+
+    /// Blocks the addresses listed in the given file, without storing them in the ban list
+    ///
+    /// The file lists one IP address per line. Empty lines and lines starting with `#` are
+    /// ignored
+    pub fn block_from_file(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+        let addresses = parse_addresses(&std::fs::read_to_string(path)?)?;
+        info!(
+            "Blocked {} addresses from {}",
+            addresses.len(),
+            path.display()
+        );
+        self.blocked.extend(addresses);
+
+        Ok(())
+    }
+
+    /// Saves the ban list to disk if it changed since the last save
+    pub fn sync(&mut self) -> Result<(), anyhow::Error> {
+        self.remove_expired();
+
+        if !self.changed {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.path {
+            let file = File::create(path)?;
+            bincode::serialize_into(file, &self)?;
+            debug!("Saved ban list");
+        }
+        self.changed = false;
+
+        Ok(())
+    }
+
+    /// Bans an address for the given duration, or permanently if no duration is given
+    ///
+    /// Existing bans are only ever extended, never shortened
+    pub fn ban(&mut self, address: IpAddr, duration: Option<Duration>) {
+        // Overly long bans are as good as permanent
+        let until = duration.map(|duration| unix_timestamp().saturating_add(duration.as_secs()));
+
+        let current = self.bans.entry(address).or_insert(until);
+        *current = match (*current, until) {
+            (Some(current), Some(until)) => Some(current.max(until)),
+            _ => None,
+        };
+
+        self.changed = true;
+    }
+
+    /// Lifts the ban on an address
+    ///
+    /// # Returns
+    /// Whether the address was banned
+    pub fn unban(&mut self, address: &IpAddr) -> bool {
+        let was_banned = self.bans.remove(address).is_some();
+        self.changed |= was_banned;
+
+        self.blocked.remove(address) || was_banned
+    }
+
+    /// Checks if an address is currently banned
+    pub fn is_banned(&self, address: &IpAddr) -> bool {
+        if self.blocked.contains(address) {
+            return true;
+        }
+
+        match self.bans.get(address) {
+            Some(Some(until)) => *until > unix_timestamp(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Gets all active bans, including blocked addresses
+    pub fn bans(&self) -> Vec<Ban> {
+        let blocked = self.blocked.iter().map(|address| Ban {
+            address: *address,
+            until: None,
+        });
+
+        self.bans
+            .iter()
+            .filter(|(address, _)| !self.blocked.contains(address))
+            .map(|(address, until)| Ban {
+                address: *address,
+                until: *until,
+            })
+            .filter(|ban| self.is_banned(&ban.address))
+            .chain(blocked)
+            .collect()
+    }
+
+    fn remove_expired(&mut self) {
+        let now = unix_timestamp();
+        let count = self.bans.len();
+
+        self.bans
+            .retain(|_, until| until.map_or(true, |until| until > now));
+
+        self.changed |= self.bans.len() != count;
+    }
+}
+
+/// Parses a list of IP addresses, one per line
+fn parse_addresses(list: &str) -> Result<Vec<IpAddr>, anyhow::Error> {
+    list.lines()
+        .enumerate()
+        .map(|(number, line)| (number, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            line.parse()
+                .map_err(|_| anyhow::anyhow!("Invalid address on line {}: {}", number + 1, line))
+        })
+        .collect()
+}
+
+impl Drop for BanList {
+    fn drop(&mut self) {
+        if let Err(error) = self.sync() {
+            warn!("Failed to save ban list: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(i: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, i])
+    }
+
+    #[test]
+    fn bans_and_unbans() {
+        let mut ban_list = BanList::default();
+
+        ban_list.ban(address(1), Some(Duration::from_secs(60)));
+        ban_list.ban(address(2), None);
+        ban_list.ban(address(3), Some(Duration::from_secs(u64::max_value())));
+        assert!(ban_list.is_banned(&address(1)));
+        assert!(ban_list.is_banned(&address(2)));
+        assert!(ban_list.is_banned(&address(3)));
+        assert!(!ban_list.is_banned(&address(4)));
+        assert_eq!(ban_list.bans().len(), 3);
+
+        assert!(ban_list.unban(&address(1)));
+        assert!(!ban_list.unban(&address(1)));
+        assert!(!ban_list.is_banned(&address(1)));
+    }
+
+    #[test]
+    fn temporary_bans_never_shorten_permanent_ones() {
+        let mut ban_list = BanList::default();
+
+        ban_list.ban(address(1), None);
+        ban_list.ban(address(1), Some(Duration::from_secs(60)));
+
+        assert_eq!(
+            ban_list.bans(),
+            vec![Ban {
+                address: address(1),
+                until: None
+            }]
+        );
+    }
+
+    #[test]
+    fn expired_bans_are_removed() {
+        let mut ban_list = BanList::default();
+
+        ban_list.bans.insert(address(1), Some(unix_timestamp() - 1));
+        assert!(!ban_list.is_banned(&address(1)));
+        assert!(ban_list.bans().is_empty());
+
+        ban_list.remove_expired();
+        assert!(ban_list.bans.is_empty());
+    }
+
+    #[test]
+    fn blocks_listed_addresses() {
+        let list = "# Blocked hosts\n10.0.0.1\n\n  2001:db8::1  \n";
+        let addresses = parse_addresses(list).unwrap();
+        assert_eq!(
+            addresses,
+            vec![address(1), "2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+        assert!(parse_addresses("10.0.0.1\nnot an address").is_err());
+
+        let mut ban_list = BanList::default();
+        ban_list.blocked.extend(addresses);
+        assert!(ban_list.is_banned(&address(1)));
+        assert_eq!(ban_list.bans().len(), 2);
+
+        ban_list.sync().unwrap();
+        assert!(ban_list.bans.is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//! # Bandwidth accounting
+//!
+//! Counts the bytes exchanged with each peer and in total, and limits the rate of transfers
+//! with token buckets shared by all connections
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+/// Bytes received and sent
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrafficCounters {
+    /// Bytes received
+    pub bytes_in: u64,
+    /// Bytes sent
+    pub bytes_out: u64,
+}
+
+/// Traffic of connected peers and of the node as a whole since it started
+#[derive(Default)]
+pub struct BandwidthStats {
+    total: TrafficCounters,
+    peers: HashMap<PeerId, TrafficCounters>,
+}
+
+impl BandwidthStats {
+    /// Total traffic since the node started
+    pub fn total(&self) -> TrafficCounters {
+        self.total
+    }
+
+    /// Traffic of each connected peer since it connected
+    pub fn peers(&self) -> &HashMap<PeerId, TrafficCounters> {
+        &self.peers
+    }
+
+    pub(crate) fn record_received(&mut self, peer_id: &PeerId, bytes: usize) {
+        self.total.bytes_in += bytes as u64;
+        self.peers.entry(peer_id.clone()).or_default().bytes_in += bytes as u64;
+    }
+
+    pub(crate) fn record_sent(&mut self, peer_id: &PeerId, bytes: usize) {
+        self.total.bytes_out += bytes as u64;
+        self.peers.entry(peer_id.clone()).or_default().bytes_out += bytes as u64;
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+}
+
+/// Token bucket allowing bursts of up to one second worth of transfers
+///
+/// Transfers larger than the bucket go into debt, which later transfers wait out
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            rate: bytes_per_second as f64,
+            tokens: bytes_per_second as f64,
+            last_update: Instant::now(),
+        }
+    }
+
+    /// Takes tokens for a transfer of the given size
+    ///
+    /// # Returns
+    /// How long to wait before making the transfer
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now
+            .saturating_duration_since(self.last_update)
+            .as_secs_f64();
+        self.last_update = now;
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Global upload and download rate limits, shared by all connections
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    upload: Option<Arc<Mutex<TokenBucket>>>,
+    download: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter with the given limits in bytes per second, if any
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        let bucket = |rate: u64| Arc::new(Mutex::new(TokenBucket::new(rate)));
+
+        Self {
+            upload: upload.filter(|rate| *rate > 0).map(bucket),
+            download: download.filter(|rate| *rate > 0).map(bucket),
+        }
+    }
+
+    /// Waits until the given number of bytes may be sent
+    pub async fn upload(&self, bytes: usize) {
+        Self::wait(&self.upload, bytes).await
+    }
+
+    /// Waits until the given number of bytes may be received
+    pub async fn download(&self, bytes: usize) {
+        Self::wait(&self.download, bytes).await
+    }
+
+    async fn wait(bucket: &Option<Arc<Mutex<TokenBucket>>>, bytes: usize) {
+        let delay = match bucket {
+            Some(bucket) => bucket.lock().unwrap().take(bytes, Instant::now()),
+            None => return,
+        };
+
+        if delay > Duration::from_secs(0) {
+            tokio::time::delay_for(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_bursts_up_to_the_rate() {
+        let mut bucket = TokenBucket::new(1000);
+        let now = bucket.last_update;
+
+        assert_eq!(bucket.take(600, now), Duration::from_secs(0));
+        assert_eq!(bucket.take(400, now), Duration::from_secs(0));
+        assert_eq!(bucket.take(500, now), Duration::from_millis(500));
+
+        // The debt is paid off over time
+        assert_eq!(
+            bucket.take(500, now + Duration::from_secs(1)),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn counts_traffic_per_peer_and_in_total() {
+        let mut stats = BandwidthStats::default();
+        let peer_1 = PeerId::random();
+        let peer_2 = PeerId::random();
+
+        stats.record_received(&peer_1, 100);
+        stats.record_sent(&peer_1, 10);
+        stats.record_received(&peer_2, 50);
+        assert_eq!(
+            stats.peers()[&peer_1],
+            TrafficCounters {
+                bytes_in: 100,
+                bytes_out: 10
+            }
+        );
+
+        stats.remove_peer(&peer_1);
+        assert!(!stats.peers().contains_key(&peer_1));
+        assert_eq!(
+            stats.total(),
+            TrafficCounters {
+                bytes_in: 150,
+                bytes_out: 10
+            }
+        );
+    }
+}
@@ -1,9 +1,17 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use libp2p::Multiaddr;
+
 use structopt::StructOpt;
 
 /// Crypronote P2P configuration
 #[derive(StructOpt, Debug)]
 #[structopt(rename_all = "kebab-case", name = "Unprll")]
 pub struct Config {
+    /// IP address to bind the P2P listener, either IPv4 or IPv6
+    #[structopt(long, default_value = "0.0.0.0")]
+    pub p2p_bind_address: IpAddr,
+
     /// Port to bind the P2P listener
     #[structopt(long, default_value = "21149")]
     pub p2p_bind_port: u16,
@@ -11,4 +19,45 @@ pub struct Config {
     /// Address of node to connect to
     #[structopt(long)]
     pub connect_to: Option<String>,
+
+    /// Target number of outbound connections to maintain
+    #[structopt(long, default_value = "8")]
+    pub out_peers: usize,
+
+    /// Maximum number of inbound connections to accept
+    #[structopt(long, default_value = "64")]
+    pub in_peers: usize,
+
+    /// Connect only to the given node. Can be repeated
+    #[structopt(long)]
+    pub add_exclusive_node: Vec<Multiaddr>,
+
+    /// Connect to the given node to find peers when the peer list is empty. Can be repeated
+    #[structopt(long)]
+    pub seed_node: Vec<Multiaddr>,
+
+    /// Always stay connected to the given node, on top of other peers. Can be repeated
+    #[structopt(long)]
+    pub add_priority_node: Vec<Multiaddr>,
+
+    /// File of IP addresses to refuse connections with, one per line
+    #[structopt(long)]
+    pub ban_list: Option<PathBuf>,
+
+    /// Path where P2P state (such as the peer list) should be stored
+    /// If unset, uses the default data directory
+    #[structopt(long)]
+    pub p2p_data_directory: Option<PathBuf>,
+
+    /// Generate a new node key, changing the node's peer ID, instead of loading the stored one
+    #[structopt(long)]
+    pub regenerate_node_key: bool,
+
+    /// Limit of the upload rate in kB/s
+    #[structopt(long)]
+    pub limit_rate_up: Option<u64>,
+
+    /// Limit of the download rate in kB/s
+    #[structopt(long)]
+    pub limit_rate_down: Option<u64>,
 }
@@ -0,0 +1,211 @@
+//! # Dandelion++ transaction relay
+//!
+//! Transactions are first forwarded along a single path of peers (the "stem" phase) before
+//! being broadcasted to the whole network (the "fluff" phase). This makes it harder for an
+//! observer to link a transaction to the IP address of the node it originated from
+//!
+//! Based on the Dandelion++ paper (<https://arxiv.org/abs/1805.11060>)
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use rand::{seq::IteratorRandom, Rng};
+
+use crypto::Hash256;
+
+/// Duration of an epoch. The stem peer and relay mode are kept for an entire epoch
+const EPOCH_DURATION: Duration = Duration::from_secs(600);
+
+/// Probability of a node being in fluff mode for an epoch
+const FLUFF_PROBABILITY: f64 = 0.1;
+
+/// Average time to wait for a stem transaction to be fluffed by another node, in seconds
+///
+/// The actual embargo time is exponentially distributed around this value so that the
+/// node that fluffs a transaction isn't predictable
+const EMBARGO_AVERAGE: f64 = 39.0;
+
+/// Where a transaction in the stem phase should be sent
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    /// Forward the transaction to the given stem peer
+    Stem(PeerId),
+    /// Broadcast the transaction to all peers
+    Fluff,
+}
+
+/// Dandelion++ relay state
+pub struct Dandelion {
+    epoch_start: Instant,
+    is_fluff_epoch: bool,
+    stem_peer: Option<PeerId>,
+    embargoes: HashMap<Hash256, Instant>,
+}
+
+impl Dandelion {
+    pub fn new() -> Self {
+        let mut dandelion = Self {
+            epoch_start: Instant::now(),
+            is_fluff_epoch: false,
+            stem_peer: None,
+            embargoes: HashMap::new(),
+        };
+        dandelion.new_epoch();
+        dandelion
+    }
+
+    fn new_epoch(&mut self) {
+        self.epoch_start = Instant::now();
+        self.is_fluff_epoch = rand::thread_rng().gen_bool(FLUFF_PROBABILITY);
+        self.stem_peer = None;
+    }
+
+    /// Decides the route for stem transactions received from `source`
+    ///
+    /// The stem peer is chosen from `peers` and kept for the rest of the epoch (or until it
+    /// disconnects). Transactions are fluffed if this node is in fluff mode for the epoch or
+    /// if there's no peer to forward the stem to
+    pub fn route<'a>(
+        &mut self,
+        peers: impl Iterator<Item = &'a PeerId>,
+        source: Option<&PeerId>,
+    ) -> Route {
+        if self.epoch_start.elapsed() >= EPOCH_DURATION {
+            self.new_epoch();
+        }
+
+        if self.is_fluff_epoch {
+            return Route::Fluff;
+        }
+
+        if self.stem_peer.is_none() {
+            self.stem_peer = peers
+                .filter(|peer_id| Some(*peer_id) != source)
+                .choose(&mut rand::thread_rng())
+                .cloned();
+        }
+
+        match &self.stem_peer {
+            // Never send a stem back to where it came from
+            Some(stem_peer) if Some(stem_peer) != source => Route::Stem(stem_peer.clone()),
+            _ => Route::Fluff,
+        }
+    }
+
+    /// Removes a disconnected peer. A new stem peer is chosen if it was the current one
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        if self.stem_peer.as_ref() == Some(peer_id) {
+            self.stem_peer = None;
+        }
+    }
+
+    /// Starts the embargo timer for a transaction sent along the stem
+    pub fn add_embargo(&mut self, txid: Hash256) {
+        // Exponentially distributed delay (1 - x lies within (0, 1], avoiding ln(0))
+        let x: f64 = rand::thread_rng().gen();
+        let delay = -EMBARGO_AVERAGE * (1.0 - x).ln();
+
+        self.embargoes
+            .insert(txid, Instant::now() + Duration::from_secs_f64(delay));
+    }
+
+    /// Stops the embargo timer of a transaction. Used when the transaction was fluffed
+    /// by another node
+    pub fn remove_embargo(&mut self, txid: &Hash256) {
+        self.embargoes.remove(txid);
+    }
+
+    /// Takes the transactions whose embargo has expired
+    ///
+    /// These transactions never came back from the stem and need to be fluffed by us
+    pub fn take_expired_embargoes(&mut self) -> Vec<Hash256> {
+        let now = Instant::now();
+
+        let expired = self
+            .embargoes
+            .iter()
+            .filter(|(_, expiry)| **expiry <= now)
+            .map(|(txid, _)| txid.clone())
+            .collect::<Vec<_>>();
+
+        for txid in &expired {
+            self.embargoes.remove(txid);
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stem_epoch() -> Dandelion {
+        let mut dandelion = Dandelion::new();
+        dandelion.is_fluff_epoch = false;
+        dandelion
+    }
+
+    #[test]
+    fn keeps_stem_peer_for_epoch() {
+        let mut dandelion = stem_epoch();
+        let peers = (0..8).map(|_| PeerId::random()).collect::<Vec<_>>();
+
+        let route = dandelion.route(peers.iter(), None);
+        for _ in 0..10 {
+            assert_eq!(dandelion.route(peers.iter(), None), route);
+        }
+    }
+
+    #[test]
+    fn never_stems_back_to_source() {
+        let mut dandelion = stem_epoch();
+        let peer = PeerId::random();
+
+        assert_eq!(
+            dandelion.route(std::iter::once(&peer), Some(&peer)),
+            Route::Fluff
+        );
+    }
+
+    #[test]
+    fn replaces_disconnected_stem_peer() {
+        let mut dandelion = stem_epoch();
+        let peer_1 = PeerId::random();
+        let peer_2 = PeerId::random();
+
+        assert_eq!(
+            dandelion.route(std::iter::once(&peer_1), None),
+            Route::Stem(peer_1.clone())
+        );
+
+        dandelion.remove_peer(&peer_1);
+        assert_eq!(
+            dandelion.route(std::iter::once(&peer_2), None),
+            Route::Stem(peer_2)
+        );
+    }
+
+    #[test]
+    fn fluffs_without_peers() {
+        let mut dandelion = stem_epoch();
+
+        assert_eq!(dandelion.route(std::iter::empty(), None), Route::Fluff);
+    }
+
+    #[test]
+    fn embargoes_expire() {
+        let mut dandelion = Dandelion::new();
+        let txid = Hash256::null_hash();
+
+        dandelion
+            .embargoes
+            .insert(txid.clone(), Instant::now() - Duration::from_secs(1));
+
+        assert_eq!(dandelion.take_expired_embargoes(), vec![txid]);
+        assert!(dandelion.take_expired_embargoes().is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+//! # Message framing
+//!
+//! Each message is sent on its own substream, starting with a header:
+//!
+//! | Field          | Size                    |
+//! |----------------|-------------------------|
+//! | Framing version| 1 byte                  |
+//! | Message type   | 1 byte                  |
+//! | Payload length | 4 bytes (little endian) |
+//!
+//! The payload follows in chunks of at most `MAX_CHUNK_SIZE` bytes, each prefixed by its
+//! length (4 bytes, little endian). Receivers check the payload length against the limit of
+//! the message type before reading it, and never buffer more than a chunk ahead of the data
+//! actually received
+
+use std::convert::TryInto;
+
+use libp2p::futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version of the framing format
+pub const FRAMING_VERSION: u8 = 1;
+
+/// Maximum size of a single chunk of payload
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Errors when reading a framed message
+#[derive(thiserror::Error, Debug)]
+pub enum FramingError {
+    /// The sender uses a framing format we don't understand
+    #[error("Unsupported framing version {}", _0)]
+    UnsupportedVersion(u8),
+
+    /// The message type is unknown
+    #[error("Unknown message type {}", _0)]
+    UnknownMessageType(u8),
+
+    /// The message is larger than allowed for its type
+    #[error(
+        "Message of type {} is {} bytes long, over the limit of {} bytes",
+        message_type,
+        length,
+        limit
+    )]
+    MessageTooLarge {
+        /// Type of the message
+        message_type: u8,
+        /// Length of the message payload
+        length: usize,
+        /// Maximum length allowed for the message type
+        limit: usize,
+    },
+
+    /// A chunk is empty, too large or goes past the end of the payload
+    #[error("Invalid chunk length {}", _0)]
+    InvalidChunk(usize),
+
+    /// Error reading from the socket
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Writes a message with the given type and payload, and closes the socket
+pub async fn write_message<W>(
+    socket: &mut W,
+    message_type: u8,
+    payload: &[u8],
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let length: u32 = payload
+        .len()
+        .try_into()
+        .map_err(|_| std::io::ErrorKind::InvalidInput)?;
+
+    let mut header = [FRAMING_VERSION, message_type, 0, 0, 0, 0];
+    header[2..].copy_from_slice(&length.to_le_bytes());
+    socket.write_all(&header).await?;
+
+    for chunk in payload.chunks(MAX_CHUNK_SIZE) {
+        socket
+            .write_all(&(chunk.len() as u32).to_le_bytes())
+            .await?;
+        socket.write_all(chunk).await?;
+    }
+
+    socket.close().await
+}
+
+/// Size of a message with the given payload length once framed
+pub fn framed_size(length: usize) -> usize {
+    let chunks = (length + MAX_CHUNK_SIZE - 1) / MAX_CHUNK_SIZE;
+
+    6 + 4 * chunks + length
+}
+
+/// Reads a message header, returning the message type and payload length
+///
+/// `limit` gives the maximum payload length of each message type, or `None` for unknown types
+pub async fn read_header<R, F>(socket: &mut R, limit: F) -> Result<(u8, usize), FramingError>
+where
+    R: AsyncRead + Unpin,
+    F: Fn(u8) -> Option<usize>,
+{
+    let mut header = [0u8; 6];
+    socket.read_exact(&mut header).await?;
+
+    if header[0] != FRAMING_VERSION {
+        return Err(FramingError::UnsupportedVersion(header[0]));
+    }
+
+    let message_type = header[1];
+    let limit = limit(message_type).ok_or(FramingError::UnknownMessageType(message_type))?;
+    let length = u32::from_le_bytes(header[2..].try_into().unwrap()) as usize;
+    if length > limit {
+        return Err(FramingError::MessageTooLarge {
+            message_type,
+            length,
+            limit,
+        });
+    }
+
+    Ok((message_type, length))
+}
+
+/// Reads the chunks of a message payload of the given length, following its header
+pub async fn read_payload<R>(socket: &mut R, length: usize) -> Result<Vec<u8>, FramingError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut payload = Vec::with_capacity(length.min(MAX_CHUNK_SIZE));
+    while payload.len() < length {
+        let mut chunk_header = [0u8; 4];
+        socket.read_exact(&mut chunk_header).await?;
+
+        let chunk_length = u32::from_le_bytes(chunk_header) as usize;
+        if chunk_length == 0
+            || chunk_length > MAX_CHUNK_SIZE
+            || chunk_length > length - payload.len()
+        {
+            return Err(FramingError::InvalidChunk(chunk_length));
+        }
+
+        let start = payload.len();
+        payload.resize(start + chunk_length, 0);
+        socket.read_exact(&mut payload[start..]).await?;
+    }
+
+    Ok(payload)
+}
+
+/// Reads a message, returning its type and payload
+///
+/// `limit` gives the maximum payload length of each message type, or `None` for unknown types
+#[cfg(test)]
+pub async fn read_message<R, F>(socket: &mut R, limit: F) -> Result<(u8, Vec<u8>), FramingError>
+where
+    R: AsyncRead + Unpin,
+    F: Fn(u8) -> Option<usize>,
+{
+    let (message_type, length) = read_header(socket, limit).await?;
+
+    Ok((message_type, read_payload(socket, length).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::futures::{executor::block_on, io::Cursor};
+
+    use super::*;
+
+    fn write(message_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut socket = Cursor::new(Vec::new());
+        block_on(write_message(&mut socket, message_type, payload)).unwrap();
+
+        socket.into_inner()
+    }
+
+    #[test]
+    fn large_messages_round_trip() {
+        let payload: Vec<u8> = (0..3 * MAX_CHUNK_SIZE + 5).map(|i| i as u8).collect();
+        let packet = write(7, &payload);
+        assert_eq!(packet.len(), framed_size(payload.len()));
+
+        let mut socket = Cursor::new(packet);
+
+        let (message_type, read) =
+            block_on(read_message(&mut socket, |_| Some(usize::MAX))).unwrap();
+        assert_eq!(message_type, 7);
+        assert_eq!(read, payload);
+    }
+
+    #[test]
+    fn rejects_messages_over_the_type_limit() {
+        let mut socket = Cursor::new(write(1, &[0; 100]));
+
+        match block_on(read_message(&mut socket, |_| Some(10))) {
+            Err(FramingError::MessageTooLarge {
+                length: 100,
+                limit: 10,
+                ..
+            }) => {}
+            _ => panic!("Oversize message was accepted"),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut packet = write(1, &[0; 10]);
+        packet[0] = FRAMING_VERSION + 1;
+
+        match block_on(read_message(&mut Cursor::new(packet), |_| Some(100))) {
+            Err(FramingError::UnsupportedVersion(_)) => {}
+            _ => panic!("Unknown framing version was accepted"),
+        }
+    }
+}
@@ -1,4 +1,8 @@
+mod dandelion;
+mod framing;
 mod network_behavior;
+mod peer_score;
 mod protocol;
+mod sync;
 
-pub use network_behavior::CryptonoteNetworkBehavior;
+pub use network_behavior::{CryptonoteNetworkBehavior, CryptonoteP2PEvent};
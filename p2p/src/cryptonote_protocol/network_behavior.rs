@@ -3,49 +3,399 @@
 //! Handles taking events from Libp2p and passing them to our code (for better code organization)
 
 use std::{
-    collections::{HashMap, VecDeque},
-    future::Future,
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
     sync::{Arc, RwLock},
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use common::GetHash;
-use cryptonote_core::{CryptonoteCore, EmissionCurve};
+use common::{Block, GetHash, Transaction};
+use crypto::Hash256;
+use cryptonote_core::{ChainEvent, CryptonoteCore, EmissionCurve};
 use futures::StreamExt;
 use libp2p::{
-    core::{connection::ConnectionId, PeerId},
+    core::{connection::ConnectionId, ConnectedPoint, PeerId},
+    multiaddr::Protocol,
     swarm::{
         protocols_handler::{OneShotHandler, OneShotHandlerConfig, SubstreamProtocol},
         NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters,
     },
     Multiaddr,
 };
+use rand::Rng;
+use tokio::{
+    sync::{
+        broadcast::{self, RecvError},
+        mpsc,
+    },
+    time::Interval,
+};
 
-use super::protocol::{CryptonoteP2PMessage, CryptonoteP2PUpgrade, NodeInfo};
+use super::{
+    dandelion::{Dandelion, Route},
+    peer_score::{Misbehavior, PeerScores, BAN_DURATION, BAN_SCORE},
+    protocol::{
+        max_message_size, BlockEntry, CryptonoteP2PHandlerEvent, CryptonoteP2PMessage,
+        CryptonoteP2PUpgrade, HandshakeError, NodeInfo, ProtocolError, PROTOCOL_VERSION,
+    },
+    sync::{SyncManager, MAX_CHAIN_LENGTH, SPAN_LENGTH},
+};
+use crate::{
+    bandwidth::RateLimiter,
+    config::Config,
+    network_state::NetworkState,
+    peer_list::{PeerList, PeerListEntry},
+};
 
 // IDEA: Further split each component into its own parts for easier use by other coins
 
+/// Maximum number of peer addresses sent or accepted in a single peer exchange
+const PEER_EXCHANGE_SIZE: usize = 250;
+
+/// Interval between checks of the number of outbound connections
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time after which a dial attempt that hasn't resolved is forgotten
+const DIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of relayed blocks waiting for their transactions
+const MAX_PENDING_BLOCKS: usize = 16;
+
+/// Interval between checks for sync requests to send or retry
+const SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replaces the TCP port of an address
+///
+/// Used to get the listening address of inbound peers from the address of their connection
+fn with_tcp_port(address: &Multiaddr, port: u16) -> Multiaddr {
+    address
+        .iter()
+        .map(|protocol| match protocol {
+            Protocol::Tcp(_) => Protocol::Tcp(port),
+            protocol => protocol,
+        })
+        .collect()
+}
+
+/// Gets the IP address of a multiaddress, if it has one
+fn ip_address(address: &Multiaddr) -> Option<IpAddr> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::from(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::from(ip)),
+        _ => None,
+    })
+}
+
+/// Events emitted by the `CryptonoteNetworkBehavior` to be handled by the swarm
+#[derive(Debug)]
+pub enum CryptonoteP2PEvent {
+    /// All connections to the given peer should be closed
+    DisconnectPeer(PeerId),
+}
+
 /// `NetworkBehaviour` to drive the Cryptonote P2P protocol
 pub struct CryptonoteNetworkBehavior<TCoin>
 where
     TCoin: EmissionCurve + Unpin,
 {
+    chain_events: broadcast::Receiver<ChainEvent>,
     core: Arc<RwLock<CryptonoteCore<TCoin>>>,
+    dandelion: Dandelion,
+    embargo_timer: Interval,
+    local_transactions: mpsc::UnboundedReceiver<Transaction>,
+    node_nonce: u64,
     peers: HashMap<PeerId, Option<NodeInfo>>,
     // It's an ArcRwLock to bypass mut issues
-    pending_messages: VecDeque<NetworkBehaviourAction<CryptonoteP2PUpgrade, CryptonoteP2PUpgrade>>,
+    pending_messages: VecDeque<NetworkBehaviourAction<CryptonoteP2PUpgrade, CryptonoteP2PEvent>>,
+
+    // Block synchronization
+    added_blocks: HashSet<Hash256>,
+    pending_blocks: HashMap<Hash256, (PeerId, Block)>,
+    sync: SyncManager,
+    sync_timer: Interval,
+
+    // Misbehaviour tracking and bandwidth accounting
+    network_state: Arc<RwLock<NetworkState>>,
+    peer_ips: HashMap<PeerId, IpAddr>,
+    peer_scores: PeerScores,
+    rate_limiter: RateLimiter,
+    requested_peers: HashSet<PeerId>,
+    requested_txids: HashMap<PeerId, HashSet<Hash256>>,
+
+    // Peer discovery
+    connection_timer: Interval,
+    exclusive_nodes: Vec<Multiaddr>,
+    in_peers: usize,
+    inbound_addresses: HashMap<PeerId, Multiaddr>,
+    listen_port: u16,
+    local_addresses: HashSet<Multiaddr>,
+    out_peers: usize,
+    outbound_peers: HashSet<PeerId>,
+    peer_addresses: HashMap<PeerId, Multiaddr>,
+    peer_list: PeerList,
+    pending_dials: HashMap<Multiaddr, Instant>,
+    priority_nodes: Vec<Multiaddr>,
+    seed_nodes: Vec<Multiaddr>,
 }
 
 impl<TCoin> CryptonoteNetworkBehavior<TCoin>
 where
     TCoin: EmissionCurve + Unpin,
 {
-    pub fn new(_peer_id: PeerId, core: Arc<RwLock<CryptonoteCore<TCoin>>>) -> Self {
+    pub fn new(
+        _peer_id: PeerId,
+        core: Arc<RwLock<CryptonoteCore<TCoin>>>,
+        network_state: Arc<RwLock<NetworkState>>,
+        config: &Config,
+        peer_list: PeerList,
+        seed_nodes: Vec<Multiaddr>,
+    ) -> Self {
+        let chain_events = core.read().unwrap().blockchain().subscribe();
+        let local_transactions = network_state
+            .write()
+            .unwrap()
+            .take_relay_receiver()
+            .expect("The network state is already used by another P2P handler");
+
         Self {
+            chain_events,
             core,
+            dandelion: Dandelion::new(),
+            embargo_timer: tokio::time::interval(Duration::from_secs(1)),
+            local_transactions,
+            node_nonce: rand::thread_rng().gen(),
             peers: HashMap::new(),
             pending_messages: VecDeque::new(),
+
+            added_blocks: HashSet::new(),
+            pending_blocks: HashMap::new(),
+            sync: SyncManager::default(),
+            sync_timer: tokio::time::interval(SYNC_CHECK_INTERVAL),
+
+            network_state,
+            peer_ips: HashMap::new(),
+            peer_scores: PeerScores::default(),
+            rate_limiter: RateLimiter::new(
+                config.limit_rate_up.map(|rate| rate * 1024),
+                config.limit_rate_down.map(|rate| rate * 1024),
+            ),
+            requested_peers: HashSet::new(),
+            requested_txids: HashMap::new(),
+
+            connection_timer: tokio::time::interval(CONNECTION_CHECK_INTERVAL),
+            exclusive_nodes: config.add_exclusive_node.clone(),
+            in_peers: config.in_peers,
+            inbound_addresses: HashMap::new(),
+            listen_port: config.p2p_bind_port,
+            local_addresses: HashSet::new(),
+            out_peers: config.out_peers,
+            outbound_peers: HashSet::new(),
+            peer_addresses: HashMap::new(),
+            peer_list,
+            pending_dials: HashMap::new(),
+            priority_nodes: config.add_priority_node.clone(),
+            seed_nodes,
+        }
+    }
+
+    fn dial(&mut self, address: Multiaddr) {
+        log::debug!("Dialing {}", address);
+        self.pending_dials.insert(address.clone(), Instant::now());
+        self.pending_messages
+            .push_back(NetworkBehaviourAction::DialAddress { address });
+    }
+
+    /// Checks if an address belongs to a banned host
+    fn is_banned(&self, address: &Multiaddr) -> bool {
+        let network_state = self.network_state.read().unwrap();
+
+        ip_address(address).map_or(false, |ip| network_state.ban_list().is_banned(&ip))
+    }
+
+    /// Disconnects peers whose address got banned since they connected
+    fn disconnect_banned_peers(&mut self) {
+        let network_state = self.network_state.read().unwrap();
+
+        let banned_peers = self
+            .peer_ips
+            .iter()
+            .filter(|(_, ip)| network_state.ban_list().is_banned(ip))
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect::<Vec<_>>();
+
+        drop(network_state);
+
+        for peer_id in banned_peers {
+            log::info!("Disconnecting from banned peer {}", peer_id);
+            self.disconnect_peer(peer_id);
+        }
+    }
+
+    /// Relays transactions in the stem phase received from `source`, or submitted locally if
+    /// there's no source
+    fn relay_stem_transactions(&mut self, transactions: Vec<Transaction>, source: Option<&PeerId>) {
+        // Only peers that completed the handshake can be sent messages
+        let handshaked_peers = self
+            .peers
+            .iter()
+            .filter(|(_, node_info)| node_info.is_some())
+            .map(|(peer_id, _)| peer_id);
+        match self.dandelion.route(handshaked_peers, source) {
+            Route::Stem(stem_peer) => {
+                for transaction in &transactions {
+                    self.dandelion.add_embargo(transaction.get_hash());
+                }
+                self.send_to_peer(
+                    stem_peer,
+                    CryptonoteP2PMessage::StemTransactions(transactions),
+                );
+            }
+            Route::Fluff => {
+                let mut core = self.core.write().unwrap();
+                let blockchain = core.blockchain_mut();
+
+                let txids = transactions
+                    .iter()
+                    .map(GetHash::get_hash)
+                    .filter(|txid| blockchain.fluff_transaction(txid))
+                    .collect::<Vec<_>>();
+
+                drop(core);
+
+                // Transactions that were already fluffed have been announced before
+                if !txids.is_empty() {
+                    self.broadcast_message(CryptonoteP2PMessage::NewTransactions(txids), None);
+                }
+            }
+        }
+    }
+
+    /// Shares the number of connected peers with the rest of the node
+    fn update_connection_counts(&self) {
+        self.network_state
+            .write()
+            .unwrap()
+            .set_connection_counts(self.inbound_addresses.len(), self.outbound_peers.len());
+    }
+
+    fn record_received(&mut self, peer_id: &PeerId, size: usize) {
+        self.network_state
+            .write()
+            .unwrap()
+            .bandwidth_mut()
+            .record_received(peer_id, size);
+    }
+
+    /// Penalises a peer for misbehaving, banning it if it reached the ban score
+    fn penalize(&mut self, peer_id: PeerId, misbehavior: Misbehavior) {
+        log::debug!("Peer {} misbehaved: {:?}", peer_id, misbehavior);
+
+        // Peers on transports without IP addresses can't be banned, only disconnected
+        let ip = self.peer_ips.get(&peer_id).cloned();
+        let reached_ban_score = match ip {
+            Some(ip) => self.peer_scores.penalize(ip, misbehavior),
+            None => misbehavior.penalty() >= BAN_SCORE,
+        };
+        if !reached_ban_score {
+            return;
+        }
+
+        log::info!("Banning {} for misbehaving", peer_id);
+        if let Some(ip) = ip {
+            self.network_state
+                .write()
+                .unwrap()
+                .ban_list_mut()
+                .ban(ip, Some(BAN_DURATION));
+        }
+        if let Some(address) = self.peer_addresses.get(&peer_id) {
+            self.peer_list.remove(address);
+        }
+
+        self.disconnect_peer(peer_id);
+    }
+
+    /// Dials new peers if we're below the target number of outbound connections
+    ///
+    /// Exclusive and priority nodes are redialed whenever they're not connected. If exclusive
+    /// nodes are configured, no other peers are dialed
+    fn maintain_connections(&mut self) {
+        self.disconnect_banned_peers();
+
+        // Forget dial attempts that never resolved
+        self.pending_dials
+            .retain(|_, started| started.elapsed() < DIAL_TIMEOUT);
+
+        let connected_addresses = self
+            .peers
+            .keys()
+            .filter_map(|peer_id| self.peer_addresses.get(peer_id))
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        let fixed_nodes = self
+            .exclusive_nodes
+            .iter()
+            .chain(&self.priority_nodes)
+            .filter(|address| {
+                !connected_addresses.contains(address) && !self.pending_dials.contains_key(address)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for address in fixed_nodes {
+            self.dial(address);
+        }
+
+        if self.exclusive_nodes.is_empty() {
+            self.dial_new_peers(&connected_addresses);
+        }
+
+        if let Err(error) = self.peer_list.sync() {
+            log::warn!("Failed to save peer list: {}", error);
+        }
+        if let Err(error) = self.network_state.write().unwrap().ban_list_mut().sync() {
+            log::warn!("Failed to save ban list: {}", error);
+        }
+    }
+
+    /// Dials peers from the peer list, or the seed nodes if the list is empty
+    fn dial_new_peers(&mut self, connected_addresses: &HashSet<Multiaddr>) {
+        let missing = self
+            .out_peers
+            .saturating_sub(self.outbound_peers.len() + self.pending_dials.len());
+        if missing == 0 {
+            return;
+        }
+
+        // Bootstrap from the seed nodes if we don't know anyone else
+        if self.peer_list.is_empty() && self.outbound_peers.is_empty() {
+            for address in self.seed_nodes.clone() {
+                if !self.pending_dials.contains_key(&address) {
+                    self.dial(address);
+                }
+            }
+            return;
+        }
+
+        for _ in 0..missing {
+            let address = self.peer_list.pick_peer_to_dial(|address| {
+                connected_addresses.contains(address)
+                    || self.pending_dials.contains_key(address)
+                    || self.is_banned(address)
+            });
+
+            match address {
+                Some(address) => self.dial(address),
+                None => break,
+            }
+        }
+    }
+
+    fn upgrade(&self, message: CryptonoteP2PMessage) -> CryptonoteP2PUpgrade {
+        CryptonoteP2PUpgrade {
+            message,
+            rate_limiter: self.rate_limiter.clone(),
         }
     }
 
@@ -55,115 +405,698 @@ where
         peer_id: PeerId,
         message: CryptonoteP2PMessage,
     ) {
+        let event = self.upgrade(message);
         self.pending_messages
             .push_back(NetworkBehaviourAction::NotifyHandler {
-                event: CryptonoteP2PUpgrade(message),
+                event,
                 handler: NotifyHandler::One(connection_id),
                 peer_id,
             });
     }
 
+    /// Sends a message to any connection of the given peer
+    fn send_to_peer(&mut self, peer_id: PeerId, message: CryptonoteP2PMessage) {
+        let event = self.upgrade(message);
+        self.pending_messages
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                event,
+                handler: NotifyHandler::Any,
+                peer_id,
+            });
+    }
+
+    /// Sends a message to all peers that completed the handshake, except the given one
+    /// (usually the peer the message's contents came from)
+    fn broadcast_message(&mut self, message: CryptonoteP2PMessage, except: Option<&PeerId>) {
+        let event = self.upgrade(message);
+        for (peer_id, node_info) in self.peers.iter() {
+            if node_info.is_none() || Some(peer_id) == except {
+                continue;
+            }
+            self.pending_messages
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    event: event.clone(),
+                    handler: NotifyHandler::Any,
+                    peer_id: peer_id.clone(),
+                });
+        }
+    }
+
+    /// Closes all connections to the given peer
+    fn disconnect_peer(&mut self, peer_id: PeerId) {
+        self.pending_messages
+            .push_back(NetworkBehaviourAction::GenerateEvent(
+                CryptonoteP2PEvent::DisconnectPeer(peer_id),
+            ));
+    }
+
+    /// Sends pending sync requests and updates the sync target height
+    fn drive_sync(&mut self) {
+        let core = self.core.read().unwrap();
+        let blockchain = core.blockchain();
+        let (current_height, _) = blockchain.get_tail().unwrap();
+
+        let chain_request = self
+            .sync
+            .request_chain(current_height)
+            .map(|peer_id| (peer_id, blockchain.get_block_locator()));
+
+        drop(core);
+
+        if let Some((peer_id, block_locator)) = chain_request {
+            log::info!(
+                "Syncing from {}. Current height: {}, Target height: {}",
+                peer_id,
+                current_height,
+                self.sync.target_height()
+            );
+            self.send_to_peer(peer_id, CryptonoteP2PMessage::GetChain(block_locator));
+        }
+
+        for (peer_id, block_ids) in self.sync.request_spans() {
+            log::debug!("Requesting {} blocks from {}", block_ids.len(), peer_id);
+            self.send_to_peer(peer_id, CryptonoteP2PMessage::GetSpan(block_ids));
+        }
+
+        self.network_state
+            .write()
+            .unwrap()
+            .set_target_height(self.sync.target_height().max(current_height));
+    }
+
+    /// Adds the downloaded spans that are next in line to the chain
+    fn add_synced_blocks(&mut self) {
+        let ready_spans = self.sync.take_ready_spans();
+        if ready_spans.is_empty() {
+            return;
+        }
+
+        let mut core = self.core.write().unwrap();
+        let blockchain = core.blockchain_mut();
+
+        let mut failure = None;
+        'spans: for (peer_id, span) in ready_spans {
+            for BlockEntry {
+                block,
+                transactions,
+            } in span
+            {
+                let block_id = block.get_hash();
+                if blockchain.has_block(&block_id) {
+                    continue;
+                }
+
+                if let Err(error) = blockchain.add_block_with_transactions(block, transactions) {
+                    // Blocks of a chain that split from ours are kept as alternative blocks
+                    if blockchain.has_block(&block_id) {
+                        continue;
+                    }
+
+                    log::debug!(
+                        "Failed to add block {} from {}: {}",
+                        block_id,
+                        peer_id,
+                        error
+                    );
+                    failure = Some((peer_id, error.is_invalid()));
+                    break 'spans;
+                }
+                self.added_blocks.insert(block_id);
+            }
+        }
+
+        let (current_height, _) = blockchain.get_tail().unwrap();
+
+        drop(core);
+
+        log::info!(
+            "Synced up to height {} of {}",
+            current_height,
+            self.sync.target_height()
+        );
+
+        // The chain is of no use anymore, a new one will be requested
+        if let Some((peer_id, is_invalid)) = failure {
+            self.sync.reset();
+            if is_invalid {
+                self.penalize(peer_id, Misbehavior::InvalidBlock);
+            }
+        }
+    }
+
+    /// Adds a block relayed by a peer, along with the transactions requested for it
+    fn add_relayed_block(&mut self, peer_id: PeerId, block: Block, transactions: Vec<Transaction>) {
+        let block_id = block.get_hash();
+        let height = block.height();
+
+        let mut core = self.core.write().unwrap();
+        let blockchain = core.blockchain_mut();
+        let result = blockchain.add_block_with_transactions(block.clone(), transactions);
+        let is_tail = blockchain.get_tail().unwrap().1.get_hash() == block_id;
+
+        drop(core);
+
+        // Blocks we can't use yet (such as alt chain blocks) are fine
+        match result {
+            Err(error) if error.is_invalid() => {
+                log::debug!("Invalid block {} from {}: {}", block_id, peer_id, error);
+                self.penalize(peer_id, Misbehavior::InvalidBlock);
+                return;
+            }
+            Err(error) => {
+                log::debug!(
+                    "Could not add block {} from {}: {}",
+                    block_id,
+                    peer_id,
+                    error
+                );
+
+                // The peer's chain might be ahead of ours
+                if let Some(height) = height {
+                    self.sync.update_peer_height(&peer_id, height);
+                }
+            }
+            Ok(()) => {
+                // Pass the block on if it's the new tail, except to the peer that sent it
+                if is_tail {
+                    self.added_blocks.insert(block_id);
+                    self.broadcast_message(CryptonoteP2PMessage::NewBlock(block), Some(&peer_id));
+                }
+            }
+        }
+
+        self.drive_sync();
+    }
+
+    /// Gets the handshake information of this node
+    fn get_node_info(&self) -> NodeInfo {
+        let core = self.core.read().unwrap();
+        let blockchain = core.blockchain();
+        let (chain_height, top_block) = blockchain.get_tail().unwrap();
+
+        NodeInfo {
+            network_id: core.network().network_id(),
+            protocol_version: PROTOCOL_VERSION,
+            node_nonce: self.node_nonce,
+            listen_port: self.listen_port,
+
+            genesis_hash: blockchain
+                .get_block_by_height(0)
+                .expect("Genesis block does not exist")
+                .get_hash(),
+            top_block_hash: top_block.get_hash(),
+            chain_height,
+            cumulative_difficulty: blockchain.get_cumulative_difficulty(),
+        }
+    }
+
     fn handle_message(
         &mut self,
         peer_id: PeerId,
         connection_id: ConnectionId,
         message: CryptonoteP2PMessage,
     ) {
+        // Peers have to complete the handshake before sending anything else
+        let is_handshake_message = matches!(
+            message,
+            CryptonoteP2PMessage::Empty
+                | CryptonoteP2PMessage::Info(_)
+                | CryptonoteP2PMessage::Error(_)
+                | CryptonoteP2PMessage::GetInfo
+        );
+        if !is_handshake_message && !matches!(self.peers.get(&peer_id), Some(Some(_))) {
+            log::debug!("Ignoring message from {} before handshake", peer_id);
+            return;
+        }
+
         match message {
             CryptonoteP2PMessage::Empty => {}
             CryptonoteP2PMessage::Info(node_info) => {
                 log::debug!("Info from {}", peer_id);
 
+                if let Err(error) = self.get_node_info().check_compatibility(&node_info) {
+                    log::info!("Disconnecting from {}: {}", peer_id, error);
+
+                    // Don't try connecting to ourselves again
+                    if let HandshakeError::SelfConnection = error {
+                        if let Some(address) = self.peer_addresses.get(&peer_id) {
+                            self.peer_list.remove(address);
+                        }
+                    }
+
+                    self.disconnect_peer(peer_id);
+                    return;
+                }
+
                 if let Some(current_node_info) = self.peers.get_mut(&peer_id) {
                     *current_node_info = Some(node_info.clone());
 
+                    // We can only learn the listening address of inbound peers from their info
+                    if let Some(address) = self.inbound_addresses.get(&peer_id) {
+                        let address = with_tcp_port(address, node_info.listen_port);
+
+                        self.peer_addresses.insert(peer_id.clone(), address.clone());
+                        self.peer_list.add_grey(PeerListEntry {
+                            address,
+                            last_seen: crate::peer_list::unix_timestamp(),
+                        });
+                    }
+
+                    // Ask for more peers
+                    self.requested_peers.insert(peer_id.clone());
+                    self.send_message(
+                        connection_id,
+                        peer_id.clone(),
+                        CryptonoteP2PMessage::GetPeers,
+                    );
+
                     // Start syncing from this node if we're lagging behind
-                    let core = self.core.read().unwrap();
-                    let (current_height, _) = core.blockchain().get_tail().unwrap();
+                    self.sync.add_peer(peer_id, node_info.chain_height);
+                    self.drive_sync();
+                }
+            }
+            CryptonoteP2PMessage::NewBlock(block) => {
+                let block_id = block.get_hash();
 
-                    drop(core);
+                let core = self.core.read().unwrap();
+                let blockchain = core.blockchain();
+
+                if blockchain.has_block(&block_id) || self.pending_blocks.contains_key(&block_id) {
+                    return;
+                }
+
+                let (_, tail) = blockchain.get_tail().unwrap();
+                let extends_main_chain = block.header.prev_id == tail.get_hash();
+                let is_ahead = !blockchain.has_block(&block.header.prev_id);
+                let missing_txids = blockchain.get_missing_transactions(&block);
+
+                drop(core);
 
-                    if current_height < node_info.chain_height {
-                        log::info!(
-                            "Syncing from {}. Current Height: {}, Target height: {}",
-                            peer_id,
-                            current_height,
-                            node_info.chain_height
-                        );
-                        self.send_message(
-                            connection_id,
-                            peer_id,
-                            CryptonoteP2PMessage::GetBlocks(
-                                current_height + 1,
-                                current_height + 20,
-                            ),
-                        )
+                // The peer's chain is ahead of ours. The blocks in between are synced first
+                if is_ahead {
+                    if let Some(height) = block.height() {
+                        self.sync.update_peer_height(&peer_id, height);
                     }
+                    self.drive_sync();
+                    return;
+                }
+
+                // Only ask for the transactions we don't have. The block is added once they arrive
+                if extends_main_chain
+                    && !missing_txids.is_empty()
+                    && self.pending_blocks.len() < MAX_PENDING_BLOCKS
+                {
+                    log::debug!(
+                        "Requesting {} transactions of block {} from {}",
+                        missing_txids.len(),
+                        block_id,
+                        peer_id
+                    );
+                    self.pending_blocks
+                        .insert(block_id.clone(), (peer_id.clone(), block));
+                    self.send_message(
+                        connection_id,
+                        peer_id,
+                        CryptonoteP2PMessage::GetBlockTransactions(block_id, missing_txids),
+                    );
+                    return;
                 }
+
+                self.add_relayed_block(peer_id, block, Vec::new());
             }
-            CryptonoteP2PMessage::Blocks(blocks) => {
+            CryptonoteP2PMessage::BlockTransactions(block_id, transactions) => {
+                let block = match self.pending_blocks.remove(&block_id) {
+                    Some((requested_from, block)) if requested_from == peer_id => block,
+                    Some(pending_block) => {
+                        self.pending_blocks.insert(block_id, pending_block);
+                        self.penalize(peer_id, Misbehavior::UnsolicitedData);
+                        return;
+                    }
+                    None => {
+                        self.penalize(peer_id, Misbehavior::UnsolicitedData);
+                        return;
+                    }
+                };
+
+                self.add_relayed_block(peer_id, block, transactions);
+            }
+            CryptonoteP2PMessage::Chain(start_height, mut block_ids) => {
+                log::debug!("Received {} block IDs from {}", block_ids.len(), peer_id);
+
+                let core = self.core.read().unwrap();
+                let blockchain = core.blockchain();
+                let (current_height, _) = blockchain.get_tail().unwrap();
+
+                // The chain has to start at a block from our locator
+                let is_from_locator = block_ids.first().map_or(true, |block_id| {
+                    blockchain
+                        .get_block(block_id)
+                        .and_then(|block| block.height())
+                        == Some(start_height)
+                });
+
+                // Skip the blocks we have, on the main chain or as alternative blocks
+                let known_blocks = block_ids
+                    .iter()
+                    .take_while(|block_id| blockchain.has_block(block_id))
+                    .count();
+
+                drop(core);
+
+                if !is_from_locator {
+                    self.penalize(peer_id, Misbehavior::UnsolicitedData);
+                    return;
+                }
+
+                if start_height < current_height && known_blocks < block_ids.len() {
+                    log::info!(
+                        "Chain of {} splits from ours at height {}",
+                        peer_id,
+                        start_height
+                    );
+                }
+
+                let start_height = start_height + known_blocks as u64;
+                let block_ids = block_ids.split_off(known_blocks);
+
+                match self.sync.on_chain(&peer_id, start_height, block_ids) {
+                    Ok(()) => self.drive_sync(),
+                    Err(misbehavior) => self.penalize(peer_id, misbehavior),
+                }
+            }
+            CryptonoteP2PMessage::Span(span) => {
+                log::debug!("Received {} blocks from {}", span.len(), peer_id);
+
+                match self.sync.on_span(&peer_id, span) {
+                    Ok(()) => {
+                        self.add_synced_blocks();
+                        self.drive_sync();
+                    }
+                    Err(misbehavior) => self.penalize(peer_id, misbehavior),
+                }
+            }
+            CryptonoteP2PMessage::Transactions(transactions) => {
                 let mut core = self.core.write().unwrap();
                 let blockchain = core.blockchain_mut();
 
-                let had_blocks = !blocks.is_empty();
+                let requested_txids = self.requested_txids.entry(peer_id.clone()).or_default();
+
+                let mut accepted_txids = Vec::new();
+                let mut misbehavior = None;
+                for transaction in transactions {
+                    let txid = transaction.get_hash();
 
-                for block in blocks {
-                    if blockchain.get_block(&block.get_hash()).is_none() {
-                        // TODO: Drop connection if block was invalid (alt chain blocks allowed)
-                        blockchain.add_new_block(block).unwrap_or(());
+                    // Transactions are only ever sent as a response to `GetTransactions`
+                    if !requested_txids.remove(&txid) {
+                        misbehavior = Some(Misbehavior::UnsolicitedData);
+                        continue;
+                    }
+
+                    // A stem transaction we relayed has been fluffed by another node
+                    if blockchain.fluff_transaction(&txid) {
+                        self.dandelion.remove_embargo(&txid);
+                        accepted_txids.push(txid);
+                        continue;
+                    }
+
+                    // Ignore transactions we already know about
+                    if blockchain.has_transaction(&txid) {
+                        continue;
+                    }
+
+                    match blockchain.add_transaction(transaction) {
+                        Ok(()) => accepted_txids.push(txid),
+                        Err(error) => {
+                            log::debug!(
+                                "Rejected transaction {} from {}: {}",
+                                txid,
+                                peer_id,
+                                error
+                            );
+                            if error.is_invalid() {
+                                misbehavior = Some(Misbehavior::InvalidTransaction);
+                            }
+                        }
                     }
                 }
 
-                // If we're syncing, send the request for the next block range
-                if had_blocks {
-                    let (current_height, _) = blockchain.get_tail().unwrap();
+                drop(core);
 
-                    drop(core);
+                if let Some(misbehavior) = misbehavior {
+                    self.penalize(peer_id.clone(), misbehavior);
+                }
+
+                // Announce the newly accepted transactions to everyone else
+                if !accepted_txids.is_empty() {
+                    log::debug!(
+                        "Added {} new transactions from {}",
+                        accepted_txids.len(),
+                        peer_id
+                    );
+                    self.broadcast_message(
+                        CryptonoteP2PMessage::NewTransactions(accepted_txids),
+                        Some(&peer_id),
+                    );
+                }
+            }
+            CryptonoteP2PMessage::StemTransactions(transactions) => {
+                let mut core = self.core.write().unwrap();
+                let blockchain = core.blockchain_mut();
+
+                let mut accepted_transactions = Vec::new();
+                let mut has_invalid_transactions = false;
+                for transaction in transactions {
+                    let txid = transaction.get_hash();
+
+                    if blockchain.has_transaction(&txid)
+                        || blockchain.tx_pool().has_stem_transaction(&txid)
+                    {
+                        continue;
+                    }
+
+                    match blockchain.add_stem_transaction(transaction.clone()) {
+                        Ok(()) => accepted_transactions.push(transaction),
+                        Err(error) => {
+                            log::debug!(
+                                "Rejected stem transaction {} from {}: {}",
+                                txid,
+                                peer_id,
+                                error
+                            );
+                            has_invalid_transactions |= error.is_invalid();
+                        }
+                    }
+                }
+
+                drop(core);
+
+                if has_invalid_transactions {
+                    self.penalize(peer_id.clone(), Misbehavior::InvalidTransaction);
+                }
+
+                if accepted_transactions.is_empty() {
+                    return;
+                }
+
+                self.relay_stem_transactions(accepted_transactions, Some(&peer_id));
+            }
+            CryptonoteP2PMessage::NewTransactions(txids) => {
+                let mut core = self.core.write().unwrap();
+                let blockchain = core.blockchain_mut();
+
+                // Stem transactions we relayed that have now been fluffed by another node
+                let fluffed_txids = txids
+                    .iter()
+                    .filter(|txid| blockchain.fluff_transaction(txid))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                // Only request the transactions we don't have
+                let unknown_txids = txids
+                    .into_iter()
+                    .filter(|txid| !blockchain.has_transaction(txid))
+                    .collect::<Vec<_>>();
+
+                drop(core);
+
+                if !fluffed_txids.is_empty() {
+                    for txid in &fluffed_txids {
+                        self.dandelion.remove_embargo(txid);
+                    }
+                    self.broadcast_message(
+                        CryptonoteP2PMessage::NewTransactions(fluffed_txids),
+                        Some(&peer_id),
+                    );
+                }
 
+                if !unknown_txids.is_empty() {
+                    self.requested_txids
+                        .entry(peer_id.clone())
+                        .or_default()
+                        .extend(unknown_txids.iter().cloned());
                     self.send_message(
                         connection_id,
                         peer_id,
-                        CryptonoteP2PMessage::GetBlocks(current_height + 1, current_height + 20),
+                        CryptonoteP2PMessage::GetTransactions(unknown_txids),
                     );
                 }
             }
-            CryptonoteP2PMessage::Transactions(_transactions) => unimplemented!(),
+            CryptonoteP2PMessage::Error(error) => {
+                log::warn!("{} rejected a message: {}", peer_id, error);
+            }
             CryptonoteP2PMessage::GetInfo => {
                 log::debug!("GetInfo from {}", peer_id);
+                let node_info = self.get_node_info();
+
+                self.send_message(
+                    connection_id,
+                    peer_id,
+                    CryptonoteP2PMessage::Info(node_info),
+                );
+            }
+            CryptonoteP2PMessage::Peers(peers) => {
+                log::debug!("Received {} peer addresses from {}", peers.len(), peer_id);
+
+                if !self.requested_peers.remove(&peer_id) {
+                    self.penalize(peer_id, Misbehavior::UnsolicitedData);
+                    return;
+                }
+
+                for entry in peers.into_iter().take(PEER_EXCHANGE_SIZE) {
+                    // Don't try connecting to ourselves
+                    if self.local_addresses.contains(&entry.address) {
+                        continue;
+                    }
+                    self.peer_list.add_shared(entry);
+                }
+            }
+            CryptonoteP2PMessage::GetPeers => {
+                let peers = self.peer_list.random_white_peers(PEER_EXCHANGE_SIZE);
+
+                self.send_message(connection_id, peer_id, CryptonoteP2PMessage::Peers(peers));
+            }
+            CryptonoteP2PMessage::GetChain(block_locator) => {
                 let core = self.core.read().unwrap();
                 let blockchain = core.blockchain();
 
-                let node_info = NodeInfo {
-                    chain_height: blockchain.get_tail().unwrap().0,
-                };
+                let (start_height, block_ids) =
+                    match blockchain.find_common_ancestor(&block_locator) {
+                        Some(start_height) => (
+                            start_height,
+                            blockchain
+                                .get_blocks(start_height, start_height + MAX_CHAIN_LENGTH as u64)
+                                .iter()
+                                .map(GetHash::get_hash)
+                                .collect(),
+                        ),
+                        None => (0, Vec::new()),
+                    };
 
                 drop(core);
 
                 self.send_message(
                     connection_id,
                     peer_id,
-                    CryptonoteP2PMessage::Info(node_info),
+                    CryptonoteP2PMessage::Chain(start_height, block_ids),
                 );
             }
-            CryptonoteP2PMessage::GetBlocks(start, end) => {
+            CryptonoteP2PMessage::GetSpan(block_ids) => {
                 let core = self.core.read().unwrap();
                 let blockchain = core.blockchain();
 
-                // TODO: Implement alternative chain block retrieval
-                let blocks = blockchain.get_blocks(start, end);
+                // Blocks are served from the main chain and alternative chains alike, up to the
+                // first block that can't be served in full
+                let span = block_ids
+                    .iter()
+                    .take(SPAN_LENGTH)
+                    .map(|block_id| {
+                        let block = blockchain
+                            .get_block(block_id)
+                            .or_else(|| blockchain.get_alternative_block(block_id))?;
+                        let transactions = block
+                            .tx_hashes
+                            .iter()
+                            .map(|txid| {
+                                blockchain
+                                    .tx_pool()
+                                    .get_transaction(txid)
+                                    .cloned()
+                                    .or_else(|| blockchain.get_transaction(txid))
+                            })
+                            .collect::<Option<Vec<_>>>()?;
+
+                        Some(BlockEntry {
+                            block,
+                            transactions,
+                        })
+                    })
+                    .take_while(Option::is_some)
+                    .flatten();
+
+                // Leave out the blocks that don't fit in a single message
+                let span_type = CryptonoteP2PMessage::Span(Vec::new()).message_type();
+                let mut size_left =
+                    max_message_size(span_type).unwrap_or(0).saturating_sub(8) as u64;
+                let span = span
+                    .take_while(|entry| {
+                        let size = bincode::serialized_size(entry).unwrap_or(u64::MAX);
+                        let fits = size <= size_left;
+                        size_left = size_left.saturating_sub(size);
+                        fits
+                    })
+                    .collect();
 
                 drop(core);
 
-                self.send_message(connection_id, peer_id, CryptonoteP2PMessage::Blocks(blocks));
+                self.send_message(connection_id, peer_id, CryptonoteP2PMessage::Span(span));
+            }
+            CryptonoteP2PMessage::GetBlockTransactions(block_id, txids) => {
+                let core = self.core.read().unwrap();
+                let blockchain = core.blockchain();
+
+                let block = blockchain
+                    .get_block(&block_id)
+                    .or_else(|| blockchain.get_alternative_block(&block_id));
+
+                // Only serve transactions that are part of the block
+                let transactions = block
+                    .map(|block| {
+                        txids
+                            .iter()
+                            .filter(|txid| block.tx_hashes.contains(txid))
+                            .filter_map(|txid| {
+                                blockchain
+                                    .tx_pool()
+                                    .get_transaction(txid)
+                                    .cloned()
+                                    .or_else(|| blockchain.get_transaction(txid))
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                drop(core);
+
+                self.send_message(
+                    connection_id,
+                    peer_id,
+                    CryptonoteP2PMessage::BlockTransactions(block_id, transactions),
+                );
             }
             CryptonoteP2PMessage::GetTransactions(txids) => {
-                // TODO: Implement unconfirmed transaction retrieval
                 let core = self.core.read().unwrap();
                 let blockchain = core.blockchain();
 
+                // Serve unconfirmed transactions first, then confirmed ones
                 let transactions = txids
                     .iter()
-                    .filter_map(|txid| blockchain.get_transaction(txid))
+                    .filter_map(|txid| {
+                        blockchain
+                            .tx_pool()
+                            .get_transaction(txid)
+                            .cloned()
+                            .or_else(|| blockchain.get_transaction(txid))
+                    })
                     .collect::<Vec<_>>();
 
                 drop(core);
@@ -184,12 +1117,12 @@ where
     TCoin: cryptonote_core::EmissionCurve + Unpin + Send + Sync + 'static,
 {
     type ProtocolsHandler =
-        OneShotHandler<CryptonoteP2PUpgrade, CryptonoteP2PUpgrade, CryptonoteP2PUpgrade>;
-    type OutEvent = CryptonoteP2PUpgrade;
+        OneShotHandler<CryptonoteP2PUpgrade, CryptonoteP2PUpgrade, CryptonoteP2PHandlerEvent>;
+    type OutEvent = CryptonoteP2PEvent;
 
     fn new_handler(&mut self) -> Self::ProtocolsHandler {
         OneShotHandler::new(
-            SubstreamProtocol::from(CryptonoteP2PUpgrade(CryptonoteP2PMessage::Empty)),
+            SubstreamProtocol::from(self.upgrade(CryptonoteP2PMessage::Empty)),
             OneShotHandlerConfig {
                 keep_alive_timeout: Duration::from_secs(600),
                 outbound_substream_timeout: Duration::from_secs(608),
@@ -197,23 +1130,112 @@ where
         )
     }
 
-    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
-        Vec::new()
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.peer_addresses
+            .get(peer_id)
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _connection_id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let address = match endpoint {
+            ConnectedPoint::Dialer { address } => address,
+            ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+        };
+        if let Some(ip) = ip_address(address) {
+            self.peer_ips.insert(peer_id.clone(), ip);
+        }
+
+        if self.is_banned(address) {
+            log::debug!("Refusing connection with banned peer {}", peer_id);
+            self.disconnect_peer(peer_id.clone());
+            return;
+        }
+
+        match endpoint {
+            ConnectedPoint::Dialer { address } => {
+                self.pending_dials.remove(address);
+                self.outbound_peers.insert(peer_id.clone());
+                self.peer_addresses.insert(peer_id.clone(), address.clone());
+                self.peer_list.add_white(address.clone());
+            }
+            ConnectedPoint::Listener { send_back_addr, .. } => {
+                if self.inbound_addresses.len() >= self.in_peers {
+                    log::debug!(
+                        "Refusing inbound connection from {}: too many peers",
+                        peer_id
+                    );
+                    self.disconnect_peer(peer_id.clone());
+                    return;
+                }
+
+                self.inbound_addresses
+                    .insert(peer_id.clone(), send_back_addr.clone());
+            }
+        }
+        self.update_connection_counts();
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        _peer_id: Option<&PeerId>,
+        address: &Multiaddr,
+        error: &dyn std::error::Error,
+    ) {
+        log::debug!("Failed to reach {}: {}", address, error);
+        self.pending_dials.remove(address);
+        self.peer_list.mark_failed(address);
+    }
+
+    fn inject_new_listen_addr(&mut self, address: &Multiaddr) {
+        self.local_addresses.insert(address.clone());
+    }
+
+    fn inject_expired_listen_addr(&mut self, address: &Multiaddr) {
+        self.local_addresses.remove(address);
+    }
+
+    fn inject_new_external_addr(&mut self, address: &Multiaddr) {
+        // Other peers see the port of the connection, not the one we listen on
+        self.local_addresses
+            .insert(with_tcp_port(address, self.listen_port));
     }
 
     fn inject_connected(&mut self, peer_id: &PeerId) {
-        self.pending_messages
-            .push_back(NetworkBehaviourAction::NotifyHandler {
-                event: CryptonoteP2PUpgrade(CryptonoteP2PMessage::GetInfo),
-                handler: NotifyHandler::Any,
-                peer_id: peer_id.clone(),
-            });
+        self.send_to_peer(peer_id.clone(), CryptonoteP2PMessage::GetInfo);
         self.peers.insert(peer_id.clone(), None);
         log::debug!("New peer connected: {}", peer_id);
     }
 
     fn inject_disconnected(&mut self, peer_id: &PeerId) {
         self.peers.remove(peer_id);
+        self.dandelion.remove_peer(peer_id);
+        self.inbound_addresses.remove(peer_id);
+        self.peer_ips.remove(peer_id);
+        self.network_state
+            .write()
+            .unwrap()
+            .bandwidth_mut()
+            .remove_peer(peer_id);
+        self.requested_peers.remove(peer_id);
+        self.requested_txids.remove(peer_id);
+        self.sync.remove_peer(peer_id);
+        self.pending_blocks
+            .retain(|_, (requested_from, _)| requested_from != peer_id);
+
+        // Refresh the last seen time of outbound peers
+        if self.outbound_peers.remove(peer_id) {
+            if let Some(address) = self.peer_addresses.get(peer_id) {
+                self.peer_list.add_white(address.clone());
+            }
+        }
+        self.update_connection_counts();
         log::debug!("Peer disconnected: {}", peer_id);
     }
 
@@ -221,39 +1243,123 @@ where
         &mut self,
         peer_id: PeerId,
         connection_id: ConnectionId,
-        event: CryptonoteP2PUpgrade,
+        event: CryptonoteP2PHandlerEvent,
     ) {
-        self.handle_message(peer_id, connection_id, event.0);
+        match event {
+            CryptonoteP2PHandlerEvent::Received(message, size) => {
+                self.record_received(&peer_id, size);
+                self.handle_message(peer_id, connection_id, message)
+            }
+            CryptonoteP2PHandlerEvent::Sent(size) => {
+                self.network_state
+                    .write()
+                    .unwrap()
+                    .bandwidth_mut()
+                    .record_sent(&peer_id, size);
+            }
+            CryptonoteP2PHandlerEvent::Undecodable(error, size) => {
+                self.record_received(&peer_id, size);
+                log::debug!("Undecodable message from {}: {}", peer_id, error);
+                self.penalize(peer_id, Misbehavior::UndecodableMessage);
+            }
+            CryptonoteP2PHandlerEvent::TooLarge {
+                message_type,
+                limit,
+            } => {
+                log::debug!(
+                    "Message of type {} from {} is over the limit of {} bytes",
+                    message_type,
+                    peer_id,
+                    limit
+                );
+                self.send_message(
+                    connection_id,
+                    peer_id,
+                    CryptonoteP2PMessage::Error(ProtocolError::MessageTooLarge {
+                        message_type,
+                        limit: limit as u64,
+                    }),
+                );
+            }
+        }
     }
 
     fn poll(
         &mut self,
         context: &mut Context,
         _params: &mut impl PollParameters,
-    ) -> Poll<NetworkBehaviourAction<CryptonoteP2PUpgrade, CryptonoteP2PUpgrade>> {
+    ) -> Poll<NetworkBehaviourAction<CryptonoteP2PUpgrade, CryptonoteP2PEvent>> {
+        // Relay blocks mined by this node
+        while let Poll::Ready(Some(event)) = self.chain_events.poll_next_unpin(context) {
+            match event {
+                Ok(ChainEvent::NewBlock { block, .. }) => {
+                    // Blocks added by the P2P protocol were synced or relayed already
+                    let block_id = block.get_hash();
+                    if self.added_blocks.remove(&block_id) {
+                        continue;
+                    }
+
+                    // Blocks re-added by reorganizations aren't new, and blocks mined while
+                    // syncing are stale
+                    let core = self.core.read().unwrap();
+                    let (height, tail) = core.blockchain().get_tail().unwrap();
+                    let is_new_tail =
+                        tail.get_hash() == block_id && height >= self.sync.target_height();
+                    drop(core);
+
+                    if is_new_tail {
+                        self.broadcast_message(CryptonoteP2PMessage::NewBlock(block), None);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(count)) => {
+                    log::warn!("Missed {} chain events", count);
+                    // The events of the blocks we added may have been missed
+                    self.added_blocks.clear();
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        // Relay transactions submitted to this node as if they were received as stem transactions
+        while let Poll::Ready(Some(transaction)) = self.local_transactions.poll_next_unpin(context)
         {
+            self.relay_stem_transactions(vec![transaction], None);
+        }
+
+        // Fluff any stem transactions whose embargo expired
+        while let Poll::Ready(_) = self.embargo_timer.poll_tick(context) {
+            let expired_txids = self.dandelion.take_expired_embargoes();
+            if expired_txids.is_empty() {
+                continue;
+            }
+
             let mut core = self.core.write().unwrap();
             let blockchain = core.blockchain_mut();
 
-            let block = blockchain.next();
-            futures::pin_mut!(block);
-
-            // Check for new blocks from the blockchain
-            // TODO FIXME: Blocking on a future feels incorrect within an async context
-            if let Poll::Ready(Some(block)) = block.poll(context) {
-                for (peer_id, _) in self.peers.iter() {
-                    self.pending_messages
-                        .push_back(NetworkBehaviourAction::NotifyHandler {
-                            event: CryptonoteP2PUpgrade(CryptonoteP2PMessage::Blocks(vec![
-                                block.clone()
-                            ])),
-                            handler: NotifyHandler::Any,
-                            peer_id: peer_id.clone(),
-                        });
-                }
+            let txids = expired_txids
+                .into_iter()
+                .filter(|txid| blockchain.fluff_transaction(txid))
+                .collect::<Vec<_>>();
+
+            drop(core);
+
+            if !txids.is_empty() {
+                log::debug!("Embargo expired for {} stem transactions", txids.len());
+                self.broadcast_message(CryptonoteP2PMessage::NewTransactions(txids), None);
             }
         }
 
+        // Download blocks if we're behind and retry stalled requests
+        while let Poll::Ready(_) = self.sync_timer.poll_tick(context) {
+            self.drive_sync();
+        }
+
+        // Keep up the number of outbound connections
+        while let Poll::Ready(_) = self.connection_timer.poll_tick(context) {
+            self.maintain_connections();
+        }
+
         if let Some(message) = self.pending_messages.pop_front() {
             return Poll::Ready(message);
         }
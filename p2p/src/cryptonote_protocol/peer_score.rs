@@ -0,0 +1,127 @@
+//! # Peer misbehaviour scoring
+//!
+//! Peers accumulate penalty points for misbehaving. Once a peer reaches the ban score it is
+//! disconnected and its address is banned temporarily
+//!
+//! Scores are kept by IP address, so peers can't reset them by reconnecting. They're forgotten
+//! once a peer behaves for long enough
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Score at which a peer gets banned
+pub const BAN_SCORE: u32 = 100;
+
+/// Duration of bans issued for misbehaviour
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Time after the last misbehaviour of a peer at which its score is forgotten
+pub const SCORE_DECAY_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Ways in which a peer can misbehave
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Misbehavior {
+    /// Sent a block that breaks consensus rules
+    InvalidBlock,
+    /// Sent a transaction that breaks consensus rules
+    InvalidTransaction,
+    /// Sent a packet that could not be decoded
+    UndecodableMessage,
+    /// Sent data we didn't ask for
+    UnsolicitedData,
+}
+
+impl Misbehavior {
+    /// Number of penalty points given for the misbehaviour
+    pub fn penalty(self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock => BAN_SCORE,
+            Misbehavior::InvalidTransaction => 20,
+            Misbehavior::UndecodableMessage => 50,
+            Misbehavior::UnsolicitedData => 10,
+        }
+    }
+}
+
+/// Penalty points of peers by IP address, along with the time they last misbehaved
+#[derive(Default)]
+pub struct PeerScores {
+    scores: HashMap<IpAddr, (u32, Instant)>,
+}
+
+impl PeerScores {
+    /// Penalises the peer at the given address for misbehaving
+    ///
+    /// # Returns
+    /// Whether the peer reached the ban score
+    pub fn penalize(&mut self, ip: IpAddr, misbehavior: Misbehavior) -> bool {
+        self.penalize_at(ip, misbehavior, Instant::now())
+    }
+
+    fn penalize_at(&mut self, ip: IpAddr, misbehavior: Misbehavior, now: Instant) -> bool {
+        self.scores.retain(|_, (_, last_misbehavior)| {
+            now.saturating_duration_since(*last_misbehavior) < SCORE_DECAY_WINDOW
+        });
+
+        let (score, last_misbehavior) = self.scores.entry(ip).or_insert((0, now));
+        *score = score.saturating_add(misbehavior.penalty());
+        *last_misbehavior = now;
+
+        *score >= BAN_SCORE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last_octet: u8) -> IpAddr {
+        IpAddr::from([1, 2, 3, last_octet])
+    }
+
+    #[test]
+    fn bans_after_repeated_misbehavior() {
+        let mut scores = PeerScores::default();
+
+        for _ in 0..9 {
+            assert!(!scores.penalize(ip(1), Misbehavior::UnsolicitedData));
+        }
+        assert!(scores.penalize(ip(1), Misbehavior::UnsolicitedData));
+    }
+
+    #[test]
+    fn bans_invalid_blocks_immediately() {
+        let mut scores = PeerScores::default();
+
+        assert!(scores.penalize(ip(1), Misbehavior::InvalidBlock));
+    }
+
+    #[test]
+    fn scores_are_per_address() {
+        let mut scores = PeerScores::default();
+
+        assert!(!scores.penalize(ip(1), Misbehavior::UndecodableMessage));
+        assert!(!scores.penalize(ip(2), Misbehavior::UndecodableMessage));
+        assert!(scores.penalize(ip(2), Misbehavior::UndecodableMessage));
+    }
+
+    #[test]
+    fn scores_decay_after_good_behavior() {
+        let mut scores = PeerScores::default();
+        let start = Instant::now();
+
+        assert!(!scores.penalize_at(ip(1), Misbehavior::UndecodableMessage, start));
+        assert!(!scores.penalize_at(ip(2), Misbehavior::UndecodableMessage, start));
+
+        // Misbehaving within the window keeps the score around for longer
+        let later = start + SCORE_DECAY_WINDOW / 2;
+        assert!(!scores.penalize_at(ip(1), Misbehavior::UnsolicitedData, later));
+
+        let after_window = start + SCORE_DECAY_WINDOW;
+        assert!(scores.penalize_at(ip(1), Misbehavior::UndecodableMessage, after_window));
+        assert!(!scores.penalize_at(ip(2), Misbehavior::UndecodableMessage, after_window));
+    }
+}
@@ -3,7 +3,7 @@ use std::pin::Pin;
 
 use anyhow::Context;
 use libp2p::{
-    core::upgrade::{self, InboundUpgrade, OutboundUpgrade, UpgradeInfo},
+    core::upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeInfo},
     futures::io::{AsyncRead, AsyncWrite},
 };
 use serde::{Deserialize, Serialize};
@@ -11,9 +11,88 @@ use serde::{Deserialize, Serialize};
 use common::{Block, Transaction};
 use crypto::Hash256;
 
+use super::framing::{self, FramingError};
+use crate::{bandwidth::RateLimiter, peer_list::PeerListEntry};
+
+/// Version of the P2P protocol implemented by this node
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest version of the P2P protocol this node can talk to
+pub const MINIMUM_PROTOCOL_VERSION: u32 = 1;
+
+/// Reasons for which a peer's handshake is rejected
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum HandshakeError {
+    /// The peer is on a different network
+    #[error("Peer is on a different network")]
+    NetworkMismatch,
+
+    /// The peer's protocol version is too old
+    #[error("Peer uses unsupported protocol version {}", _0)]
+    UnsupportedProtocolVersion(u32),
+
+    /// The peer's chain starts with a different genesis block
+    #[error("Peer has a different genesis block {}", _0)]
+    GenesisMismatch(Hash256),
+
+    /// The peer is this node
+    #[error("Connected to ourselves")]
+    SelfConnection,
+}
+
+/// Handshake information about a node and its main chain
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NodeInfo {
+    /// ID of the network the node is on
+    pub network_id: [u8; 16],
+    /// Version of the P2P protocol the node implements
+    pub protocol_version: u32,
+    /// Random value generated at startup to detect connections to ourselves
+    pub node_nonce: u64,
+    /// Port the node accepts P2P connections on
+    pub listen_port: u16,
+
+    /// ID of the genesis block of the node's chain
+    pub genesis_hash: Hash256,
+    /// ID of the block at the tail of the node's main chain
+    pub top_block_hash: Hash256,
+    /// Height of the node's main chain tail
     pub chain_height: u64,
+    /// Cumulative difficulty of the node's main chain
+    pub cumulative_difficulty: u128,
+}
+
+impl NodeInfo {
+    /// Checks if a peer with the given info can be talked to by a node with this info
+    pub fn check_compatibility(&self, peer_info: &NodeInfo) -> Result<(), HandshakeError> {
+        if peer_info.node_nonce == self.node_nonce {
+            return Err(HandshakeError::SelfConnection);
+        }
+        if peer_info.network_id != self.network_id {
+            return Err(HandshakeError::NetworkMismatch);
+        }
+        if peer_info.protocol_version < MINIMUM_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedProtocolVersion(
+                peer_info.protocol_version,
+            ));
+        }
+        if peer_info.genesis_hash != self.genesis_hash {
+            return Err(HandshakeError::GenesisMismatch(
+                peer_info.genesis_hash.clone(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A block along with its transactions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockEntry {
+    /// The block itself
+    pub block: Block,
+    /// Transactions of the block, in the same order as its transaction hashes
+    pub transactions: Vec<Transaction>,
 }
 
 /// P2P Protocol Messages
@@ -27,24 +106,71 @@ pub enum CryptonoteP2PMessage {
     /// chain
     Info(NodeInfo),
 
-    /// A set of blocks of transactions, as a response to a node sync or
-    /// when a miner finds a new block
-    Blocks(Vec<Block>),
+    /// A newly mined block, broadcasted when a miner finds it
+    ///
+    /// Only the IDs of the block's transactions are sent. Receivers rebuild the block from
+    /// their transaction pool and request the transactions they're missing using
+    /// `GetBlockTransactions`
+    NewBlock(Block),
+
+    /// Transactions of the block with the given ID, as a response to `GetBlockTransactions`
+    BlockTransactions(Hash256, Vec<Transaction>),
+
+    /// Consecutive block IDs of a node's main chain, as a response to `GetChain`
+    ///
+    /// The first ID is the most recent block the node found in the block locator, which is at
+    /// the given height
+    Chain(u64, Vec<Hash256>),
+
+    /// Blocks along with their transactions, as a response to `GetSpan`
+    Span(Vec<BlockEntry>),
 
     /// A set of individual transactions, as a response to a node sync or
     /// when new transactions are broadcasted
     Transactions(Vec<Transaction>),
 
+    /// A set of transactions in the Dandelion++ stem phase
+    ///
+    /// These are forwarded to a single peer or fluffed, and must not be announced to
+    /// other peers while they are in the stem phase
+    StemTransactions(Vec<Transaction>),
+
+    /// Addresses of other peers on the network, as a response to a peer exchange
+    Peers(Vec<PeerListEntry>),
+
+    /// Announcement of transaction IDs newly accepted into a node's transaction pool
+    ///
+    /// Receivers request the transactions they don't have using `GetTransactions`
+    NewTransactions(Vec<Hash256>),
+
+    /// Notice that a message from the receiver was rejected
+    Error(ProtocolError),
+
     // ----------- Request Messages ------------
     /// Request for node info
     GetInfo,
 
-    /// Request for the given blocks from start to end height
+    /// Request for addresses of other peers the node knows about
+    GetPeers,
+
+    /// Request for the IDs of the main chain blocks following the most recent block found in
+    /// the given block locator
+    ///
+    /// The block locator is a sparse list of block IDs from the sender's main chain, dense near
+    /// its tail. It lets the node find where the chains of both nodes split. The number of IDs
+    /// sent is limited, and none are sent if no block in the locator is known
+    GetChain(Vec<Hash256>),
+
+    /// Request for the given blocks along with their transactions
     ///
-    /// The range of blocks returned is similar to Rust slices (i.e, start inclusive, end exclusive)
-    /// The node can send multiple blocks for the same height in case of a chain split, and
-    /// fewer blocks than the end in case of shorter node chain height
-    GetBlocks(u64, u64),
+    /// Blocks can be from the main chain or alternative chains. The node sends the blocks in
+    /// the order they were requested, stopping at the first block it doesn't have
+    GetSpan(Vec<Hash256>),
+
+    /// Request for the given transactions of the block with the given ID
+    ///
+    /// The node only sends the transactions it has that are part of the block
+    GetBlockTransactions(Hash256, Vec<Hash256>),
 
     /// Request for the given transaction IDs, confirmed and unconfirmed
     ///
@@ -53,28 +179,104 @@ pub enum CryptonoteP2PMessage {
     GetTransactions(Vec<Hash256>),
 }
 
-impl From<()> for CryptonoteP2PUpgrade {
-    fn from(_: ()) -> Self {
-        CryptonoteP2PUpgrade(CryptonoteP2PMessage::Empty)
+/// Reasons for rejecting a message, sent back to its sender
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum ProtocolError {
+    /// The message was larger than allowed for its type
+    #[error(
+        "Message of type {} exceeds the limit of {} bytes",
+        message_type,
+        limit
+    )]
+    MessageTooLarge {
+        /// Type of the rejected message
+        message_type: u8,
+        /// Maximum length allowed for the message type
+        limit: u64,
+    },
+}
+
+impl CryptonoteP2PMessage {
+    /// ID of the message type, sent in the frame header
+    pub fn message_type(&self) -> u8 {
+        match self {
+            CryptonoteP2PMessage::Empty => 0,
+            CryptonoteP2PMessage::Info(_) => 1,
+            CryptonoteP2PMessage::NewBlock(_) => 2,
+            CryptonoteP2PMessage::BlockTransactions(_, _) => 3,
+            CryptonoteP2PMessage::Chain(_, _) => 4,
+            CryptonoteP2PMessage::Span(_) => 5,
+            CryptonoteP2PMessage::Transactions(_) => 6,
+            CryptonoteP2PMessage::StemTransactions(_) => 7,
+            CryptonoteP2PMessage::Peers(_) => 8,
+            CryptonoteP2PMessage::NewTransactions(_) => 9,
+            CryptonoteP2PMessage::Error(_) => 10,
+            CryptonoteP2PMessage::GetInfo => 11,
+            CryptonoteP2PMessage::GetPeers => 12,
+            CryptonoteP2PMessage::GetChain(_) => 13,
+            CryptonoteP2PMessage::GetSpan(_) => 14,
+            CryptonoteP2PMessage::GetBlockTransactions(_, _) => 15,
+            CryptonoteP2PMessage::GetTransactions(_) => 16,
+        }
     }
 }
 
-impl From<CryptonoteP2PMessage> for CryptonoteP2PUpgrade {
-    fn from(message: CryptonoteP2PMessage) -> Self {
-        CryptonoteP2PUpgrade(message)
+/// Maximum encoded size of messages of the given type, or `None` for unknown types
+///
+/// Responses carrying blocks and transactions may be large, while requests are kept small
+pub fn max_message_size(message_type: u8) -> Option<usize> {
+    const KIB: usize = 1024;
+    const MIB: usize = 1024 * KIB;
+
+    match message_type {
+        // Empty, GetInfo, GetPeers
+        0 | 11 | 12 => Some(64),
+        // Info, Error
+        1 | 10 => Some(KIB),
+        // NewBlock, Chain
+        2 | 4 => Some(MIB),
+        // BlockTransactions, Span, Transactions, StemTransactions
+        3 | 5 | 6 | 7 => Some(64 * MIB),
+        // Peers, GetChain, GetSpan
+        8 | 13 | 14 => Some(64 * KIB),
+        // NewTransactions, GetBlockTransactions, GetTransactions
+        9 | 15 | 16 => Some(256 * KIB),
+        _ => None,
     }
 }
 
+/// Events produced by the protocol handler of a connection
+pub enum CryptonoteP2PHandlerEvent {
+    /// A message of the given framed size was received from the peer
+    Received(CryptonoteP2PMessage, usize),
+    /// A message of the given framed size was sent to the peer
+    Sent(usize),
+    /// A packet of the given framed size was received from the peer that could not be decoded
+    Undecodable(bincode::Error, usize),
+    /// The peer sent a message over the size limit of its type, which was not read
+    TooLarge {
+        /// Type of the message
+        message_type: u8,
+        /// Maximum length allowed for the message type
+        limit: usize,
+    },
+}
+
 /// Handles sending the actual message to the network
 #[derive(Clone)]
-pub struct CryptonoteP2PUpgrade(pub CryptonoteP2PMessage);
+pub struct CryptonoteP2PUpgrade {
+    /// Message to send, ignored for inbound substreams
+    pub message: CryptonoteP2PMessage,
+    /// Limits of the rate at which messages are sent and received
+    pub rate_limiter: RateLimiter,
+}
 
 impl UpgradeInfo for CryptonoteP2PUpgrade {
     type Info = &'static [u8];
     type InfoIter = std::iter::Once<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once(b"/unprll/1.0.0")
+        std::iter::once(b"/unprll/2.0.0")
     }
 }
 
@@ -84,14 +286,42 @@ impl<TSocket> InboundUpgrade<TSocket> for CryptonoteP2PUpgrade
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = CryptonoteP2PMessage;
+    // Decoding errors and oversize messages are part of the output so that the sender can be
+    // penalised for or told about them
+    type Output = CryptonoteP2PHandlerEvent;
     type Error = anyhow::Error;
     type Future = UpgradeFuture<Self::Output, Self::Error>;
 
     fn upgrade_inbound(self, mut socket: TSocket, _: Self::Info) -> Self::Future {
         Box::pin(async move {
-            let packet = upgrade::read_one(&mut socket, 1_048_576).await?;
-            bincode::deserialize(&packet).with_context(|| "Error deserializing incoming packet")
+            let length = match framing::read_header(&mut socket, max_message_size).await {
+                Ok((_, length)) => length,
+                Err(FramingError::MessageTooLarge {
+                    message_type,
+                    limit,
+                    ..
+                }) => {
+                    return Ok(CryptonoteP2PHandlerEvent::TooLarge {
+                        message_type,
+                        limit,
+                    })
+                }
+                Err(error) => {
+                    return Err(error).with_context(|| "Error reading incoming packet header")
+                }
+            };
+
+            let size = framing::framed_size(length);
+            self.rate_limiter.download(size).await;
+
+            let payload = framing::read_payload(&mut socket, length)
+                .await
+                .with_context(|| "Error reading incoming packet")?;
+
+            Ok(match bincode::deserialize(&payload) {
+                Ok(message) => CryptonoteP2PHandlerEvent::Received(message, size),
+                Err(error) => CryptonoteP2PHandlerEvent::Undecodable(error, size),
+            })
         })
     }
 }
@@ -100,16 +330,128 @@ impl<TSocket> OutboundUpgrade<TSocket> for CryptonoteP2PUpgrade
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = ();
+    type Output = CryptonoteP2PHandlerEvent;
     type Error = std::io::Error;
     type Future = UpgradeFuture<Self::Output, Self::Error>;
 
     fn upgrade_outbound(self, mut socket: TSocket, _: Self::Info) -> Self::Future {
         Box::pin(async move {
+            let message_type = self.message.message_type();
             let packet =
-                bincode::serialize(&self.0).map_err(|_| std::io::ErrorKind::InvalidInput)?;
-            upgrade::write_one(&mut socket, packet).await?;
-            Ok(())
+                bincode::serialize(&self.message).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+
+            // The receiver would reject the message without reading it
+            if max_message_size(message_type).map_or(true, |limit| packet.len() > limit) {
+                return Err(std::io::ErrorKind::InvalidInput.into());
+            }
+
+            let size = framing::framed_size(packet.len());
+            self.rate_limiter.upload(size).await;
+            framing::write_message(&mut socket, message_type, &packet).await?;
+
+            Ok(CryptonoteP2PHandlerEvent::Sent(size))
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    fn node_info() -> NodeInfo {
+        NodeInfo {
+            network_id: [1; 16],
+            protocol_version: PROTOCOL_VERSION,
+            node_nonce: 1,
+            listen_port: 21149,
+            genesis_hash: Hash256::null_hash(),
+            top_block_hash: Hash256::null_hash(),
+            chain_height: 0,
+            cumulative_difficulty: 1,
+        }
+    }
+
+    #[test]
+    fn accepts_compatible_peers() {
+        let peer_info = NodeInfo {
+            node_nonce: 2,
+            chain_height: 100,
+            cumulative_difficulty: 101,
+            ..node_info()
+        };
+
+        assert_eq!(node_info().check_compatibility(&peer_info), Ok(()));
+    }
+
+    #[test]
+    fn rejects_incompatible_peers() {
+        let ours = node_info();
+
+        assert_eq!(
+            ours.check_compatibility(&ours),
+            Err(HandshakeError::SelfConnection)
+        );
+        assert_eq!(
+            ours.check_compatibility(&NodeInfo {
+                node_nonce: 2,
+                network_id: [2; 16],
+                ..node_info()
+            }),
+            Err(HandshakeError::NetworkMismatch)
+        );
+        assert_eq!(
+            ours.check_compatibility(&NodeInfo {
+                node_nonce: 2,
+                protocol_version: MINIMUM_PROTOCOL_VERSION - 1,
+                ..node_info()
+            }),
+            Err(HandshakeError::UnsupportedProtocolVersion(
+                MINIMUM_PROTOCOL_VERSION - 1
+            ))
+        );
+
+        let mut other_genesis = node_info();
+        other_genesis.node_nonce = 2;
+        other_genesis.genesis_hash =
+            Hash256::try_from("7d491759c7534ca5a8be62ec7fa34dc939659f5afd4b4f1da2c671a84773cedc")
+                .unwrap();
+        assert_eq!(
+            ours.check_compatibility(&other_genesis),
+            Err(HandshakeError::GenesisMismatch(
+                other_genesis.genesis_hash.clone()
+            ))
+        );
+    }
+
+    #[test]
+    fn every_message_type_has_a_size_limit() {
+        let messages = vec![
+            CryptonoteP2PMessage::Empty,
+            CryptonoteP2PMessage::Info(node_info()),
+            CryptonoteP2PMessage::Chain(0, vec![Hash256::null_hash()]),
+            CryptonoteP2PMessage::Span(vec![]),
+            CryptonoteP2PMessage::Transactions(vec![]),
+            CryptonoteP2PMessage::StemTransactions(vec![]),
+            CryptonoteP2PMessage::Peers(vec![]),
+            CryptonoteP2PMessage::NewTransactions(vec![]),
+            CryptonoteP2PMessage::Error(ProtocolError::MessageTooLarge {
+                message_type: 0,
+                limit: 0,
+            }),
+            CryptonoteP2PMessage::GetInfo,
+            CryptonoteP2PMessage::GetPeers,
+            CryptonoteP2PMessage::GetChain(vec![]),
+            CryptonoteP2PMessage::GetSpan(vec![]),
+            CryptonoteP2PMessage::GetBlockTransactions(Hash256::null_hash(), vec![]),
+            CryptonoteP2PMessage::GetTransactions(vec![]),
+        ];
+
+        for message in messages {
+            let limit = max_message_size(message.message_type()).unwrap();
+            assert!(bincode::serialize(&message).unwrap().len() <= limit);
+        }
+        assert_eq!(max_message_size(17), None);
+    }
+}
@@ -0,0 +1,507 @@
+//! # Block synchronization
+//!
+//! Downloads the main chain of our peers when we're behind. A chain of block IDs is requested
+//! from a single peer first, then split into spans which are downloaded in parallel from all
+//! peers that have them. Spans can arrive in any order, but are handed out in height order so
+//! that they can be validated one after the other
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+use common::GetHash;
+use crypto::Hash256;
+
+use super::{peer_score::Misbehavior, protocol::BlockEntry};
+
+/// Maximum number of block IDs sent in response to a chain request
+pub const MAX_CHAIN_LENGTH: usize = 10_000;
+
+/// Number of blocks requested from a peer at once
+pub const SPAN_LENGTH: usize = 20;
+
+/// Maximum number of spans being downloaded or waiting to be added to the chain
+const MAX_QUEUED_SPANS: usize = 50;
+
+/// Time after which a request that hasn't been answered is given to another peer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time a peer that stalled is skipped for before being asked again
+const STALL_DURATION: Duration = Duration::from_secs(60);
+
+/// Checks if a peer stalled recently enough to be skipped
+fn is_stalled(stalled_peers: &HashMap<PeerId, Instant>, peer_id: &PeerId) -> bool {
+    stalled_peers
+        .get(peer_id)
+        .map_or(false, |stalled_at| stalled_at.elapsed() < STALL_DURATION)
+}
+
+/// A span of blocks requested from a peer
+struct SpanRequest {
+    peer_id: PeerId,
+    requested_at: Instant,
+}
+
+/// Keeps track of which blocks to download from which peers
+#[derive(Default)]
+pub struct SyncManager {
+    peer_heights: HashMap<PeerId, u64>,
+    // Peers that didn't answer a request in time, along with when they stalled. They're skipped
+    // for a while, or until the next chain
+    stalled_peers: HashMap<PeerId, Instant>,
+    // Peers whose pending request was dropped. Their answer is ignored
+    late_peers: HashSet<PeerId>,
+
+    chain_request: Option<(PeerId, Instant)>,
+    chain_start: u64,
+    chain: Vec<Hash256>,
+
+    // Spans are identified by the index of their first block in the chain
+    next_span: usize,
+    next_ready: usize,
+    retries: BTreeSet<usize>,
+    requests: BTreeMap<usize, SpanRequest>,
+    downloaded: BTreeMap<usize, (PeerId, Vec<BlockEntry>)>,
+}
+
+impl SyncManager {
+    /// Adds a peer that completed the handshake, along with the height of its chain
+    pub fn add_peer(&mut self, peer_id: PeerId, height: u64) {
+        self.peer_heights.insert(peer_id, height);
+    }
+
+    /// Updates the chain height of a peer if it's higher than the one known
+    pub fn update_peer_height(&mut self, peer_id: &PeerId, height: u64) {
+        if let Some(current) = self.peer_heights.get_mut(peer_id) {
+            *current = (*current).max(height);
+        }
+    }
+
+    /// Removes a disconnected peer. Its pending requests are given to other peers
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.peer_heights.remove(peer_id);
+        self.stalled_peers.remove(peer_id);
+        self.late_peers.remove(peer_id);
+
+        if matches!(&self.chain_request, Some((requested_from, _)) if requested_from == peer_id) {
+            self.chain_request = None;
+        }
+
+        let dropped_spans = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.peer_id == *peer_id)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in dropped_spans {
+            self.requests.remove(&start);
+            self.retries.insert(start);
+        }
+    }
+
+    /// Height of the highest chain known among our peers
+    pub fn target_height(&self) -> u64 {
+        let chain_height = (self.chain_start + self.chain.len() as u64).saturating_sub(1);
+
+        self.peer_heights
+            .values()
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(chain_height)
+    }
+
+    /// Drops the current chain, such as when one of its blocks turns out to be invalid
+    ///
+    /// A new chain is requested afterwards
+    pub fn reset(&mut self) {
+        for request in self.requests.values() {
+            self.late_peers.insert(request.peer_id.clone());
+        }
+
+        self.chain.clear();
+        self.next_span = 0;
+        self.next_ready = 0;
+        self.retries.clear();
+        self.requests.clear();
+        self.downloaded.clear();
+    }
+
+    /// Picks a peer to request the chain of block IDs following our chain tail from
+    ///
+    /// # Returns
+    /// The peer to request the chain from. Nothing if a chain is being downloaded already or
+    /// no peer is ahead of us
+    pub fn request_chain(&mut self, current_height: u64) -> Option<PeerId> {
+        if let Some((peer_id, requested_at)) = &self.chain_request {
+            if requested_at.elapsed() < REQUEST_TIMEOUT {
+                return None;
+            }
+
+            self.stalled_peers.insert(peer_id.clone(), Instant::now());
+            self.late_peers.insert(peer_id.clone());
+            self.chain_request = None;
+        }
+
+        if !self.chain.is_empty() {
+            return None;
+        }
+
+        let stalled_peers = &self.stalled_peers;
+        let (peer_id, _) = self
+            .peer_heights
+            .iter()
+            .filter(|(peer_id, height)| {
+                **height > current_height && !is_stalled(stalled_peers, peer_id)
+            })
+            .max_by_key(|(_, height)| **height)?;
+
+        self.chain_request = Some((peer_id.clone(), Instant::now()));
+
+        Some(peer_id.clone())
+    }
+
+    /// Handles a chain of block IDs received from a peer
+    ///
+    /// `start_height` is the height of the first block in the chain. Blocks we already have
+    /// are expected to be left out
+    ///
+    /// # Errors
+    /// If the chain wasn't requested from the peer
+    pub fn on_chain(
+        &mut self,
+        peer_id: &PeerId,
+        start_height: u64,
+        chain: Vec<Hash256>,
+    ) -> Result<(), Misbehavior> {
+        match &self.chain_request {
+            Some((requested_from, _)) if requested_from == peer_id => self.chain_request = None,
+            _ if self.late_peers.remove(peer_id) => return Ok(()),
+            _ => return Err(Misbehavior::UnsolicitedData),
+        }
+
+        if chain.len() > MAX_CHAIN_LENGTH {
+            return Err(Misbehavior::UnsolicitedData);
+        }
+
+        // The peer's chain doesn't go as high as we thought
+        if chain.is_empty() {
+            self.peer_heights
+                .insert(peer_id.clone(), start_height.saturating_sub(1));
+            return Ok(());
+        }
+
+        self.reset();
+        self.stalled_peers.clear();
+        self.chain_start = start_height;
+        self.chain = chain;
+
+        let chain_height = self.chain_start + self.chain.len() as u64 - 1;
+        self.update_peer_height(peer_id, chain_height);
+
+        Ok(())
+    }
+
+    /// Assigns spans of the chain to peers that aren't busy, retrying spans that stalled
+    ///
+    /// # Returns
+    /// The peers to request blocks from, along with the IDs of the blocks to request
+    pub fn request_spans(&mut self) -> Vec<(PeerId, Vec<Hash256>)> {
+        // Give up on requests that took too long
+        let stalled_spans = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.requested_at.elapsed() >= REQUEST_TIMEOUT)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in stalled_spans {
+            if let Some(request) = self.requests.remove(&start) {
+                self.stalled_peers
+                    .insert(request.peer_id.clone(), Instant::now());
+                self.late_peers.insert(request.peer_id);
+            }
+            self.retries.insert(start);
+        }
+
+        let busy_peers = self
+            .requests
+            .values()
+            .map(|request| &request.peer_id)
+            .chain(self.chain_request.iter().map(|(peer_id, _)| peer_id))
+            .collect::<HashSet<_>>();
+        let mut idle_peers = self
+            .peer_heights
+            .iter()
+            .filter(|(peer_id, _)| {
+                !busy_peers.contains(peer_id) && !is_stalled(&self.stalled_peers, peer_id)
+            })
+            .map(|(peer_id, height)| (peer_id.clone(), *height))
+            .collect::<Vec<_>>();
+
+        let mut span_requests = Vec::new();
+        while self.requests.len() + self.downloaded.len() < MAX_QUEUED_SPANS {
+            // Retry earlier spans first since the ones after them can't be added without them
+            let start = match self.retries.iter().next() {
+                Some(start) => *start,
+                None if self.next_span < self.chain.len() => self.next_span,
+                None => break,
+            };
+            let end = (start + SPAN_LENGTH).min(self.chain.len());
+            let end_height = self.chain_start + end as u64 - 1;
+
+            let peer_id = match idle_peers
+                .iter()
+                .position(|(_, height)| *height >= end_height)
+            {
+                Some(index) => idle_peers.swap_remove(index).0,
+                None => break,
+            };
+
+            if !self.retries.remove(&start) {
+                self.next_span = end;
+            }
+            self.requests.insert(
+                start,
+                SpanRequest {
+                    peer_id: peer_id.clone(),
+                    requested_at: Instant::now(),
+                },
+            );
+            span_requests.push((peer_id, self.chain[start..end].to_vec()));
+        }
+
+        span_requests
+    }
+
+    /// Handles a span of blocks received from a peer
+    ///
+    /// # Errors
+    /// If the span wasn't requested from the peer or doesn't contain the blocks requested
+    pub fn on_span(&mut self, peer_id: &PeerId, span: Vec<BlockEntry>) -> Result<(), Misbehavior> {
+        let start = self
+            .requests
+            .iter()
+            .find(|(_, request)| request.peer_id == *peer_id)
+            .map(|(start, _)| *start);
+        let start = match start {
+            Some(start) => start,
+            None if self.late_peers.remove(peer_id) => return Ok(()),
+            None => return Err(Misbehavior::UnsolicitedData),
+        };
+        self.requests.remove(&start);
+
+        // The peer doesn't have the blocks anymore
+        if span.is_empty() {
+            self.stalled_peers.insert(peer_id.clone(), Instant::now());
+            self.retries.insert(start);
+            return Ok(());
+        }
+
+        let end = (start + SPAN_LENGTH).min(self.chain.len());
+        let is_requested_span = span.len() <= end - start
+            && span
+                .iter()
+                .zip(&self.chain[start..end])
+                .all(|(entry, block_id)| entry.block.get_hash() == *block_id);
+        if !is_requested_span {
+            self.retries.insert(start);
+            return Err(Misbehavior::UnsolicitedData);
+        }
+
+        // The peer couldn't fit the whole span in a message
+        if span.len() < end - start {
+            self.stalled_peers.insert(peer_id.clone(), Instant::now());
+            self.retries.insert(start);
+            return Ok(());
+        }
+
+        self.downloaded.insert(start, (peer_id.clone(), span));
+
+        Ok(())
+    }
+
+    /// Takes the spans that can be added to the chain, in height order
+    ///
+    /// # Returns
+    /// The spans along with the peers they were received from
+    pub fn take_ready_spans(&mut self) -> Vec<(PeerId, Vec<BlockEntry>)> {
+        let mut ready_spans = Vec::new();
+
+        while let Some((peer_id, span)) = self.downloaded.remove(&self.next_ready) {
+            self.next_ready += span.len();
+            ready_spans.push((peer_id, span));
+        }
+
+        // The whole chain has been downloaded. The next one is requested if we're still behind
+        if !self.chain.is_empty() && self.next_ready >= self.chain.len() {
+            self.reset();
+        }
+
+        ready_spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{Block, TXIn};
+
+    use super::*;
+
+    fn chain(length: u64) -> Vec<BlockEntry> {
+        (1..=length)
+            .map(|height| {
+                let mut block = Block::genesis();
+                block.miner_tx.prefix.inputs = vec![TXIn::Gen(height)];
+
+                BlockEntry {
+                    block,
+                    transactions: Vec::new(),
+                }
+            })
+            .collect()
+    }
+
+    fn block_ids(entries: &[BlockEntry]) -> Vec<Hash256> {
+        entries.iter().map(|entry| entry.block.get_hash()).collect()
+    }
+
+    fn span_of(entries: &[BlockEntry], block_ids: &[Hash256]) -> Vec<BlockEntry> {
+        entries
+            .iter()
+            .filter(|entry| block_ids.contains(&entry.block.get_hash()))
+            .cloned()
+            .collect()
+    }
+
+    fn start_syncing(sync: &mut SyncManager, peers: &[PeerId], entries: &[BlockEntry]) {
+        for peer_id in peers {
+            sync.add_peer(peer_id.clone(), entries.len() as u64);
+        }
+
+        let chain_peer = sync.request_chain(0).unwrap();
+        sync.on_chain(&chain_peer, 1, block_ids(entries)).unwrap();
+    }
+
+    #[test]
+    fn downloads_spans_in_parallel_and_in_order() {
+        let mut sync = SyncManager::default();
+        let peers = (0..3).map(|_| PeerId::random()).collect::<Vec<_>>();
+        let entries = chain(50);
+
+        start_syncing(&mut sync, &peers, &entries);
+        assert_eq!(sync.target_height(), 50);
+
+        let requests = sync.request_spans();
+        assert_eq!(requests.len(), 3);
+
+        // Answer in reverse order. Nothing is ready until the first span arrives
+        for (peer_id, block_ids) in requests.iter().rev() {
+            assert!(sync.take_ready_spans().is_empty());
+            sync.on_span(peer_id, span_of(&entries, block_ids)).unwrap();
+        }
+
+        let ready = sync
+            .take_ready_spans()
+            .into_iter()
+            .flat_map(|(_, span)| span)
+            .collect::<Vec<_>>();
+        assert_eq!(block_ids(&ready), block_ids(&entries));
+        assert!(sync.request_spans().is_empty());
+    }
+
+    #[test]
+    fn retries_stalled_spans_elsewhere() {
+        let mut sync = SyncManager::default();
+        let peers = (0..2).map(|_| PeerId::random()).collect::<Vec<_>>();
+        let entries = chain(SPAN_LENGTH as u64);
+
+        start_syncing(&mut sync, &peers, &entries);
+
+        let requests = sync.request_spans();
+        assert_eq!(requests.len(), 1);
+        let (stalled_peer, block_ids) = &requests[0];
+
+        sync.requests.values_mut().next().unwrap().requested_at -= REQUEST_TIMEOUT;
+
+        let retries = sync.request_spans();
+        assert_eq!(retries.len(), 1);
+        assert_ne!(&retries[0].0, stalled_peer);
+        assert_eq!(&retries[0].1, block_ids);
+
+        // The late answer of the stalled peer is ignored
+        assert_eq!(sync.on_span(stalled_peer, entries.clone()), Ok(()));
+        assert!(sync.take_ready_spans().is_empty());
+    }
+
+    #[test]
+    fn asks_a_stalled_peer_again_later() {
+        let mut sync = SyncManager::default();
+        let peer_id = PeerId::random();
+        let entries = chain(SPAN_LENGTH as u64);
+
+        start_syncing(&mut sync, &[peer_id.clone()], &entries);
+        assert_eq!(sync.request_spans().len(), 1);
+
+        // The only peer times out, so nobody is left to ask for now
+        sync.requests.values_mut().next().unwrap().requested_at -= REQUEST_TIMEOUT;
+        assert!(sync.request_spans().is_empty());
+
+        *sync.stalled_peers.get_mut(&peer_id).unwrap() -= STALL_DURATION;
+        let retries = sync.request_spans();
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].0, peer_id);
+
+        sync.on_span(&peer_id, span_of(&entries, &retries[0].1))
+            .unwrap();
+        let ready = sync
+            .take_ready_spans()
+            .into_iter()
+            .flat_map(|(_, span)| span)
+            .collect::<Vec<_>>();
+        assert_eq!(block_ids(&ready), block_ids(&entries));
+    }
+
+    #[test]
+    fn retries_spans_of_disconnected_peers() {
+        let mut sync = SyncManager::default();
+        let peers = (0..2).map(|_| PeerId::random()).collect::<Vec<_>>();
+        let entries = chain(SPAN_LENGTH as u64);
+
+        start_syncing(&mut sync, &peers, &entries);
+
+        let (peer_id, _) = sync.request_spans().remove(0);
+        sync.remove_peer(&peer_id);
+
+        let retries = sync.request_spans();
+        assert_eq!(retries.len(), 1);
+        assert_ne!(retries[0].0, peer_id);
+    }
+
+    #[test]
+    fn rejects_unsolicited_data() {
+        let mut sync = SyncManager::default();
+        let peer_id = PeerId::random();
+        let entries = chain(SPAN_LENGTH as u64);
+
+        assert_eq!(
+            sync.on_chain(&peer_id, 1, block_ids(&entries)),
+            Err(Misbehavior::UnsolicitedData)
+        );
+        assert_eq!(
+            sync.on_span(&peer_id, entries.clone()),
+            Err(Misbehavior::UnsolicitedData)
+        );
+
+        start_syncing(&mut sync, &[peer_id.clone()], &entries);
+        sync.request_spans();
+
+        // Blocks that weren't requested
+        assert_eq!(
+            sync.on_span(&peer_id, chain(2 * SPAN_LENGTH as u64)),
+            Err(Misbehavior::UnsolicitedData)
+        );
+    }
+}
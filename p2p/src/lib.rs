@@ -4,26 +4,38 @@
 
 use std::{
     future::Future,
+    path::PathBuf,
     pin::Pin,
     sync::{Arc, RwLock},
 };
 
 use libp2p::{
     core::Executor,
-    identity::Keypair,
     multiaddr::{Multiaddr, Protocol},
     swarm::SwarmBuilder,
     PeerId, Swarm,
 };
-use log::info;
+use log::{info, warn};
 
 use cryptonote_core::{CryptonoteCore, EmissionCurve};
 
+mod ban_list;
+mod bandwidth;
 mod config;
 mod cryptonote_protocol;
+mod network_state;
+mod node_key;
+mod peer_list;
+mod seed_nodes;
+#[cfg(test)]
+mod simulation;
 
+pub use ban_list::{Ban, BanList};
+pub use bandwidth::{BandwidthStats, TrafficCounters};
 pub use config::Config;
-use cryptonote_protocol::CryptonoteNetworkBehavior;
+use cryptonote_protocol::{CryptonoteNetworkBehavior, CryptonoteP2PEvent};
+pub use network_state::NetworkState;
+use peer_list::PeerList;
 
 struct TokioExecutor;
 
@@ -33,19 +45,74 @@ impl Executor for TokioExecutor {
     }
 }
 
+/// Gets the directory P2P state is stored in, creating it if necessary
+fn get_data_dir(config: &Config) -> Result<PathBuf, anyhow::Error> {
+    let mut data_dir = config
+        .p2p_data_directory
+        .clone()
+        .unwrap_or_else(common::data_dir::get_default_data_dir);
+    data_dir.push("p2p");
+
+    std::fs::create_dir_all(&data_dir)?;
+
+    Ok(data_dir)
+}
+
 /// Initialize the P2P handler
 pub fn init<TCoin: 'static + EmissionCurve + Unpin + Send + Sync>(
     config: &Config,
     core: Arc<RwLock<CryptonoteCore<TCoin>>>,
+    network_state: Arc<RwLock<NetworkState>>,
 ) -> Result<impl Future, anyhow::Error> {
-    // Create a random PeerId
-    let local_key = Keypair::generate_ed25519();
+    let network = core.read().unwrap().network();
+
+    // Load the node's identity for the network we're on
+    let local_key = {
+        let mut path = get_data_dir(config)?;
+        path.push(format!("node_key_{}", network));
+        path.set_extension("dat");
+
+        node_key::load_or_generate(&path, config.regenerate_node_key)?
+    };
     let peer_id = PeerId::from(local_key.public());
+    info!("Local peer ID: {}", peer_id);
+
+    // Load the peer list for the network we're on
+    let peer_list = {
+        let mut path = get_data_dir(config)?;
+        path.push(format!("peer_list_{}", network));
+        path.set_extension("dat");
+
+        PeerList::load(&path)
+    };
+
+    // Nodes without seed nodes can only find peers through those given on the command line or
+    // a previously saved peer list
+    let seed_nodes = seed_nodes::for_network(network, &config.seed_node);
+    if seed_nodes.is_empty()
+        && config.connect_to.is_none()
+        && config.add_exclusive_node.is_empty()
+        && config.add_priority_node.is_empty()
+    {
+        warn!(
+            "No seed nodes are known for {}. Unless the saved peer list has reachable peers, \
+             the node stays isolated. Give peers with --seed-node, --add-priority-node or \
+             --connect-to",
+            network
+        );
+    }
 
     // Set up the swarm
     let mut swarm = {
         let transport = libp2p::build_development_transport(local_key)?;
-        let network_behavior = CryptonoteNetworkBehavior::new(peer_id.clone(), core);
+        let network_behavior = CryptonoteNetworkBehavior::new(
+            peer_id.clone(),
+            core,
+            network_state,
+            config,
+            peer_list,
+            seed_nodes,
+        );
         SwarmBuilder::new(transport, network_behavior, peer_id)
             .executor(Box::from(TokioExecutor))
             .build()
@@ -54,7 +121,7 @@ pub fn init<TCoin: 'static + EmissionCurve + Unpin + Send + Sync>(
     // Get which address to listen to
     let addr = {
         let mut m = Multiaddr::empty();
-        m.push(Protocol::Ip4("0.0.0.0".parse().unwrap()));
+        m.push(Protocol::from(config.p2p_bind_address));
         m.push(Protocol::Tcp(config.p2p_bind_port));
         m
     };
@@ -68,7 +135,14 @@ pub fn init<TCoin: 'static + EmissionCurve + Unpin + Send + Sync>(
     Ok(async move {
         loop {
             // Keep polling the swarm non-stop
-            swarm.next().await;
+            match swarm.next().await {
+                CryptonoteP2PEvent::DisconnectPeer(peer_id) => {
+                    // Banning a peer closes all its connections. The ban is lifted right away
+                    // so the peer isn't refused if it connects again later
+                    Swarm::ban_peer_id(&mut swarm, peer_id.clone());
+                    Swarm::unban_peer_id(&mut swarm, peer_id);
+                }
+            }
         }
     })
 }
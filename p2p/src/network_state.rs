@@ -0,0 +1,105 @@
+//! # Network state
+//!
+//! State of the P2P network that is shared with other components of the node (such as the
+//! RPC server)
+
+use common::{Network, Transaction};
+use tokio::sync::mpsc;
+
+use crate::{ban_list::BanList, bandwidth::BandwidthStats, config::Config};
+
+/// State of the P2P network shared between the P2P handler and other components
+pub struct NetworkState {
+    ban_list: BanList,
+    bandwidth: BandwidthStats,
+    incoming_connections: usize,
+    outgoing_connections: usize,
+    relay_receiver: Option<mpsc::UnboundedReceiver<Transaction>>,
+    relay_sender: mpsc::UnboundedSender<Transaction>,
+    target_height: u64,
+}
+
+impl NetworkState {
+    /// Loads the network state of the given network from the P2P data directory
+    pub fn new(config: &Config, network: Network) -> Result<Self, anyhow::Error> {
+        let ban_list = {
+            let mut path = crate::get_data_dir(config)?;
+            path.push(format!("ban_list_{}", network));
+            path.set_extension("dat");
+
+            let mut ban_list = BanList::load(&path);
+            if let Some(path) = &config.ban_list {
+                ban_list.block_from_file(path)?;
+            }
+
+            ban_list
+        };
+
+        let (relay_sender, relay_receiver) = mpsc::unbounded_channel();
+
+        Ok(Self {
+            ban_list,
+            bandwidth: BandwidthStats::default(),
+            incoming_connections: 0,
+            outgoing_connections: 0,
+            relay_receiver: Some(relay_receiver),
+            relay_sender,
+            target_height: 0,
+        })
+    }
+
+    /// Get a reference to the list of banned addresses
+    pub fn ban_list(&self) -> &BanList {
+        &self.ban_list
+    }
+
+    /// Get a mutable reference to the list of banned addresses
+    pub fn ban_list_mut(&mut self) -> &mut BanList {
+        &mut self.ban_list
+    }
+
+    /// Get the traffic counters of the node and its connected peers
+    pub fn bandwidth(&self) -> &BandwidthStats {
+        &self.bandwidth
+    }
+
+    pub(crate) fn bandwidth_mut(&mut self) -> &mut BandwidthStats {
+        &mut self.bandwidth
+    }
+
+    /// Number of peers that connected to this node
+    pub fn incoming_connections(&self) -> usize {
+        self.incoming_connections
+    }
+
+    /// Number of peers this node connected to
+    pub fn outgoing_connections(&self) -> usize {
+        self.outgoing_connections
+    }
+
+    pub(crate) fn set_connection_counts(&mut self, incoming: usize, outgoing: usize) {
+        self.incoming_connections = incoming;
+        self.outgoing_connections = outgoing;
+    }
+
+    /// Hands a transaction that was added to the transaction pool's stem pool over to the P2P
+    /// handler, which relays it to the network
+    pub fn relay_transaction(&self, transaction: Transaction) {
+        // Sending only fails once the P2P handler is gone, in which case there's nobody to relay to
+        let _ = self.relay_sender.send(transaction);
+    }
+
+    /// Takes the receiving end of the transactions to relay. Only one P2P handler can have it
+    pub(crate) fn take_relay_receiver(&mut self) -> Option<mpsc::UnboundedReceiver<Transaction>> {
+        self.relay_receiver.take()
+    }
+
+    /// Height of the highest chain known on the network, which is the target when syncing
+    pub fn target_height(&self) -> u64 {
+        self.target_height
+    }
+
+    pub(crate) fn set_target_height(&mut self, target_height: u64) {
+        self.target_height = target_height;
+    }
+}
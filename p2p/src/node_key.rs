@@ -0,0 +1,73 @@
+//! # Node key
+//!
+//! The node's libp2p identity key, which determines its `PeerId`. It is kept in the data
+//! directory so that the node keeps the same `PeerId` across restarts
+
+use std::{io::Write, path::Path};
+
+use libp2p::identity::{ed25519, Keypair};
+use log::{info, warn};
+
+/// Loads the node key stored at the given path, or generates a new one and stores it if it
+/// doesn't exist, can't be read or `regenerate` is set
+pub fn load_or_generate(path: &Path, regenerate: bool) -> Result<Keypair, anyhow::Error> {
+    if !regenerate {
+        match load(path) {
+            Ok(keypair) => {
+                info!("Loaded node key from {}", path.display());
+                return Ok(Keypair::Ed25519(keypair));
+            }
+            Err(error) if path.exists() => {
+                warn!("Could not load node key ({}). Generating a new one", error)
+            }
+            Err(_) => info!("Generating a new node key"),
+        }
+    }
+
+    let keypair = ed25519::Keypair::generate();
+    save(path, &keypair)?;
+    info!("Saved node key to {}", path.display());
+
+    Ok(Keypair::Ed25519(keypair))
+}
+
+fn load(path: &Path) -> Result<ed25519::Keypair, anyhow::Error> {
+    let mut encoded = std::fs::read(path)?;
+
+    Ok(ed25519::Keypair::decode(&mut encoded)?)
+}
+
+fn save(path: &Path, keypair: &ed25519::Keypair) -> Result<(), anyhow::Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    // The key is secret, so only the owner may read it
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(&keypair.encode())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::*;
+
+    #[test]
+    fn keeps_the_key_unless_regenerated() {
+        let path =
+            std::env::temp_dir().join(format!("node_key_test_{}.dat", rand::random::<u64>()));
+
+        let peer_id = PeerId::from(load_or_generate(&path, false).unwrap().public());
+        let loaded = PeerId::from(load_or_generate(&path, false).unwrap().public());
+        let regenerated = PeerId::from(load_or_generate(&path, true).unwrap().public());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(peer_id, loaded);
+        assert_ne!(peer_id, regenerated);
+    }
+}
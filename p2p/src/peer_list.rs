@@ -0,0 +1,412 @@
+//! # Peer list
+//!
+//! Keeps track of the addresses of peers on the network. Peers we have connected to are kept
+//! in the white list while addresses we've only heard about from other peers are kept in the
+//! grey list until we manage to connect to them
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use log::{debug, info, warn};
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of entries in the white list
+const MAX_WHITE_LIST_SIZE: usize = 1000;
+
+/// Maximum number of entries in the grey list
+const MAX_GREY_LIST_SIZE: usize = 5000;
+
+/// Maximum number of grey list entries shared by other peers in the same subnet
+///
+/// Subnets are /24 for IPv4 and /48 for IPv6. This stops a single peer from filling the grey
+/// list with addresses it controls
+const MAX_SHARED_PEERS_PER_SUBNET: usize = 2;
+
+/// Address of a peer along with the last time it was seen
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerListEntry {
+    /// Address the peer can be reached at
+    pub address: Multiaddr,
+    /// UNIX timestamp of when the peer was last seen
+    pub last_seen: u64,
+}
+
+/// Gets the current UNIX timestamp
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Gets the IP address of a peer address of the form `/ip4/<ip>/tcp/<port>` or
+/// `/ip6/<ip>/tcp/<port>`
+fn tcp_ip_address(address: &Multiaddr) -> Option<IpAddr> {
+    let mut protocols = address.iter();
+
+    let ip = match protocols.next()? {
+        Protocol::Ip4(ip) => IpAddr::V4(ip),
+        Protocol::Ip6(ip) => IpAddr::V6(ip),
+        _ => return None,
+    };
+    match (protocols.next()?, protocols.next()) {
+        (Protocol::Tcp(port), None) if port != 0 => Some(ip),
+        _ => None,
+    }
+}
+
+/// Checks if an IP address can be reached over the internet
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network" (0.0.0.0/8)
+                || ip.octets()[0] == 0
+                // Shared address space used for carrier-grade NAT (100.64.0.0/10)
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            // IPv4-mapped and IPv4-compatible addresses, which also covers :: and ::1
+            if let Some(ip) = ip.to_ipv4() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+
+            let first_segment = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local (fc00::/7) and link local (fe80::/10) addresses
+                || first_segment & 0xfe00 == 0xfc00
+                || first_segment & 0xffc0 == 0xfe80
+                // Documentation addresses (2001:db8::/32)
+                || (first_segment == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+/// Gets the subnet an IP address belongs to, for limiting the entries of a single subnet
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                0,
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+    }
+}
+
+/// White and grey lists of peer addresses, mapped to when they were last seen
+#[derive(Default, Serialize, Deserialize)]
+pub struct PeerList {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    changed: bool,
+
+    white: HashMap<Multiaddr, u64>,
+    grey: HashMap<Multiaddr, u64>,
+}
+
+impl PeerList {
+    /// Loads the peer list stored at the given path, or creates an empty one if it doesn't exist
+    pub fn load(path: &Path) -> Self {
+        let peer_list = File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| bincode::deserialize_from(file).map_err(anyhow::Error::from));
+
+        let mut peer_list = match peer_list {
+            Ok(peer_list) => {
+                info!("Loaded peer list from {}", path.display());
+                peer_list
+            }
+            Err(error) => {
+                warn!(
+                    "Could not load peer list ({}). Starting with an empty one",
+                    error
+                );
+                PeerList::default()
+            }
+        };
+        peer_list.path = Some(path.to_path_buf());
+
+        peer_list
+    }
+
+    /// Saves the peer list to disk if it changed since the last save
+    pub fn sync(&mut self) -> Result<(), anyhow::Error> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.path {
+            let file = File::create(path)?;
+            bincode::serialize_into(file, &self)?;
+            debug!("Saved peer list");
+        }
+        self.changed = false;
+
+        Ok(())
+    }
+
+    /// Adds a peer we've successfully connected to, removing it from the grey list
+    pub fn add_white(&mut self, address: Multiaddr) {
+        self.grey.remove(&address);
+        self.white.insert(address, unix_timestamp());
+        Self::evict_oldest(&mut self.white, MAX_WHITE_LIST_SIZE);
+        self.changed = true;
+    }
+
+    /// Adds a peer we've heard about but haven't connected to yet
+    ///
+    /// Addresses that are in the white list already are ignored
+    pub fn add_grey(&mut self, entry: PeerListEntry) {
+        if self.white.contains_key(&entry.address) {
+            return;
+        }
+
+        // Don't trust timestamps from the future
+        let last_seen = entry.last_seen.min(unix_timestamp());
+        let current = self.grey.entry(entry.address).or_insert(last_seen);
+        *current = (*current).max(last_seen);
+
+        Self::evict_oldest(&mut self.grey, MAX_GREY_LIST_SIZE);
+        self.changed = true;
+    }
+
+    /// Adds an address shared by another peer to the grey list
+    ///
+    /// Shared addresses can't be trusted, so addresses that aren't publicly routable or that are
+    /// in a subnet that already has enough entries are ignored. Known addresses keep their last
+    /// seen time, so peers can't keep their entries from being evicted by claiming to have just
+    /// seen them
+    ///
+    /// # Returns
+    /// `true` if the address was added
+    pub fn add_shared(&mut self, entry: PeerListEntry) -> bool {
+        let ip = match tcp_ip_address(&entry.address) {
+            Some(ip) if is_public_ip(ip) => ip,
+            _ => return false,
+        };
+        if self.white.contains_key(&entry.address) || self.grey.contains_key(&entry.address) {
+            return false;
+        }
+
+        let subnet = subnet(ip);
+        let subnet_entries = self
+            .grey
+            .keys()
+            .filter_map(tcp_ip_address)
+            .filter(|ip| self::subnet(*ip) == subnet)
+            .count();
+        if subnet_entries >= MAX_SHARED_PEERS_PER_SUBNET {
+            return false;
+        }
+
+        self.add_grey(entry);
+        true
+    }
+
+    /// Handles a failed connection attempt to the given address
+    ///
+    /// White peers are moved to the grey list while grey peers are removed entirely
+    pub fn mark_failed(&mut self, address: &Multiaddr) {
+        if let Some(last_seen) = self.white.remove(address) {
+            self.grey.insert(address.clone(), last_seen);
+            self.changed = true;
+        } else if self.grey.remove(address).is_some() {
+            self.changed = true;
+        }
+    }
+
+    /// Removes an address from both lists
+    pub fn remove(&mut self, address: &Multiaddr) {
+        if self.white.remove(address).is_some() || self.grey.remove(address).is_some() {
+            self.changed = true;
+        }
+    }
+
+    /// Gets a random selection of white peers, to share with other peers
+    pub fn random_white_peers(&self, count: usize) -> Vec<PeerListEntry> {
+        self.white
+            .iter()
+            .choose_multiple(&mut rand::thread_rng(), count)
+            .into_iter()
+            .map(|(address, last_seen)| PeerListEntry {
+                address: address.clone(),
+                last_seen: *last_seen,
+            })
+            .collect()
+    }
+
+    /// Picks a random address to connect to, preferring white peers over grey ones
+    pub fn pick_peer_to_dial(&self, exclude: impl Fn(&Multiaddr) -> bool) -> Option<Multiaddr> {
+        let mut rng = rand::thread_rng();
+
+        self.white
+            .keys()
+            .filter(|address| !exclude(*address))
+            .choose(&mut rng)
+            .or_else(|| {
+                self.grey
+                    .keys()
+                    .filter(|address| !exclude(*address))
+                    .choose(&mut rng)
+            })
+            .cloned()
+    }
+
+    /// Checks if we don't know about any peers
+    pub fn is_empty(&self) -> bool {
+        self.white.is_empty() && self.grey.is_empty()
+    }
+
+    fn evict_oldest(list: &mut HashMap<Multiaddr, u64>, max_size: usize) {
+        while list.len() > max_size {
+            let oldest = list
+                .iter()
+                .min_by_key(|(_, last_seen)| **last_seen)
+                .map(|(address, _)| address.clone());
+
+            if let Some(oldest) = oldest {
+                list.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl Drop for PeerList {
+    fn drop(&mut self) {
+        if let Err(error) = self.sync() {
+            warn!("Failed to save peer list: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn grey_peers_are_promoted_and_demoted() {
+        let mut peer_list = PeerList::default();
+
+        peer_list.add_grey(PeerListEntry {
+            address: address(1),
+            last_seen: 0,
+        });
+        assert!(peer_list.grey.contains_key(&address(1)));
+
+        peer_list.add_white(address(1));
+        assert!(peer_list.white.contains_key(&address(1)));
+        assert!(!peer_list.grey.contains_key(&address(1)));
+
+        // White peers move back to the grey list on failure, then get removed
+        peer_list.mark_failed(&address(1));
+        assert!(peer_list.grey.contains_key(&address(1)));
+        peer_list.mark_failed(&address(1));
+        assert!(peer_list.is_empty());
+    }
+
+    #[test]
+    fn grey_list_does_not_override_white_list() {
+        let mut peer_list = PeerList::default();
+
+        peer_list.add_white(address(1));
+        peer_list.add_grey(PeerListEntry {
+            address: address(1),
+            last_seen: 0,
+        });
+
+        assert!(peer_list.grey.is_empty());
+    }
+
+    #[test]
+    fn evicts_oldest_entries() {
+        let mut list: HashMap<_, _> = (0..10).map(|i| (address(i), u64::from(i))).collect();
+
+        PeerList::evict_oldest(&mut list, 5);
+
+        assert_eq!(list.len(), 5);
+        assert!((5..10).all(|i| list.contains_key(&address(i))));
+    }
+
+    #[test]
+    fn filters_shared_peers() {
+        let shared = |address: &str| PeerListEntry {
+            address: address.parse().unwrap(),
+            last_seen: u64::max_value(),
+        };
+        let mut peer_list = PeerList::default();
+
+        // Unroutable addresses
+        assert!(!peer_list.add_shared(shared("/ip4/127.0.0.1/tcp/21149")));
+        assert!(!peer_list.add_shared(shared("/ip4/192.168.1.2/tcp/21149")));
+        assert!(!peer_list.add_shared(shared("/ip4/100.64.0.1/tcp/21149")));
+        assert!(!peer_list.add_shared(shared("/ip6/::1/tcp/21149")));
+        assert!(!peer_list.add_shared(shared("/ip6/fd00::1/tcp/21149")));
+        assert!(!peer_list.add_shared(shared("/ip6/::ffff:10.0.0.1/tcp/21149")));
+        assert!(!peer_list.add_shared(shared("/ip4/1.2.3.4/tcp/0")));
+        assert!(!peer_list.add_shared(shared("/dns4/example.com/tcp/21149")));
+        assert!(peer_list.is_empty());
+
+        // Subnet limits
+        assert!(peer_list.add_shared(shared("/ip4/1.2.3.4/tcp/21149")));
+        assert!(peer_list.add_shared(shared("/ip4/1.2.3.5/tcp/21149")));
+        assert!(!peer_list.add_shared(shared("/ip4/1.2.3.6/tcp/21149")));
+        assert!(peer_list.add_shared(shared("/ip4/1.2.4.6/tcp/21149")));
+        assert!(peer_list.add_shared(shared("/ip6/2a01:4f8::1/tcp/21149")));
+
+        // Timestamps from the future are capped, and known entries aren't refreshed
+        let address = "/ip4/1.2.3.4/tcp/21149".parse().unwrap();
+        assert!(peer_list.grey[&address] <= unix_timestamp());
+        peer_list.grey.insert(address.clone(), 0);
+        assert!(!peer_list.add_shared(shared("/ip4/1.2.3.4/tcp/21149")));
+        assert_eq!(peer_list.grey[&address], 0);
+    }
+
+    #[test]
+    fn prefers_white_peers() {
+        let mut peer_list = PeerList::default();
+
+        peer_list.add_grey(PeerListEntry {
+            address: address(1),
+            last_seen: 0,
+        });
+        peer_list.add_white(address(2));
+
+        assert_eq!(peer_list.pick_peer_to_dial(|_| false), Some(address(2)));
+        assert_eq!(
+            peer_list.pick_peer_to_dial(|excluded| *excluded == address(2)),
+            Some(address(1))
+        );
+    }
+}
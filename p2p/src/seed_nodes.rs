@@ -0,0 +1,45 @@
+//! Hardcoded seed nodes used to bootstrap the peer list
+//!
+//! Only nodes run by the project belong here, on the default P2P port. None are published yet,
+//! so nodes bootstrap from the peers given with `--seed-node`, `--add-priority-node` or
+//! `--connect-to`
+
+use libp2p::Multiaddr;
+
+use common::Network;
+
+const MAINNET_SEED_NODES: &[&str] = &[];
+
+const TESTNET_SEED_NODES: &[&str] = &[];
+
+const STAGENET_SEED_NODES: &[&str] = &[];
+
+/// Gets the seed nodes for the given network, followed by the extra ones given
+pub fn for_network(network: Network, extra_seed_nodes: &[Multiaddr]) -> Vec<Multiaddr> {
+    let seed_nodes = match network {
+        Network::Mainnet => MAINNET_SEED_NODES,
+        Network::Testnet => TESTNET_SEED_NODES,
+        Network::Stagenet => STAGENET_SEED_NODES,
+    };
+
+    seed_nodes
+        .iter()
+        .map(|address| address.parse().expect("Invalid seed node address"))
+        .chain(extra_seed_nodes.iter().cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_nodes_are_valid() {
+        let extra_seed_node: Multiaddr = "/ip4/1.2.3.4/tcp/21149".parse().unwrap();
+
+        for network in &[Network::Mainnet, Network::Testnet, Network::Stagenet] {
+            let seed_nodes = for_network(*network, &[extra_seed_node.clone()]);
+            assert_eq!(seed_nodes.last(), Some(&extra_seed_node));
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! # Network simulation
+//!
+//! Runs several nodes in a single process, connected over libp2p's in-memory transport. Each
+//! node has its own core with a blockchain that is never stored on disk. Tests mine blocks on
+//! individual nodes, partition and heal the network, and wait for the nodes to converge
+
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    task::Poll,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{MemoryTransport, Transport},
+        upgrade::Version,
+    },
+    identity::Keypair,
+    mplex::MplexConfig,
+    secio::SecioConfig,
+    swarm::SwarmBuilder,
+    Multiaddr, PeerId, Swarm,
+};
+use structopt::StructOpt;
+
+use common::{Block, GetHash, TXIn};
+use crypto::Hash256;
+use cryptonote_core::{CryptonoteCore, EmissionCurve};
+
+use crate::{
+    cryptonote_protocol::{CryptonoteNetworkBehavior, CryptonoteP2PEvent},
+    peer_list::PeerList,
+    Config, NetworkState, TokioExecutor,
+};
+
+/// Time given to the nodes to converge before a test fails
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Coin paying the same reward as the genesis block
+struct SimulatedCoin;
+
+impl EmissionCurve for SimulatedCoin {
+    fn get_block_reward(&self, _version: u8) -> u64 {
+        Block::genesis().miner_tx.prefix.outputs[0].amount
+    }
+}
+
+type SimulatedCore = Arc<RwLock<CryptonoteCore<SimulatedCoin>>>;
+
+struct SimulatedNode {
+    address: Multiaddr,
+    core: SimulatedCore,
+    peer_id: PeerId,
+    swarm: Swarm<CryptonoteNetworkBehavior<SimulatedCoin>>,
+}
+
+/// A network of nodes running in the same process
+struct SimulatedNetwork {
+    data_directory: PathBuf,
+    nodes: Vec<SimulatedNode>,
+    /// Pairs of nodes that can't reach each other
+    partitioned: HashSet<(usize, usize)>,
+    timestamp: u64,
+}
+
+impl SimulatedNetwork {
+    /// Starts a network of the given number of nodes, all connected to each other
+    fn new(node_count: usize) -> Self {
+        let data_directory =
+            std::env::temp_dir().join(format!("unprll_simulation_{}", rand::random::<u64>()));
+        let addresses = (0..node_count)
+            .map(|_| format!("/memory/{}", rand::random::<u64>()))
+            .collect::<Vec<_>>();
+
+        let nodes = (0..node_count)
+            .map(|i| {
+                let node_directory = data_directory.join(i.to_string());
+                let mut args = vec![
+                    "simulation".to_string(),
+                    "--p2p-data-directory".to_string(),
+                    node_directory.display().to_string(),
+                ];
+                for (_, address) in addresses.iter().enumerate().filter(|(j, _)| *j != i) {
+                    args.push("--add-exclusive-node".to_string());
+                    args.push(address.clone());
+                }
+
+                Self::start_node(addresses[i].parse().unwrap(), &Config::from_iter(args))
+            })
+            .collect();
+
+        Self {
+            data_directory,
+            nodes,
+            partitioned: HashSet::new(),
+            timestamp: 0,
+        }
+    }
+
+    fn start_node(address: Multiaddr, config: &Config) -> SimulatedNode {
+        let core_config =
+            cryptonote_core::Config::from_iter(vec!["simulation", "--db-type", "ephemeral"]);
+        let core = Arc::new(RwLock::new(
+            CryptonoteCore::new(SimulatedCoin, &core_config).unwrap(),
+        ));
+        let network = core.read().unwrap().network();
+        let network_state = Arc::new(RwLock::new(NetworkState::new(config, network).unwrap()));
+
+        let local_key = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(local_key.public());
+        let transport = MemoryTransport::default()
+            .upgrade(Version::V1)
+            .authenticate(SecioConfig::new(local_key))
+            .multiplex(MplexConfig::new())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+
+        let network_behavior = CryptonoteNetworkBehavior::new(
+            peer_id.clone(),
+            core.clone(),
+            network_state,
+            config,
+            PeerList::default(),
+            Vec::new(),
+        );
+        let mut swarm = SwarmBuilder::new(transport, network_behavior, peer_id.clone())
+            .executor(Box::from(TokioExecutor))
+            .build();
+        Swarm::listen_on(&mut swarm, address.clone()).unwrap();
+
+        SimulatedNode {
+            address,
+            core,
+            peer_id,
+            swarm,
+        }
+    }
+
+    /// Mines a block on top of the given node's main chain
+    fn mine_block(&mut self, node: usize) -> Hash256 {
+        let mut core = self.nodes[node].core.write().unwrap();
+        let blockchain = core.blockchain_mut();
+        let (height, tail) = blockchain.get_tail().unwrap();
+
+        // Blocks mined by different nodes at the same height must differ
+        self.timestamp += 1;
+        let mut block = Block::genesis();
+        block.header.prev_id = tail.get_hash();
+        block.header.timestamp = self.timestamp;
+        block.miner_tx.prefix.inputs = vec![TXIn::Gen(height + 1)];
+
+        let block_id = block.get_hash();
+        blockchain.add_new_block(block).unwrap();
+
+        block_id
+    }
+
+    /// Gets the ID of the tail of a node's main chain
+    fn tail(&self, node: usize) -> Hash256 {
+        let core = self.nodes[node].core.read().unwrap();
+
+        core.blockchain().get_tail().unwrap().1.get_hash()
+    }
+
+    /// Splits the network in two groups, which can only reach nodes within their group
+    fn partition(&mut self, group: &[usize]) {
+        for i in 0..self.nodes.len() {
+            for j in 0..self.nodes.len() {
+                if group.contains(&i) != group.contains(&j) {
+                    self.partitioned.insert((i, j));
+                    let peer_id = self.nodes[j].peer_id.clone();
+                    Swarm::ban_peer_id(&mut self.nodes[i].swarm, peer_id);
+                }
+            }
+        }
+    }
+
+    /// Lets all nodes reach each other again, and reconnects the nodes that were partitioned
+    fn heal(&mut self) {
+        for (i, j) in self.partitioned.drain().collect::<Vec<_>>() {
+            let peer_id = self.nodes[j].peer_id.clone();
+            let address = self.nodes[j].address.clone();
+            Swarm::unban_peer_id(&mut self.nodes[i].swarm, peer_id);
+            Swarm::dial_addr(&mut self.nodes[i].swarm, address).unwrap();
+        }
+    }
+
+    /// Runs the network until the condition holds
+    ///
+    /// # Returns
+    /// Whether the condition held before `CONVERGENCE_TIMEOUT`
+    async fn run_until<F>(&mut self, condition: F) -> bool
+    where
+        F: Fn(&Self) -> bool,
+    {
+        let run = futures::future::poll_fn(|context| {
+            for i in 0..self.nodes.len() {
+                while let Poll::Ready(Some(event)) = self.nodes[i].swarm.poll_next_unpin(context) {
+                    match event {
+                        CryptonoteP2PEvent::DisconnectPeer(peer_id) => {
+                            Swarm::ban_peer_id(&mut self.nodes[i].swarm, peer_id.clone());
+
+                            // Keep the bans separating partitions
+                            let is_partitioned = self
+                                .partitioned
+                                .iter()
+                                .any(|(from, to)| *from == i && self.nodes[*to].peer_id == peer_id);
+                            if !is_partitioned {
+                                Swarm::unban_peer_id(&mut self.nodes[i].swarm, peer_id);
+                            }
+                        }
+                    }
+                }
+            }
+
+            if condition(self) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+
+        tokio::time::timeout(CONVERGENCE_TIMEOUT, run).await.is_ok()
+    }
+
+    /// Runs the network until the given nodes have the same main chain tail
+    async fn converge(&mut self, nodes: &[usize]) -> bool {
+        let nodes = nodes.to_vec();
+
+        self.run_until(|network| {
+            let tail = network.tail(nodes[0]);
+            nodes.iter().all(|node| network.tail(*node) == tail)
+        })
+        .await
+    }
+
+    /// Runs the network until all nodes are connected to the given number of peers
+    async fn connect(&mut self, peer_count: usize) -> bool {
+        self.run_until(|network| {
+            network
+                .nodes
+                .iter()
+                .all(|node| Swarm::network_info(&node.swarm).num_peers >= peer_count)
+        })
+        .await
+    }
+}
+
+impl Drop for SimulatedNetwork {
+    fn drop(&mut self) {
+        // The nodes store their ban lists on drop
+        self.nodes.clear();
+        let _ = std::fs::remove_dir_all(&self.data_directory);
+    }
+}
+
+#[tokio::test]
+async fn blocks_propagate_to_all_nodes() {
+    let mut network = SimulatedNetwork::new(3);
+    assert!(network.connect(2).await);
+
+    for _ in 0..3 {
+        let block_id = network.mine_block(0);
+        assert!(
+            network
+                .run_until(|network| network.tail(2) == block_id)
+                .await
+        );
+    }
+    assert!(network.converge(&[0, 1, 2]).await);
+}
+
+#[tokio::test]
+async fn nodes_converge_after_partition_heals() {
+    let mut network = SimulatedNetwork::new(4);
+    assert!(network.connect(3).await);
+
+    network.partition(&[0, 1]);
+    for _ in 0..2 {
+        network.mine_block(0);
+        assert!(network.converge(&[0, 1]).await);
+    }
+    let mut heaviest_tail = None;
+    for _ in 0..3 {
+        heaviest_tail = Some(network.mine_block(2));
+        assert!(network.converge(&[2, 3]).await);
+    }
+    assert_ne!(network.tail(0), network.tail(2));
+
+    network.heal();
+    assert!(network.converge(&[0, 1, 2, 3]).await);
+    assert_eq!(Some(network.tail(0)), heaviest_tail);
+}
@@ -7,16 +7,68 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::HexBlob;
+use common::{Block, Transaction};
+use crypto::{Hash256, KeyImage, PublicKey};
+
 jsonrpsee::rpc_api! {
     pub DaemonRPC {
         /// Request the core's current status
         fn get_stats() -> GetStatsResponse;
 
         /// Submit a mined block to the chain
-        fn submit_block(block: String) -> String;
+        fn submit_block(block: HexBlob<Block>) -> String;
+
+        /// Request a block to mine on top of the chain's tail, paying the miner's wallet
+        /// address and reserving the given number of bytes in the coinbase transaction
+        fn get_block_template(wallet_address: String, reserve_size: u64) -> GetBlockTemplateResponse;
+
+        /// Submit a transaction to the transaction pool, relaying it to the P2P network unless
+        /// asked not to
+        fn send_raw_transaction(tx_hex: HexBlob<Transaction>, do_not_relay: bool) -> SendRawTransactionResponse;
+
+        /// Request a main chain block by its hash or height, optionally decoded to JSON
+        fn get_block(hash: Option<HexBlob<Hash256>>, height: Option<u64>, decode_as_json: Option<bool>) -> GetBlockResponse;
+
+        /// Request the header of the main chain block with the given hash
+        fn get_block_header_by_hash(hash: HexBlob<Hash256>) -> BlockHeaderInfo;
+
+        /// Request the header of the main chain block at the given height
+        fn get_block_header_by_height(height: u64) -> BlockHeaderInfo;
+
+        /// Request the header of the chain's tail
+        fn get_last_block_header() -> BlockHeaderInfo;
+
+        /// Request confirmed or pooled transactions by their IDs, optionally decoded to JSON
+        fn get_transactions(txids: Vec<HexBlob<Hash256>>, decode_as_json: Option<bool>) -> GetTransactionsResponse;
+
+        /// Request whether key images were spent in the chain or by pooled transactions
+        fn is_key_image_spent(key_images: Vec<HexBlob<KeyImage>>) -> IsKeyImageSpentResponse;
+
+        /// Request outputs by their global output index, for use as ring members
+        fn get_outs(indices: Vec<u64>) -> GetOutsResponse;
+
+        /// Request the cumulative number of outputs at each height of a range of blocks
+        fn get_output_distribution(from: u64, to: Option<u64>) -> GetOutputDistributionResponse;
 
-        /// Request a range of confirmed blocks from the blockchain
-        fn get_blocks(from: u64, to: Option<u64>) -> GetBlocksResponse;
+        /// Request a range of confirmed blocks from the blockchain, up to a limited count and
+        /// size. Pruned transactions keep their full prefix and RingCT base, which is what
+        /// wallets need to scan for outputs, but drop the range proofs, input commitments and
+        /// MLSAG signatures, so they can no longer be verified
+        fn get_blocks(from: u64, to: Option<u64>, prune: Option<bool>) -> GetBlocksResponse;
+
+        /// Ban a host from the P2P network for the given number of seconds, or permanently
+        /// if no duration is given
+        fn ban(host: String, seconds: Option<u64>) -> String;
+
+        /// Lift the ban on a host
+        fn unban(host: String) -> String;
+
+        /// Request the list of banned hosts
+        fn get_bans() -> GetBansResponse;
+
+        /// Request the number of bytes exchanged with the P2P network
+        fn get_net_stats() -> GetNetStatsResponse;
     }
 }
 
@@ -24,25 +76,293 @@ jsonrpsee::rpc_api! {
 #[derive(Serialize, Deserialize)]
 pub struct GetStatsResponse {
     /// Current tail of the chain in the form of (height, block_id)
-    pub tail: (u64, String),
+    pub tail: (u64, HexBlob<Hash256>),
 
     /// Target height for syncing
     pub target_height: u64,
 
-    /// Current difficulty of the network
-    // TODO FIXME: Change back to u128 once jsonrpsee works with it
-    pub difficulty: u64,
+    /// Difficulty the next block has to satisfy, as a decimal string
+    pub difficulty: String,
+
+    /// Cumulative difficulty of the main chain, as a decimal string
+    pub cumulative_difficulty: String,
+
+    /// Version of the network at the chain's tail
+    pub major_version: u8,
+
+    /// Network the node is running on
+    pub network: String,
 
     /// Number of unconfirmed transactions in the mempool
     pub tx_pool_count: u64,
+
+    /// Total size of the unconfirmed transactions in the mempool in bytes
+    pub tx_pool_weight: u64,
+
+    /// Number of peers connected to the node
+    pub incoming_connections: u64,
+
+    /// Number of peers the node connected to
+    pub outgoing_connections: u64,
+
+    /// Size of the blockchain database in bytes
+    pub db_size: u64,
+
+    /// Number of seconds since the node started
+    pub uptime: u64,
+}
+
+/// Response to a GetBlockTemplateRequest
+#[derive(Serialize, Deserialize)]
+pub struct GetBlockTemplateResponse {
+    /// Block to mine
+    pub block: HexBlob<Block>,
+
+    /// Height of the block
+    pub height: u64,
+
+    /// ID of the block the template builds on
+    pub prev_id: HexBlob<Hash256>,
+
+    /// Difficulty the block has to satisfy, as a decimal string
+    pub difficulty: String,
+
+    /// Amount paid by the coinbase transaction, including transaction fees
+    pub expected_reward: u64,
+
+    /// Offset of the reserved bytes within the serialized block, or 0 if none were reserved
+    pub reserved_offset: u64,
+}
+
+/// Reason a transaction was rejected by the transaction pool
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionRejection {
+    /// The transaction is in the chain or the transaction pool already
+    AlreadyKnown,
+    /// A key image of the transaction was spent in the chain or by another pool transaction
+    DoubleSpend,
+    /// The transaction pays less than the minimum fee for its size
+    LowFee {
+        /// Minimum fee for the transaction
+        minimum: u64,
+    },
+    /// The RingCT signature of the transaction is missing or fails verification
+    InvalidRing,
+    /// The transaction is larger than the maximum transaction size
+    TooBig {
+        /// Maximum size of a transaction in bytes
+        limit: u64,
+    },
+    /// The transaction breaks another rule, given by the message
+    Invalid(String),
+}
+
+/// Response to a SendRawTransactionRequest
+#[derive(Serialize, Deserialize)]
+pub struct SendRawTransactionResponse {
+    /// ID of the transaction
+    pub txid: HexBlob<Hash256>,
+
+    /// Reason the transaction was rejected, or none if it was added to the transaction pool
+    pub rejection: Option<TransactionRejection>,
+
+    /// Whether the transaction was handed over to the P2P network for relaying
+    pub relayed: bool,
+}
+
+/// Header of a main chain block
+#[derive(Serialize, Deserialize)]
+pub struct BlockHeaderInfo {
+    /// ID of the block
+    pub hash: HexBlob<Hash256>,
+    /// Height of the block
+    pub height: u64,
+    /// Number of blocks on top of the block
+    pub depth: u64,
+    /// Current version of the network
+    pub major_version: u8,
+    /// Version of the network the miner voted for
+    pub minor_version: u8,
+    /// Timestamp of the block
+    pub timestamp: u64,
+    /// ID of the previous block
+    pub prev_id: HexBlob<Hash256>,
+    /// Amount paid by the coinbase transaction, including transaction fees
+    pub reward: u64,
+    /// ID of the coinbase transaction
+    pub miner_tx_hash: HexBlob<Hash256>,
+    /// Number of transactions confirmed by the block, excluding the coinbase transaction
+    pub tx_count: u64,
+}
+
+/// Response to a GetBlockRequest
+#[derive(Serialize, Deserialize)]
+pub struct GetBlockResponse {
+    /// Header of the block
+    pub block_header: BlockHeaderInfo,
+    /// The block itself
+    pub block: HexBlob<Block>,
+    /// Block decoded to JSON for reading by humans, if requested
+    pub json: Option<serde_json::Value>,
+    /// IDs of the transactions confirmed by the block
+    pub tx_hashes: Vec<HexBlob<Hash256>>,
+}
+
+/// A confirmed or pooled transaction
+#[derive(Serialize, Deserialize)]
+pub struct TransactionInfo {
+    /// ID of the transaction
+    pub txid: HexBlob<Hash256>,
+    /// The transaction itself
+    pub transaction: HexBlob<Transaction>,
+    /// Transaction decoded to JSON for reading by humans, if requested
+    pub json: Option<serde_json::Value>,
+    /// Whether the transaction is in the transaction pool rather than the chain
+    pub in_pool: bool,
+    /// Height of the block that confirmed the transaction, or none if it's in the pool
+    pub block_height: Option<u64>,
+    /// Number of blocks confirming the transaction, starting at 1 for the block including it
+    pub confirmations: u64,
+}
+
+/// Response to a GetTransactionsRequest
+#[derive(Serialize, Deserialize)]
+pub struct GetTransactionsResponse {
+    /// Transactions that were found
+    pub transactions: Vec<TransactionInfo>,
+    /// IDs of the transactions that weren't found
+    pub missed_txids: Vec<HexBlob<Hash256>>,
+}
+
+/// Spent status of a key image
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyImageStatus {
+    /// The key image isn't spent
+    Unspent,
+    /// The key image was spent in the main chain
+    SpentInChain,
+    /// The key image is spent by a transaction in the transaction pool
+    SpentInPool,
+}
+
+/// Response to an IsKeyImageSpentRequest
+#[derive(Serialize, Deserialize)]
+pub struct IsKeyImageSpentResponse {
+    /// Status of each key image, in the order of the request
+    pub statuses: Vec<KeyImageStatus>,
+}
+
+/// An output of a confirmed transaction
+#[derive(Serialize, Deserialize)]
+pub struct OutputInfo {
+    /// Destination key of the output
+    pub key: HexBlob<PublicKey>,
+    /// Amount commitment of the output
+    pub commitment: HexBlob<PublicKey>,
+    /// Whether the output can be spent on top of the chain's current tail
+    pub unlocked: bool,
+    /// Height of the block that confirmed the output
+    pub height: u64,
+    /// ID of the transaction the output belongs to
+    pub txid: HexBlob<Hash256>,
+}
+
+/// Response to a GetOutsRequest
+#[derive(Serialize, Deserialize)]
+pub struct GetOutsResponse {
+    /// Requested outputs, in the order of the requested indices
+    pub outs: Vec<OutputInfo>,
+}
+
+/// Response to a GetOutputDistributionRequest
+#[derive(Serialize, Deserialize)]
+pub struct GetOutputDistributionResponse {
+    /// Height of the first block of the distribution
+    pub start_height: u64,
+    /// Number of outputs in the chain up to and including each block from the start height
+    pub distribution: Vec<u64>,
+}
+
+/// A host banned from the P2P network
+#[derive(Serialize, Deserialize)]
+pub struct BanInfo {
+    /// IP address of the host
+    pub host: String,
+    /// Number of seconds until the ban expires, or none if the ban is permanent
+    pub seconds: Option<u64>,
+}
+
+/// Response to a GetBansRequest
+#[derive(Serialize, Deserialize)]
+pub struct GetBansResponse {
+    /// Hosts currently banned
+    pub bans: Vec<BanInfo>,
+}
+
+/// Bytes exchanged with a connected peer
+#[derive(Serialize, Deserialize)]
+pub struct PeerNetStats {
+    /// ID of the peer
+    pub peer_id: String,
+    /// Bytes received from the peer since it connected
+    pub bytes_in: u64,
+    /// Bytes sent to the peer since it connected
+    pub bytes_out: u64,
+}
+
+/// Response to a GetNetStatsRequest
+#[derive(Serialize, Deserialize)]
+pub struct GetNetStatsResponse {
+    /// Bytes received since the node started
+    pub total_bytes_in: u64,
+    /// Bytes sent since the node started
+    pub total_bytes_out: u64,
+    /// Traffic of each connected peer
+    pub peers: Vec<PeerNetStats>,
 }
 
-// TODO: Make these strongly typed while still serializing to hex strings
 /// Response to a GetBlocksRequest
 #[derive(Serialize, Deserialize)]
 pub struct GetBlocksResponse {
-    /// Blocks in the form of hex strings
-    pub blocks: Vec<String>,
-    /// Transactions contained in the given blocks in the form of hex strings
-    pub transactions: Vec<String>,
+    /// Requested blocks, which may stop short of the requested end height
+    pub blocks: Vec<HexBlob<Block>>,
+    /// Transactions contained in the given blocks, in the order of their IDs in the blocks
+    pub transactions: Vec<HexBlob<Transaction>>,
+    /// Height to request the following blocks from
+    pub next_height: u64,
+}
+
+/// Notification of a block added to the main chain, sent to `subscribe_new_blocks` subscribers
+#[derive(Serialize, Deserialize)]
+pub struct NewBlockNotification {
+    /// Height of the block
+    pub height: u64,
+    /// ID of the block
+    pub hash: HexBlob<Hash256>,
+    /// The block itself
+    pub block: HexBlob<Block>,
+}
+
+/// Notification of a transaction added to the transaction pool, sent to `subscribe_tx_pool`
+/// subscribers
+#[derive(Serialize, Deserialize)]
+pub struct TxPoolNotification {
+    /// ID of the transaction
+    pub txid: HexBlob<Hash256>,
+    /// The transaction itself
+    pub transaction: HexBlob<Transaction>,
+}
+
+/// Notification of the main chain switching over to an alternative chain, sent to
+/// `subscribe_chain_reorg` subscribers
+///
+/// The blocks of the new main chain follow as new block notifications
+#[derive(Serialize, Deserialize)]
+pub struct ChainReorgNotification {
+    /// Height of the last block both chains have in common
+    pub split_height: u64,
+    /// IDs of the blocks removed from the main chain, from the old tail down
+    pub disconnected: Vec<HexBlob<Hash256>>,
 }
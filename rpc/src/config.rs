@@ -1,10 +1,43 @@
+use std::{net::IpAddr, path::PathBuf};
+
 use structopt::StructOpt;
 
 /// RPC Server config
 #[derive(StructOpt, Debug)]
 #[structopt(rename_all = "kebab-case", name = "Unprll")]
 pub struct Config {
+    /// IP address to bind the RPC server, either IPv4 or IPv6
+    #[structopt(long, default_value = "127.0.0.1")]
+    pub rpc_bind_address: IpAddr,
+
     /// RPC Server bind port
     #[structopt(long, default_value = "21150")]
     pub rpc_bind_port: u16,
+
+    /// WebSocket server bind port, for subscribing to new blocks, pool transactions and chain
+    /// reorganizations
+    #[structopt(long, default_value = "21151")]
+    pub rpc_ws_bind_port: u16,
+
+    /// Require clients to send this token in an `Authorization: Bearer <token>` header
+    #[structopt(long)]
+    pub rpc_access_token: Option<String>,
+
+    /// Serve the RPC server over TLS
+    #[structopt(long)]
+    pub rpc_ssl: bool,
+
+    /// Path to the PEM encoded TLS certificate. Enables TLS, generating a self-signed
+    /// certificate if neither the certificate nor the private key exist
+    #[structopt(long)]
+    pub rpc_ssl_certificate: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key of the TLS certificate
+    #[structopt(long)]
+    pub rpc_ssl_private_key: Option<PathBuf>,
+
+    /// Disable administrative calls and limit the size of expensive queries, for exposing the
+    /// RPC server to the public
+    #[structopt(long)]
+    pub restricted_rpc: bool,
 }
@@ -0,0 +1,374 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+};
+
+use hyper::{
+    body::HttpBody,
+    client::HttpConnector,
+    header::{self, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    Body, Client, Method, Request, Response, StatusCode, Uri,
+};
+use jsonrpsee::{
+    common,
+    transport::{TransportClient, TransportServer, TransportServerEvent},
+};
+use rustls::ServerConfig;
+use serde_json::json;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Maximum size of an RPC request body in bytes
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
+
+/// Number of requests waiting for the RPC server before connections stop being read from
+const REQUEST_QUEUE_SIZE: usize = 64;
+
+/// A request received by the listener, along with where to send its serialized response
+type PendingRequest = (common::Request, oneshot::Sender<Option<String>>);
+
+/// The transport of an RPC server, fed by the HTTP listener started by `bind_transport`
+///
+/// Requests are handed over within the process, so the only way to reach the RPC server is
+/// through the listener and its authentication
+pub struct GatewayTransport {
+    requests: mpsc::Receiver<PendingRequest>,
+    responders: HashMap<u64, oneshot::Sender<Option<String>>>,
+    next_id: u64,
+}
+
+impl TransportServer for GatewayTransport {
+    type RequestId = u64;
+
+    fn next_request<'a>(
+        &'a mut self,
+    ) -> Pin<Box<dyn Future<Output = TransportServerEvent<Self::RequestId>> + Send + 'a>> {
+        Box::pin(async move {
+            match self.requests.recv().await {
+                Some((request, responder)) => {
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    self.responders.insert(id, responder);
+
+                    TransportServerEvent::Request { id, request }
+                }
+                // The listener keeps accepting connections for as long as the process runs
+                None => futures::future::pending().await,
+            }
+        })
+    }
+
+    fn finish<'a>(
+        &'a mut self,
+        request_id: &'a Self::RequestId,
+        response: Option<&'a common::Response>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + 'a>> {
+        let result = match self.responders.remove(request_id) {
+            Some(responder) => {
+                let response = response.map(|response| {
+                    serde_json::to_string(response).expect("Responses can always be serialized")
+                });
+                // The client may have disconnected in the meantime
+                let _ = responder.send(response);
+                Ok(())
+            }
+            None => Err(()),
+        };
+
+        Box::pin(async move { result })
+    }
+
+    fn supports_resuming(&self, request_id: &Self::RequestId) -> Result<bool, ()> {
+        if self.responders.contains_key(request_id) {
+            Ok(false)
+        } else {
+            Err(())
+        }
+    }
+
+    fn send<'a>(
+        &'a mut self,
+        _request_id: &'a Self::RequestId,
+        _response: &'a common::Response,
+    ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + 'a>> {
+        // Each HTTP request gets exactly one response
+        Box::pin(async { Err(()) })
+    }
+}
+
+/// Binds the HTTP transport of an RPC server to the given address
+///
+/// jsonrpsee's own HTTP server neither gives access to request headers nor speaks TLS, so the
+/// requests are served by a listener of our own instead. It terminates TLS if a configuration is
+/// given, and only hands requests carrying an `Authorization: Bearer <token>` header over to the
+/// RPC server if an access token is given
+pub async fn bind_transport(
+    addr: &SocketAddr,
+    access_token: Option<String>,
+    tls_config: Option<Arc<ServerConfig>>,
+) -> Result<GatewayTransport, anyhow::Error> {
+    let mut listener = TcpListener::bind(addr)
+        .await
+        .map_err(|error| anyhow::anyhow!("Failed to bind RPC server to {}: {}", addr, error))?;
+    let acceptor = tls_config.map(TlsAcceptor::from);
+    let authorization = Arc::new(access_token.map(|token| format!("Bearer {}", token)));
+    let (sender, requests) = mpsc::channel(REQUEST_QUEUE_SIZE);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    log::warn!("Failed to accept RPC connection: {}", error);
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            let authorization = authorization.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    handle_request(request, sender.clone(), authorization.clone())
+                });
+
+                let result = match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => Http::new().serve_connection(stream, service).await,
+                        Err(error) => {
+                            log::debug!("RPC TLS handshake failed: {}", error);
+                            return;
+                        }
+                    },
+                    None => Http::new().serve_connection(stream, service).await,
+                };
+                if let Err(error) = result {
+                    log::debug!("RPC connection failed: {}", error);
+                }
+            });
+        }
+    });
+
+    Ok(GatewayTransport {
+        requests,
+        responders: HashMap::new(),
+        next_id: 0,
+    })
+}
+
+/// Hands a request over to the RPC server and waits for its response, or rejects the request
+/// if it isn't authenticated
+async fn handle_request(
+    request: Request<Body>,
+    mut sender: mpsc::Sender<PendingRequest>,
+    authorization: Arc<Option<String>>,
+) -> Result<Response<Body>, hyper::Error> {
+    let authorized = match &*authorization {
+        Some(authorization) => request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .map_or(false, |value| {
+                constant_time_eq(value.as_bytes(), authorization.as_bytes())
+            }),
+        None => true,
+    };
+
+    if !authorized {
+        let mut response = status_response(StatusCode::UNAUTHORIZED);
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return Ok(response);
+    }
+    if request.method() != Method::POST {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let body = match read_body(request.into_body()).await? {
+        Some(body) => body,
+        None => return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE)),
+    };
+    let request = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(_) => {
+            let error = json!({
+                "jsonrpc": "2.0",
+                "error": { "code": -32700, "message": "Parse error" },
+                "id": null,
+            });
+            return Ok(json_response(error.to_string()));
+        }
+    };
+
+    let (responder, response) = oneshot::channel();
+    if sender.send((request, responder)).await.is_err() {
+        return Ok(status_response(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    match response.await {
+        Ok(Some(response)) => Ok(json_response(response)),
+        // Notifications don't get a response
+        Ok(None) => Ok(status_response(StatusCode::NO_CONTENT)),
+        Err(_) => Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+/// Reads a request body, unless it's larger than `MAX_REQUEST_SIZE`
+async fn read_body(mut body: Body) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(bytes))
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn json_response(body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+/// Errors of `ClientTransport`
+#[derive(Debug, thiserror::Error)]
+pub enum ClientTransportError {
+    /// The request couldn't be sent or its response couldn't be received
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] hyper::Error),
+
+    /// The server answered with an HTTP error
+    #[error("Server responded with {0}")]
+    Status(StatusCode),
+
+    /// A request couldn't be serialized or a response couldn't be parsed
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// A response was awaited without sending a request first
+    #[error("No response to wait for")]
+    NoResponse,
+}
+
+/// The transport of an RPC client, sending each request to the server in an HTTP request of its
+/// own
+///
+/// jsonrpsee's own HTTP client can't send headers, so this one is used to send the access token
+/// in an `Authorization: Bearer <token>` header. Clones send to the same server but don't share
+/// responses
+pub struct ClientTransport {
+    client: Client<HttpConnector>,
+    url: Uri,
+    authorization: Option<HeaderValue>,
+    responses: VecDeque<common::Response>,
+}
+
+impl ClientTransport {
+    /// Creates a transport sending requests to the given plain HTTP address
+    pub(crate) fn new(address: &str, access_token: Option<&str>) -> Result<Self, anyhow::Error> {
+        Ok(ClientTransport {
+            client: Client::new(),
+            url: format!("http://{}", address).parse()?,
+            authorization: access_token
+                .map(|token| HeaderValue::from_str(&format!("Bearer {}", token)))
+                .transpose()?,
+            responses: VecDeque::new(),
+        })
+    }
+}
+
+impl Clone for ClientTransport {
+    fn clone(&self) -> Self {
+        ClientTransport {
+            client: self.client.clone(),
+            url: self.url.clone(),
+            authorization: self.authorization.clone(),
+            responses: VecDeque::new(),
+        }
+    }
+}
+
+impl TransportClient for ClientTransport {
+    type Error = ClientTransportError;
+
+    fn send_request<'s>(
+        &'s mut self,
+        request: common::Request,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send + 's>> {
+        Box::pin(async move {
+            let mut http_request = Request::new(Body::from(serde_json::to_vec(&request)?));
+            *http_request.method_mut() = Method::POST;
+            *http_request.uri_mut() = self.url.clone();
+            let headers = http_request.headers_mut();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            if let Some(authorization) = &self.authorization {
+                headers.insert(header::AUTHORIZATION, authorization.clone());
+            }
+
+            let response = self.client.request(http_request).await?;
+            if !response.status().is_success() {
+                return Err(ClientTransportError::Status(response.status()));
+            }
+
+            // Notifications don't get a response
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            if !body.is_empty() {
+                self.responses.push_back(serde_json::from_slice(&body)?);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn next_response<'s>(
+        &'s mut self,
+    ) -> Pin<Box<dyn Future<Output = Result<common::Response, Self::Error>> + Send + 's>> {
+        let response = self
+            .responses
+            .pop_front()
+            .ok_or(ClientTransportError::NoResponse);
+
+        Box::pin(async move { response })
+    }
+}
+
+/// Compares two byte strings without leaking the position of the first difference through timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"Bearer token", b"Bearer token"));
+        assert!(!constant_time_eq(b"Bearer token", b"Bearer tokem"));
+        assert!(!constant_time_eq(b"Bearer token", b"Bearer token2"));
+        assert!(!constant_time_eq(b"", b"Bearer token"));
+    }
+}
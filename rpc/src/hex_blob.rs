@@ -0,0 +1,93 @@
+use std::{fmt, marker::PhantomData, ops::Deref};
+
+use serde::{
+    de::{self, DeserializeOwned, Visitor},
+    ser, Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// A value carried over RPC as its bincode serialization in the form of a hex string
+///
+/// Blocks, transactions, hashes and keys are encoded with bincode, the same serialization the
+/// P2P protocol uses. This is not the consensus serialization used for hashing, but it keeps
+/// them compact and lets clients decode them into the real types. Values that fail to decode
+/// are reported as deserialization errors
+#[derive(Clone, Debug, PartialEq)]
+pub struct HexBlob<T>(pub T);
+
+impl<T> HexBlob<T> {
+    /// Gets the wrapped value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for HexBlob<T> {
+    fn from(value: T) -> Self {
+        HexBlob(value)
+    }
+}
+
+impl<T> Deref for HexBlob<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Serialize> Serialize for HexBlob<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let blob = bincode::serialize(&self.0).map_err(ser::Error::custom)?;
+        serializer.serialize_str(&hex::encode(blob))
+    }
+}
+
+struct HexBlobVisitor<T>(PhantomData<T>);
+
+impl<'de, T: DeserializeOwned> Visitor<'de> for HexBlobVisitor<T> {
+    type Value = HexBlob<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a hex encoded blob")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        let blob = hex::decode(value)
+            .map_err(|error| E::custom(format!("Invalid hex string: {}", error)))?;
+        bincode::deserialize(&blob)
+            .map(HexBlob)
+            .map_err(|error| E::custom(format!("Invalid blob: {}", error)))
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for HexBlob<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(HexBlobVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::Hash256;
+
+    #[test]
+    fn hashes_are_plain_hex() {
+        let hash = Hash256::null_hash();
+
+        let json = serde_json::to_string(&HexBlob(hash.clone())).unwrap();
+        assert_eq!(json, format!("\"{}\"", hash));
+
+        let decoded: HexBlob<Hash256> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.into_inner(), hash);
+    }
+
+    #[test]
+    fn decode_errors_are_reported() {
+        assert!(serde_json::from_str::<HexBlob<Hash256>>("\"not hex\"").is_err());
+        // Too short to be a hash
+        assert!(serde_json::from_str::<HexBlob<Hash256>>("\"0011\"").is_err());
+        assert!(serde_json::from_str::<HexBlob<Hash256>>("42").is_err());
+    }
+}
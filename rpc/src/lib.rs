@@ -6,34 +6,81 @@
 
 use std::{
     future::Future,
+    net::SocketAddr,
     sync::{Arc, RwLock},
 };
 
 use cryptonote_core::{CryptonoteCore, EmissionCurve};
-use jsonrpsee::{raw::RawServer, transport::http::HttpTransportServer};
+use jsonrpsee::raw::RawServer;
+use p2p::NetworkState;
+use transaction_util::address::AddressPrefixes;
 
 pub mod api_definitions;
 mod config;
+mod gateway;
+mod hex_blob;
 mod rpc_server;
+mod subscriptions;
+mod tls;
 
 pub use config::Config;
+pub use gateway::{bind_transport, ClientTransport, ClientTransportError, GatewayTransport};
+pub use hex_blob::HexBlob;
 use rpc_server::DaemonRPCServer;
+pub use subscriptions::{subscribe, subscribe_with_retry, SubscriptionEvent};
+pub use tls::{client_address, client_transport, fingerprint, load_server_config};
 
 /// Initialize the RPC server
-pub fn init<TCoin: 'static + EmissionCurve + Send + Sync>(
+pub fn init<TCoin: 'static + EmissionCurve + AddressPrefixes + Send + Sync>(
     config: &Config,
     core: Arc<RwLock<CryptonoteCore<TCoin>>>,
+    network_state: Arc<RwLock<NetworkState>>,
 ) -> Result<impl Future, anyhow::Error> {
-    let addr = format!("127.0.0.1:{}", config.rpc_bind_port).parse()?;
+    let addr = SocketAddr::new(config.rpc_bind_address, config.rpc_bind_port);
+    let ws_addr = SocketAddr::new(config.rpc_bind_address, config.rpc_ws_bind_port);
+    let access_token = config.rpc_access_token.clone();
+    let restricted = config.restricted_rpc;
+    let tls_config = if config.rpc_ssl || config.rpc_ssl_certificate.is_some() {
+        Some(load_server_config(
+            config.rpc_ssl_certificate.as_deref(),
+            config.rpc_ssl_private_key.as_deref(),
+        )?)
+    } else {
+        None
+    };
+
+    if !addr.ip().is_loopback() && access_token.is_none() && !restricted {
+        log::warn!(
+            "RPC server is reachable from other hosts without authentication or restrictions"
+        );
+    }
 
     Ok(async move {
-        let transport_server = HttpTransportServer::bind(&addr).await.unwrap();
+        let transport_server = bind_transport(&addr, access_token.clone(), tls_config.clone())
+            .await
+            .unwrap();
         let server = RawServer::new(transport_server);
 
-        let daemon_rpc_server = DaemonRPCServer::new(server, core);
+        let subscribe = {
+            let core = core.clone();
+            move || core.read().unwrap().blockchain().subscribe()
+        };
+        let daemon_rpc_server = DaemonRPCServer::new(server, core, network_state, restricted);
+
+        if restricted {
+            log::info!("RPC server listening on {} in restricted mode", addr);
+        } else {
+            log::info!("RPC server listening on {}", addr);
+        }
 
-        log::info!("RPC server listening on {}", addr);
+        let subscriptions = async {
+            if let Err(error) =
+                subscriptions::serve(ws_addr, access_token, tls_config, subscribe).await
+            {
+                log::error!("WebSocket RPC server failed: {}", error);
+            }
+        };
 
-        daemon_rpc_server.run().await;
+        futures::future::join(daemon_rpc_server.run(), subscriptions).await;
     })
 }
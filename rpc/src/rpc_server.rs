@@ -1,36 +1,156 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use jsonrpsee::{common::Error, raw::RawServer, transport::TransportServer};
 
-use crate::api_definitions::*;
-use common::GetHash;
+use crate::{api_definitions::*, HexBlob};
+use blockchain::{Blockchain, Error as BlockchainError, TXPoolError};
+use blockchain_db::Error as BlockchainDBError;
+use common::{Block, GetHash, Transaction};
 use cryptonote_core::{CryptonoteCore, EmissionCurve};
+use p2p::NetworkState;
+use transaction_util::{
+    address::{Address, AddressPrefixes},
+    tx_construction,
+};
+
+/// Maximum number of blocks returned by get_blocks
+const MAX_BLOCKS_PER_REQUEST: u64 = 1000;
+
+/// Maximum size of the hex-encoded blocks and transactions returned by get_blocks
+const MAX_BLOCKS_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Maximum number of blocks returned by get_blocks in restricted mode
+const RESTRICTED_MAX_BLOCKS_PER_REQUEST: u64 = 100;
+
+/// Maximum number of transactions that can be requested at once in restricted mode
+const RESTRICTED_MAX_TRANSACTIONS: usize = 100;
+
+/// Maximum number of outputs that can be requested at once in restricted mode
+const RESTRICTED_MAX_OUTS: usize = 5000;
+
+/// Maximum number of key images that can be checked at once in restricted mode
+const RESTRICTED_MAX_KEY_IMAGES: usize = 5000;
+
+/// Maximum number of heights of the output distribution returned at once in restricted mode
+const RESTRICTED_MAX_DISTRIBUTION_HEIGHTS: usize = 100_000;
 
 type CoreRef<TCoin> = Arc<RwLock<CryptonoteCore<TCoin>>>;
+type NetworkStateRef = Arc<RwLock<NetworkState>>;
+
+/// Gets the size a value of the given serialized size takes up in a JSON response as a
+/// `HexBlob`, including its quotes and the separating comma
+fn hex_blob_json_size(serialized_size: usize) -> usize {
+    2 * serialized_size + 3
+}
+
+/// Gets the reason to report to the client for a transaction rejected by the blockchain
+fn get_rejection(error: BlockchainError) -> TransactionRejection {
+    match error {
+        BlockchainError::DBError(BlockchainDBError::Exists) => TransactionRejection::AlreadyKnown,
+        BlockchainError::DBError(BlockchainDBError::KeyImageSpent)
+        | BlockchainError::TXPoolError(TXPoolError::DoubleSpend) => {
+            TransactionRejection::DoubleSpend
+        }
+        BlockchainError::TXPoolError(TXPoolError::FeeTooLow { minimum }) => {
+            TransactionRejection::LowFee { minimum }
+        }
+        BlockchainError::TXPoolError(TXPoolError::MissingRingCTSignature)
+        | BlockchainError::TXPoolError(TXPoolError::RingCT(_)) => TransactionRejection::InvalidRing,
+        BlockchainError::TXPoolError(TXPoolError::TooBig { limit }) => {
+            TransactionRejection::TooBig {
+                limit: limit as u64,
+            }
+        }
+        error => TransactionRejection::Invalid(error.to_string()),
+    }
+}
+
+/// Gets the header information of a main chain block
+fn get_block_header_info<TCoin: EmissionCurve>(
+    blockchain: &Blockchain<TCoin>,
+    block: &Block,
+) -> Result<BlockHeaderInfo, anyhow::Error> {
+    let height = block.height().with_context(|| "Block has no height")?;
+    let tail_height = blockchain
+        .get_tail()
+        .with_context(|| "No blocks in chain")?
+        .0;
+
+    Ok(BlockHeaderInfo {
+        hash: block.get_hash().into(),
+        height,
+        depth: tail_height - height,
+        major_version: block.header.major_version,
+        minor_version: block.header.minor_version,
+        timestamp: block.header.timestamp,
+        prev_id: block.header.prev_id.clone().into(),
+        reward: block
+            .miner_tx
+            .prefix
+            .outputs
+            .iter()
+            .map(|output| output.amount)
+            .sum(),
+        miner_tx_hash: block.miner_tx.get_hash().into(),
+        tx_count: block.tx_hashes.len() as u64,
+    })
+}
 
 pub struct DaemonRPCServer<R, I, TCoin>
 where
     R: TransportServer<RequestId = I>,
     I: Clone + Eq + std::hash::Hash + Send + Sync,
-    TCoin: EmissionCurve,
+    TCoin: EmissionCurve + AddressPrefixes,
 {
     core: CoreRef<TCoin>,
+    network_state: NetworkStateRef,
     server: Arc<RwLock<RawServer<R, I>>>,
+    start_time: Instant,
+    restricted: bool,
 }
 
 impl<R, I, TCoin> DaemonRPCServer<R, I, TCoin>
 where
     R: TransportServer<RequestId = I>,
     I: Clone + Eq + std::hash::Hash + Send + Sync,
-    TCoin: EmissionCurve,
+    TCoin: EmissionCurve + AddressPrefixes,
 {
-    pub fn new(server: RawServer<R, I>, core: CoreRef<TCoin>) -> Self {
+    pub fn new(
+        server: RawServer<R, I>,
+        core: CoreRef<TCoin>,
+        network_state: NetworkStateRef,
+        restricted: bool,
+    ) -> Self {
         Self {
             core,
+            network_state,
             server: Arc::from(RwLock::from(server)),
+            start_time: Instant::now(),
+            restricted,
         }
     }
+
+    /// Fails administrative calls in restricted mode
+    fn ensure_unrestricted(&self) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(!self.restricted, "Method is unavailable in restricted mode");
+        Ok(())
+    }
+
+    /// Fails requests for more items than allowed in restricted mode
+    fn ensure_within_limit(&self, count: usize, limit: usize) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(
+            !self.restricted || count <= limit,
+            "Requests are limited to {} items in restricted mode",
+            limit
+        );
+        Ok(())
+    }
+
     pub async fn run(self) {
         while let Ok(request) = DaemonRPC::next_request(&mut self.server.write().unwrap()).await {
             match request {
@@ -40,14 +160,28 @@ where
                         let core = self.core.read().unwrap();
                         let blockchain = core.blockchain();
 
+                        let (tail_height, tail_block) = blockchain
+                            .get_tail()
+                            .with_context(|| "No blocks in chain")?;
+
+                        let network_state = self.network_state.read().unwrap();
+
                         Ok::<_, anyhow::Error>(GetStatsResponse {
-                            difficulty: 1,
-                            tail: blockchain
-                                .get_tail()
-                                .map(|x| (x.0, x.1.get_hash().to_string()))
-                                .with_context(|| "No blocks in chain")?,
-                            target_height: 9999,
-                            tx_pool_count: 0,
+                            tail: (tail_height, tail_block.get_hash().into()),
+                            // We're our own target when we're not behind any peers
+                            target_height: network_state.target_height().max(tail_height),
+                            difficulty: blockchain.get_difficulty().to_string(),
+                            cumulative_difficulty: blockchain
+                                .get_cumulative_difficulty()
+                                .to_string(),
+                            major_version: tail_block.header.major_version,
+                            network: core.network().to_string(),
+                            tx_pool_count: blockchain.tx_pool().transaction_count() as u64,
+                            tx_pool_weight: blockchain.tx_pool().get_weight() as u64,
+                            incoming_connections: network_state.incoming_connections() as u64,
+                            outgoing_connections: network_state.outgoing_connections() as u64,
+                            db_size: blockchain.get_db_size(),
+                            uptime: self.start_time.elapsed().as_secs(),
                         })
                     };
                     match response.await {
@@ -59,12 +193,12 @@ where
                 // submit_block
                 DaemonRPC::SubmitBlock { respond, block } => {
                     let response = async {
-                        let block = bincode::deserialize(&hex::decode(block)?)?;
+                        self.ensure_unrestricted()?;
 
                         let mut core = self.core.write().unwrap();
                         let blockchain = core.blockchain_mut();
 
-                        blockchain.add_new_block(block)?;
+                        blockchain.add_new_block(block.into_inner())?;
 
                         Ok::<_, anyhow::Error>(())
                     };
@@ -74,45 +208,524 @@ where
                     };
                 }
 
+                // get_block_template
+                DaemonRPC::GetBlockTemplate {
+                    respond,
+                    wallet_address,
+                    reserve_size,
+                } => {
+                    let response = async {
+                        let miner_address = Address::from_address_string::<TCoin>(&wallet_address)?;
+                        let reserve_size = reserve_size as usize;
+                        anyhow::ensure!(
+                            reserve_size <= tx_construction::MAX_EXTRA_NONCE_SIZE,
+                            "Reserve size is larger than {} bytes",
+                            tx_construction::MAX_EXTRA_NONCE_SIZE
+                        );
+
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let block = blockchain.create_block_template(&miner_address, reserve_size);
+                        let reserved_offset = if reserve_size == 0 {
+                            0
+                        } else {
+                            block.get_reserved_offset(reserve_size)?
+                        };
+
+                        Ok::<_, anyhow::Error>(GetBlockTemplateResponse {
+                            height: block.height().with_context(|| "Block has no height")?,
+                            prev_id: block.header.prev_id.clone().into(),
+                            difficulty: blockchain.get_difficulty().to_string(),
+                            expected_reward: block.miner_tx.prefix.outputs[0].amount,
+                            reserved_offset: reserved_offset as u64,
+                            block: block.into(),
+                        })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // send_raw_transaction
+                DaemonRPC::SendRawTransaction {
+                    respond,
+                    tx_hex,
+                    do_not_relay,
+                } => {
+                    let response = async {
+                        let transaction = tx_hex.into_inner();
+                        let txid = transaction.get_hash();
+
+                        let mut core = self.core.write().unwrap();
+                        let blockchain = core.blockchain_mut();
+
+                        // Relayed transactions start out in the Dandelion++ stem phase
+                        let result = if blockchain.has_transaction(&txid)
+                            || blockchain.tx_pool().has_stem_transaction(&txid)
+                        {
+                            Err(TransactionRejection::AlreadyKnown)
+                        } else if do_not_relay {
+                            blockchain
+                                .add_transaction(transaction.clone())
+                                .map_err(get_rejection)
+                        } else {
+                            blockchain
+                                .add_stem_transaction(transaction.clone())
+                                .map_err(get_rejection)
+                        };
+
+                        drop(core);
+
+                        let relayed = result.is_ok() && !do_not_relay;
+                        if relayed {
+                            log::info!("Relaying transaction {}", txid);
+                            self.network_state
+                                .read()
+                                .unwrap()
+                                .relay_transaction(transaction);
+                        }
+
+                        Ok::<_, anyhow::Error>(SendRawTransactionResponse {
+                            txid: txid.into(),
+                            rejection: result.err(),
+                            relayed,
+                        })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_block
+                DaemonRPC::GetBlock {
+                    respond,
+                    hash,
+                    height,
+                    decode_as_json,
+                } => {
+                    let response = async {
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let block = match (hash, height) {
+                            (Some(hash), None) => blockchain.get_block(&hash),
+                            (None, Some(height)) => blockchain.get_block_by_height(height),
+                            _ => anyhow::bail!("Exactly one of hash and height must be given"),
+                        }
+                        .with_context(|| "Block not found in main chain")?;
+
+                        Ok::<_, anyhow::Error>(GetBlockResponse {
+                            block_header: get_block_header_info(blockchain, &block)?,
+                            json: if decode_as_json.unwrap_or(false) {
+                                Some(serde_json::to_value(&block)?)
+                            } else {
+                                None
+                            },
+                            tx_hashes: block.tx_hashes.iter().cloned().map(HexBlob).collect(),
+                            block: block.into(),
+                        })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_block_header_by_hash
+                DaemonRPC::GetBlockHeaderByHash { respond, hash } => {
+                    let response = async {
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let block = blockchain
+                            .get_block(&hash)
+                            .with_context(|| "Block not found in main chain")?;
+
+                        get_block_header_info(blockchain, &block)
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_block_header_by_height
+                DaemonRPC::GetBlockHeaderByHeight { respond, height } => {
+                    let response = async {
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let block = blockchain
+                            .get_block_by_height(height)
+                            .with_context(|| "Block not found in main chain")?;
+
+                        get_block_header_info(blockchain, &block)
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_last_block_header
+                DaemonRPC::GetLastBlockHeader { respond } => {
+                    let response = async {
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let (_, block) = blockchain
+                            .get_tail()
+                            .with_context(|| "No blocks in chain")?;
+
+                        get_block_header_info(blockchain, &block)
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_transactions
+                DaemonRPC::GetTransactions {
+                    respond,
+                    txids,
+                    decode_as_json,
+                } => {
+                    let response = async {
+                        self.ensure_within_limit(txids.len(), RESTRICTED_MAX_TRANSACTIONS)?;
+
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let tail_height = blockchain
+                            .get_tail()
+                            .with_context(|| "No blocks in chain")?
+                            .0;
+
+                        let mut transactions = Vec::new();
+                        let mut missed_txids = Vec::new();
+                        for txid in txids {
+                            // Stem transactions are left out so they can't be traced to this node
+                            let (transaction, block_height) =
+                                if let Some(transaction) = blockchain.get_transaction(&txid) {
+                                    (transaction, blockchain.get_transaction_height(&txid))
+                                } else if let Some(transaction) =
+                                    blockchain.tx_pool().get_transaction(&txid)
+                                {
+                                    (transaction.clone(), None)
+                                } else {
+                                    missed_txids.push(txid);
+                                    continue;
+                                };
+
+                            transactions.push(TransactionInfo {
+                                txid,
+                                json: if decode_as_json.unwrap_or(false) {
+                                    Some(serde_json::to_value(&transaction)?)
+                                } else {
+                                    None
+                                },
+                                transaction: transaction.into(),
+                                in_pool: block_height.is_none(),
+                                block_height,
+                                confirmations: block_height
+                                    .map_or(0, |height| tail_height - height + 1),
+                            });
+                        }
+
+                        Ok::<_, anyhow::Error>(GetTransactionsResponse {
+                            transactions,
+                            missed_txids,
+                        })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // is_key_image_spent
+                DaemonRPC::IsKeyImageSpent {
+                    respond,
+                    key_images,
+                } => {
+                    let response = async {
+                        self.ensure_within_limit(key_images.len(), RESTRICTED_MAX_KEY_IMAGES)?;
+
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let statuses = key_images
+                            .iter()
+                            .map(|key_image| {
+                                if blockchain.is_key_image_spent(key_image) {
+                                    KeyImageStatus::SpentInChain
+                                } else if blockchain.tx_pool().has_key_image(key_image) {
+                                    KeyImageStatus::SpentInPool
+                                } else {
+                                    KeyImageStatus::Unspent
+                                }
+                            })
+                            .collect();
+
+                        Ok::<_, anyhow::Error>(IsKeyImageSpentResponse { statuses })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_outs
+                DaemonRPC::GetOuts { respond, indices } => {
+                    let response = async {
+                        self.ensure_within_limit(indices.len(), RESTRICTED_MAX_OUTS)?;
+
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let tail_height = blockchain
+                            .get_tail()
+                            .with_context(|| "No blocks in chain")?
+                            .0;
+
+                        let outs = indices
+                            .into_iter()
+                            .map(|index| {
+                                let output = blockchain
+                                    .get_output(index)
+                                    .with_context(|| format!("Output {} does not exist", index))?;
+
+                                Ok(OutputInfo {
+                                    unlocked: output.is_unlocked(tail_height),
+                                    key: output.pair.destination.into(),
+                                    commitment: output.pair.commitment.into(),
+                                    height: output.height,
+                                    txid: output.txid.into(),
+                                })
+                            })
+                            .collect::<Result<_, anyhow::Error>>()?;
+
+                        Ok::<_, anyhow::Error>(GetOutsResponse { outs })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_output_distribution
+                DaemonRPC::GetOutputDistribution {
+                    respond,
+                    from: start_height,
+                    to: end_height,
+                } => {
+                    let response = async {
+                        let core = self.core.read().unwrap();
+                        let blockchain = core.blockchain();
+
+                        let tail_height = blockchain
+                            .get_tail()
+                            .with_context(|| "No blocks in chain")?
+                            .0;
+                        let end_height = end_height.unwrap_or(tail_height).min(tail_height);
+                        anyhow::ensure!(
+                            start_height <= end_height,
+                            "Start height {} is above end height {}",
+                            start_height,
+                            end_height
+                        );
+                        self.ensure_within_limit(
+                            (end_height - start_height + 1) as usize,
+                            RESTRICTED_MAX_DISTRIBUTION_HEIGHTS,
+                        )?;
+
+                        let distribution = (start_height..=end_height)
+                            .map(|height| blockchain.get_cumulative_output_count(height))
+                            .collect();
+
+                        Ok::<_, anyhow::Error>(GetOutputDistributionResponse {
+                            start_height,
+                            distribution,
+                        })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
                 // get_blocks
                 DaemonRPC::GetBlocks {
                     respond,
                     from: start_height,
                     to: end_height,
+                    prune,
                 } => {
                     let response = async {
-                        // The end height is optional and will default to a specified value. If the request is too
-                        // large, the range is reduced
-                        // TODO: Implement range reduction
-
                         let core = self.core.read().unwrap();
                         let blockchain = core.blockchain();
 
-                        let end_height =
-                            end_height.unwrap_or_else(|| blockchain.get_tail().unwrap().0);
+                        // The end height defaults to the chain's tail and is exclusive. Requests
+                        // that are too large are cut short and resumed from the next height
+                        let tail_height = blockchain
+                            .get_tail()
+                            .with_context(|| "No blocks in chain")?
+                            .0;
+                        let max_blocks = if self.restricted {
+                            RESTRICTED_MAX_BLOCKS_PER_REQUEST
+                        } else {
+                            MAX_BLOCKS_PER_REQUEST
+                        };
+                        let end_height = end_height
+                            .unwrap_or(tail_height + 1)
+                            .min(tail_height + 1)
+                            .min(start_height.saturating_add(max_blocks));
+
+                        let mut blocks = Vec::new();
+                        let mut transactions = Vec::new();
+                        let mut response_size = 0;
+                        for block in blockchain.get_blocks(start_height, end_height) {
+                            let mut block_transactions = block
+                                .tx_hashes
+                                .iter()
+                                .map(|txid| {
+                                    blockchain.get_transaction(txid).expect(
+                                        "The blockchain must always have all transactions from confirmed blocks",
+                                    )
+                                })
+                                .collect::<Vec<_>>();
+                            if prune.unwrap_or(false) {
+                                block_transactions.iter_mut().for_each(Transaction::prune);
+                            }
+
+                            let size =
+                                hex_blob_json_size(bincode::serialized_size(&block)? as usize)
+                                    + block_transactions
+                                        .iter()
+                                        .map(|transaction| hex_blob_json_size(transaction.size()))
+                                        .sum::<usize>();
+                            // Always send at least one block so clients can make progress
+                            if !blocks.is_empty() && response_size + size > MAX_BLOCKS_RESPONSE_SIZE
+                            {
+                                break;
+                            }
+                            response_size += size;
+
+                            transactions.extend(block_transactions.into_iter().map(HexBlob));
+                            blocks.push(HexBlob(block));
+                        }
+
+                        Ok::<_, anyhow::Error>(GetBlocksResponse {
+                            next_height: start_height + blocks.len() as u64,
+                            blocks,
+                            transactions,
+                        })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // ban
+                DaemonRPC::Ban {
+                    respond,
+                    host,
+                    seconds,
+                } => {
+                    let response = async {
+                        self.ensure_unrestricted()?;
+
+                        let host = host.parse::<IpAddr>()?;
+
+                        self.network_state
+                            .write()
+                            .unwrap()
+                            .ban_list_mut()
+                            .ban(host, seconds.map(Duration::from_secs));
+
+                        Ok::<_, anyhow::Error>(())
+                    };
+                    match response.await {
+                        Ok(()) => respond.ok(""),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // unban
+                DaemonRPC::Unban { respond, host } => {
+                    let response = async {
+                        self.ensure_unrestricted()?;
+
+                        let host = host.parse::<IpAddr>()?;
+
+                        let was_banned = self
+                            .network_state
+                            .write()
+                            .unwrap()
+                            .ban_list_mut()
+                            .unban(&host);
+
+                        anyhow::ensure!(was_banned, "Host {} is not banned", host);
+                        Ok::<_, anyhow::Error>(())
+                    };
+                    match response.await {
+                        Ok(()) => respond.ok(""),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_bans
+                DaemonRPC::GetBans { respond } => {
+                    let response = async {
+                        self.ensure_unrestricted()?;
+
+                        let network_state = self.network_state.read().unwrap();
 
-                        let blocks = blockchain.get_blocks(start_height, end_height);
+                        let bans = network_state
+                            .ban_list()
+                            .bans()
+                            .into_iter()
+                            .map(|ban| BanInfo {
+                                host: ban.address.to_string(),
+                                seconds: ban.remaining().map(|remaining| remaining.as_secs()),
+                            })
+                            .collect();
 
-                        let transactions = blocks
+                        Ok::<_, anyhow::Error>(GetBansResponse { bans })
+                    };
+                    match response.await {
+                        Ok(response) => respond.ok(response),
+                        Err(error) => respond.err(Error::invalid_params(error.to_string())),
+                    };
+                }
+
+                // get_net_stats
+                DaemonRPC::GetNetStats { respond } => {
+                    let response = async {
+                        self.ensure_unrestricted()?;
+
+                        let network_state = self.network_state.read().unwrap();
+                        let bandwidth = network_state.bandwidth();
+
+                        let peers = bandwidth
+                            .peers()
                             .iter()
-                            .flat_map(|block| &block.tx_hashes)
-                            .map(|txid| {
-                                hex::encode(
-                                    bincode::serialize(&blockchain.get_transaction(txid).expect(
-                                        "The blockchain must always have all transactions from confirmed blocks",
-                                    ))
-                                    .unwrap(),
-                                )
+                            .map(|(peer_id, counters)| PeerNetStats {
+                                peer_id: peer_id.to_string(),
+                                bytes_in: counters.bytes_in,
+                                bytes_out: counters.bytes_out,
                             })
                             .collect();
 
-                        Ok::<_, anyhow::Error>(GetBlocksResponse {
-                            blocks: blocks
-                                .into_iter()
-                                .flat_map(|block| bincode::serialize(&block))
-                                .map(hex::encode)
-                                .collect(),
-                            transactions,
+                        Ok::<_, anyhow::Error>(GetNetStatsResponse {
+                            total_bytes_in: bandwidth.total().bytes_in,
+                            total_bytes_out: bandwidth.total().bytes_out,
+                            peers,
                         })
                     };
                     match response.await {
@@ -0,0 +1,476 @@
+use std::{collections::HashMap, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use futures::{SinkExt, Stream, StreamExt};
+use rustls::ServerConfig;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::broadcast::{self, RecvError},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{header, HeaderValue, StatusCode},
+    Message,
+};
+
+use crate::{api_definitions::*, gateway::constant_time_eq, HexBlob};
+use common::GetHash;
+use cryptonote_core::ChainEvent;
+
+/// Maximum number of subscriptions a single connection can hold
+const MAX_SUBSCRIPTIONS: usize = 16;
+
+/// Time to wait before subscribing again after the WebSocket connection failed
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(10);
+
+/// Kinds of notifications clients can subscribe to
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Topic {
+    NewBlocks,
+    TxPool,
+    ChainReorg,
+}
+
+impl Topic {
+    fn from_method(method: &str) -> Option<Self> {
+        match method {
+            "subscribe_new_blocks" => Some(Topic::NewBlocks),
+            "subscribe_tx_pool" => Some(Topic::TxPool),
+            "subscribe_chain_reorg" => Some(Topic::ChainReorg),
+            _ => None,
+        }
+    }
+
+    fn method(self) -> &'static str {
+        match self {
+            Topic::NewBlocks => "subscribe_new_blocks",
+            Topic::TxPool => "subscribe_tx_pool",
+            Topic::ChainReorg => "subscribe_chain_reorg",
+        }
+    }
+
+    /// Gets the notification payload for an event, if the event is of this topic
+    fn payload(self, event: &ChainEvent) -> Option<Value> {
+        let payload = match (self, event) {
+            (Topic::NewBlocks, ChainEvent::NewBlock { height, block }) => {
+                serde_json::to_value(NewBlockNotification {
+                    height: *height,
+                    hash: block.get_hash().into(),
+                    block: block.clone().into(),
+                })
+            }
+            (Topic::TxPool, ChainEvent::NewTransaction(transaction)) => {
+                serde_json::to_value(TxPoolNotification {
+                    txid: transaction.get_hash().into(),
+                    transaction: transaction.clone().into(),
+                })
+            }
+            (
+                Topic::ChainReorg,
+                ChainEvent::Reorganized {
+                    split_height,
+                    disconnected,
+                },
+            ) => serde_json::to_value(ChainReorgNotification {
+                split_height: *split_height,
+                disconnected: disconnected.iter().cloned().map(HexBlob).collect(),
+            }),
+            _ => return None,
+        };
+
+        Some(payload.expect("Notifications can always be serialized"))
+    }
+}
+
+/// Subscriptions held by a single connection
+#[derive(Default)]
+struct Subscriptions {
+    topics: HashMap<u64, Topic>,
+    next_id: u64,
+}
+
+impl Subscriptions {
+    /// Handles a JSON-RPC request, returning the response to send back
+    fn handle_request(&mut self, request: &str) -> Value {
+        let request: Value = match serde_json::from_str(request) {
+            Ok(request) => request,
+            Err(error) => return error_response(Value::Null, -32700, &error.to_string()),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+
+        if let Some(topic) = Topic::from_method(method) {
+            if self.topics.len() >= MAX_SUBSCRIPTIONS {
+                return error_response(id, -32000, "Too many subscriptions");
+            }
+
+            let subscription = self.next_id;
+            self.next_id += 1;
+            self.topics.insert(subscription, topic);
+
+            return json!({ "jsonrpc": "2.0", "id": id, "result": subscription });
+        }
+
+        if method == "unsubscribe" {
+            let subscription = request
+                .get("params")
+                .and_then(|params| params.get(0))
+                .and_then(Value::as_u64);
+
+            return match subscription {
+                Some(subscription) => {
+                    let removed = self.topics.remove(&subscription).is_some();
+                    json!({ "jsonrpc": "2.0", "id": id, "result": removed })
+                }
+                None => error_response(id, -32602, "Expected a subscription ID"),
+            };
+        }
+
+        error_response(id, -32601, "Method not found")
+    }
+
+    /// Gets the notifications to send for an event
+    fn notifications(&self, event: &ChainEvent) -> Vec<Value> {
+        self.topics
+            .iter()
+            .filter_map(|(subscription, topic)| {
+                let payload = topic.payload(event)?;
+
+                Some(json!({
+                    "jsonrpc": "2.0",
+                    "method": topic.method(),
+                    "params": { "subscription": subscription, "result": payload },
+                }))
+            })
+            .collect()
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+/// Serves subscriptions to chain events over WebSocket connections on the given address
+///
+/// Each connection gets its own receiver of chain events from `subscribe`
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    access_token: Option<String>,
+    tls_config: Option<Arc<ServerConfig>>,
+    subscribe: impl Fn() -> broadcast::Receiver<ChainEvent>,
+) -> Result<(), anyhow::Error> {
+    let mut listener = TcpListener::bind(addr).await?;
+    let acceptor = tls_config.map(TlsAcceptor::from);
+    let authorization = Arc::new(access_token.map(|token| format!("Bearer {}", token)));
+
+    log::info!("WebSocket RPC server listening on {}", addr);
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                log::warn!("Failed to accept WebSocket connection: {}", error);
+                continue;
+            }
+        };
+
+        let events = subscribe();
+        let acceptor = acceptor.clone();
+        let authorization = authorization.clone();
+        tokio::spawn(async move {
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle_connection(stream, authorization, events).await,
+                    Err(error) => Err(error.into()),
+                },
+                None => handle_connection(stream, authorization, events).await,
+            };
+            if let Err(error) = result {
+                log::debug!("WebSocket connection from {} failed: {}", peer_addr, error);
+            }
+        });
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    authorization: Arc<Option<String>>,
+    mut events: broadcast::Receiver<ChainEvent>,
+) -> Result<(), anyhow::Error> {
+    let authenticate = |request: &Request, response: Response| {
+        let authorized = match &*authorization {
+            Some(authorization) => request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .map_or(false, |value| {
+                    constant_time_eq(value.as_bytes(), authorization.as_bytes())
+                }),
+            None => true,
+        };
+
+        if authorized {
+            Ok(response)
+        } else {
+            let mut response = ErrorResponse::new(None);
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            Err(response)
+        }
+    };
+    let mut websocket = tokio_tungstenite::accept_hdr_async(stream, authenticate).await?;
+
+    let mut subscriptions = Subscriptions::default();
+    loop {
+        tokio::select! {
+            message = websocket.next() => match message {
+                Some(Ok(Message::Text(request))) => {
+                    let response = subscriptions.handle_request(&request);
+                    websocket.send(Message::Text(response.to_string())).await?;
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(error)) => return Err(error.into()),
+            },
+            event = events.recv() => match event {
+                Ok(event) => {
+                    for notification in subscriptions.notifications(&event) {
+                        websocket.send(Message::Text(notification.to_string())).await?;
+                    }
+                }
+                // Clients that missed notifications have to catch up through the other RPC calls
+                Err(RecvError::Lagged(missed)) => {
+                    anyhow::bail!("Client fell behind by {} events", missed)
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Subscribes to a kind of notification of a daemon's WebSocket RPC server at the given URL
+///
+/// The method is one of `subscribe_new_blocks`, `subscribe_tx_pool` or `subscribe_chain_reorg`.
+/// The access token, if any, is sent in an `Authorization: Bearer <token>` header. Returns the
+/// payloads of the notifications as they arrive, ending when the connection closes
+pub async fn subscribe<T: DeserializeOwned>(
+    url: &str,
+    method: &str,
+    access_token: Option<&str>,
+) -> Result<impl Stream<Item = Result<T, anyhow::Error>>, anyhow::Error> {
+    let mut request = url.into_client_request()?;
+    if let Some(access_token) = access_token {
+        request.headers_mut().insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", access_token))?,
+        );
+    }
+    let (mut websocket, _) = tokio_tungstenite::connect_async(request).await?;
+
+    let request = json!({ "jsonrpc": "2.0", "id": 0, "method": method, "params": [] });
+    websocket.send(Message::Text(request.to_string())).await?;
+
+    loop {
+        match websocket.next().await {
+            Some(Ok(Message::Text(response))) => {
+                let response: Value = serde_json::from_str(&response)?;
+                if let Some(error) = response.get("error") {
+                    anyhow::bail!("Failed to subscribe to {}: {}", method, error);
+                }
+                break;
+            }
+            Some(Ok(_)) => {}
+            Some(Err(error)) => return Err(error.into()),
+            None => anyhow::bail!("Connection closed before subscribing to {}", method),
+        }
+    }
+
+    Ok(websocket.filter_map(|message| async move {
+        match message {
+            Ok(Message::Text(notification)) => Some(parse_notification(&notification)),
+            Ok(_) => None,
+            Err(error) => Some(Err(error.into())),
+        }
+    }))
+}
+
+/// Changes to a subscription kept up by `subscribe_with_retry`
+#[derive(Debug)]
+pub enum SubscriptionEvent<T> {
+    /// The subscription was established. Notifications sent while not subscribed are missed
+    Subscribed,
+    /// A notification was received
+    Notification(T),
+    /// Subscribing failed or the connection was lost. Another attempt is made after a delay
+    Disconnected,
+}
+
+/// Notifications of an established subscription, as returned by `subscribe`
+type Notifications<T> = Pin<Box<dyn Stream<Item = Result<T, anyhow::Error>> + Send>>;
+
+/// The state of a subscription kept up by `subscribe_with_retry`
+struct Resubscription<T> {
+    url: String,
+    method: &'static str,
+    access_token: Option<String>,
+    notifications: Option<Notifications<T>>,
+    attempted: bool,
+}
+
+/// Subscribes like `subscribe`, subscribing again after a delay whenever the connection fails
+///
+/// Clients can't tell which notifications they missed while not subscribed, so they should catch
+/// up through the other RPC calls on `Subscribed`. Polling on `Disconnected` keeps them up to
+/// date until the WebSocket server is back. Invalid notifications are logged and skipped
+pub fn subscribe_with_retry<T: DeserializeOwned + Send + 'static>(
+    url: String,
+    method: &'static str,
+    access_token: Option<String>,
+) -> impl Stream<Item = SubscriptionEvent<T>> {
+    let state = Resubscription {
+        url,
+        method,
+        access_token,
+        notifications: None,
+        attempted: false,
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(notifications) = &mut state.notifications {
+                match notifications.next().await {
+                    Some(Ok(notification)) => {
+                        return Some((SubscriptionEvent::Notification(notification), state))
+                    }
+                    Some(Err(error)) => {
+                        log::warn!("Invalid {} notification: {}", state.method, error);
+                        continue;
+                    }
+                    None => {
+                        log::warn!(
+                            "Daemon stopped sending {} notifications. Resubscribing in {} seconds",
+                            state.method,
+                            RESUBSCRIBE_DELAY.as_secs()
+                        );
+                        state.notifications = None;
+                        return Some((SubscriptionEvent::Disconnected, state));
+                    }
+                }
+            }
+
+            if state.attempted {
+                tokio::time::delay_for(RESUBSCRIBE_DELAY).await;
+            }
+            state.attempted = true;
+
+            match subscribe(&state.url, state.method, state.access_token.as_deref()).await {
+                Ok(notifications) => {
+                    state.notifications = Some(Box::pin(notifications));
+                    return Some((SubscriptionEvent::Subscribed, state));
+                }
+                Err(error) => {
+                    log::warn!(
+                        "Failed to subscribe to {}: {}. Retrying in {} seconds",
+                        state.method,
+                        error,
+                        RESUBSCRIBE_DELAY.as_secs()
+                    );
+                    return Some((SubscriptionEvent::Disconnected, state));
+                }
+            }
+        }
+    })
+}
+
+fn parse_notification<T: DeserializeOwned>(notification: &str) -> Result<T, anyhow::Error> {
+    let mut notification: Value = serde_json::from_str(notification)?;
+    let payload = notification
+        .pointer_mut("/params/result")
+        .map(Value::take)
+        .ok_or_else(|| anyhow::anyhow!("Malformed notification"))?;
+
+    Ok(serde_json::from_value(payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::Hash256;
+
+    fn reorg() -> ChainEvent {
+        ChainEvent::Reorganized {
+            split_height: 5,
+            disconnected: vec![Hash256::null_hash()],
+        }
+    }
+
+    #[test]
+    fn subscribes_and_unsubscribes() {
+        let mut subscriptions = Subscriptions::default();
+
+        let response = subscriptions.handle_request(
+            r#"{"jsonrpc":"2.0","id":1,"method":"subscribe_chain_reorg","params":[]}"#,
+        );
+        assert_eq!(response["id"], 1);
+        let subscription = response["result"].as_u64().unwrap();
+        assert_eq!(subscriptions.notifications(&reorg()).len(), 1);
+
+        let response = subscriptions.handle_request(&format!(
+            r#"{{"jsonrpc":"2.0","id":2,"method":"unsubscribe","params":[{}]}}"#,
+            subscription
+        ));
+        assert_eq!(response["result"], true);
+        assert!(subscriptions.notifications(&reorg()).is_empty());
+    }
+
+    #[test]
+    fn routes_notifications_by_topic() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.handle_request(r#"{"id":1,"method":"subscribe_new_blocks"}"#);
+        subscriptions.handle_request(r#"{"id":2,"method":"subscribe_tx_pool"}"#);
+        assert!(subscriptions.notifications(&reorg()).is_empty());
+
+        subscriptions.handle_request(r#"{"id":3,"method":"subscribe_chain_reorg"}"#);
+        let notifications = subscriptions.notifications(&reorg());
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["method"], "subscribe_chain_reorg");
+
+        let payload: ChainReorgNotification =
+            parse_notification(&notifications[0].to_string()).unwrap();
+        assert_eq!(payload.split_height, 5);
+        assert_eq!(*payload.disconnected[0], Hash256::null_hash());
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let mut subscriptions = Subscriptions::default();
+
+        assert_eq!(
+            subscriptions.handle_request("not json")["error"]["code"],
+            -32700
+        );
+        assert_eq!(
+            subscriptions.handle_request(r#"{"id":1,"method":"get_stats"}"#)["error"]["code"],
+            -32601
+        );
+        assert_eq!(
+            subscriptions.handle_request(r#"{"id":1,"method":"unsubscribe"}"#)["error"]["code"],
+            -32602
+        );
+
+        for _ in 0..MAX_SUBSCRIPTIONS {
+            subscriptions.handle_request(r#"{"id":1,"method":"subscribe_new_blocks"}"#);
+        }
+        assert!(subscriptions
+            .handle_request(r#"{"id":1,"method":"subscribe_new_blocks"}"#)
+            .get("error")
+            .is_some());
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    fs,
+    io::{BufReader, Write},
+    net::TcpListener as StdTcpListener,
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{
+    internal::pemfile, Certificate, ClientConfig, NoClientAuth, PrivateKey, RootCertStore,
+    ServerCertVerified, ServerCertVerifier, ServerConfig, TLSError,
+};
+use sha2::{Digest, Sha256};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsConnector;
+use webpki::DNSNameRef;
+
+use crate::gateway::ClientTransport;
+
+/// Gets the SHA-256 fingerprint of a DER encoded certificate in the form of a hex string
+pub fn fingerprint(certificate: &[u8]) -> String {
+    hex::encode(Sha256::digest(certificate))
+}
+
+/// Loads the TLS configuration of an RPC server from PEM encoded certificate and private key files
+///
+/// If neither file exists, a self-signed certificate is generated and saved to them. Without any
+/// paths, an ephemeral self-signed certificate is used, which changes on every start
+pub fn load_server_config(
+    certificate_path: Option<&Path>,
+    private_key_path: Option<&Path>,
+) -> Result<Arc<ServerConfig>, anyhow::Error> {
+    let (certificate_pem, private_key_pem) = match (certificate_path, private_key_path) {
+        (Some(certificate_path), Some(private_key_path))
+            if !certificate_path.exists() && !private_key_path.exists() =>
+        {
+            let (certificate_pem, private_key_pem) = generate_self_signed()?;
+            fs::write(certificate_path, &certificate_pem)?;
+            save_private_key(private_key_path, &private_key_pem)?;
+            log::info!(
+                "Generated a self-signed TLS certificate at {}",
+                certificate_path.display()
+            );
+
+            (certificate_pem, private_key_pem)
+        }
+        (Some(certificate_path), Some(private_key_path)) => (
+            fs::read_to_string(certificate_path)?,
+            fs::read_to_string(private_key_path)?,
+        ),
+        (None, None) => {
+            log::warn!("Using an ephemeral self-signed TLS certificate");
+            generate_self_signed()?
+        }
+        _ => anyhow::bail!("Both the TLS certificate and private key must be given"),
+    };
+
+    let certificates = pemfile::certs(&mut BufReader::new(certificate_pem.as_bytes()))
+        .map_err(|_| anyhow::anyhow!("Invalid TLS certificate"))?;
+    let private_key = parse_private_key(&private_key_pem)?;
+
+    let leaf = certificates
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No TLS certificate found"))?;
+    log::info!("TLS certificate fingerprint: {}", fingerprint(&leaf.0));
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certificates, private_key)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Generates a self-signed certificate for localhost, returning the PEM encoded certificate and
+/// private key
+fn generate_self_signed() -> Result<(String, String), anyhow::Error> {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+
+    Ok((
+        certificate.serialize_pem()?,
+        certificate.serialize_private_key_pem(),
+    ))
+}
+
+fn save_private_key(path: &Path, private_key_pem: &str) -> Result<(), anyhow::Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    // The key is secret, so only the owner may read it
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(private_key_pem.as_bytes())?;
+
+    Ok(())
+}
+
+/// Parses a PEM encoded PKCS#8 or RSA private key
+fn parse_private_key(private_key_pem: &str) -> Result<PrivateKey, anyhow::Error> {
+    let read_keys = |parse: fn(&mut dyn std::io::BufRead) -> Result<Vec<PrivateKey>, ()>| {
+        parse(&mut BufReader::new(private_key_pem.as_bytes()))
+            .map_err(|_| anyhow::anyhow!("Invalid TLS private key"))
+    };
+
+    read_keys(pemfile::pkcs8_private_keys)?
+        .into_iter()
+        .chain(read_keys(pemfile::rsa_private_keys)?)
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No TLS private key found"))
+}
+
+/// Accepts servers whose leaf certificate has the given SHA-256 fingerprint
+///
+/// Used to trust self-signed certificates, which can't be verified through the web PKI
+struct FingerprintVerifier {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        match presented_certs.first() {
+            Some(certificate) if Sha256::digest(&certificate.0)[..] == self.fingerprint[..] => {
+                Ok(ServerCertVerified::assertion())
+            }
+            _ => Err(TLSError::General(
+                "Server certificate doesn't match the pinned fingerprint".to_string(),
+            )),
+        }
+    }
+}
+
+/// Creates the transport an RPC client should use to reach the server at the given address
+///
+/// See [`client_address`](fn.client_address.html) for how TLS is handled. If an access token is
+/// given, it's sent along with every request
+///
+/// Must be called from within a Tokio runtime
+pub fn client_transport(
+    server_address: &str,
+    tls: bool,
+    fingerprint: Option<&str>,
+    access_token: Option<&str>,
+) -> Result<ClientTransport, anyhow::Error> {
+    let address = client_address(server_address, tls, fingerprint)?;
+
+    ClientTransport::new(&address, access_token)
+}
+
+/// Gets the address a client should connect to in plain text to reach the server at the given
+/// address
+///
+/// RPC clients only speak plain HTTP, so for TLS this starts a tunnel on a loopback port that
+/// forwards connections to the server over TLS, and returns the tunnel's address instead. The
+/// server is verified by the SHA-256 fingerprint of its certificate if one is given, which also
+/// enables TLS, and through the web PKI otherwise. The tunnel carries no credentials of its own,
+/// so local processes connecting to it have to authenticate with the server like any other client
+///
+/// Must be called from within a Tokio runtime
+pub fn client_address(
+    server_address: &str,
+    tls: bool,
+    fingerprint: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    if !tls && fingerprint.is_none() {
+        return Ok(server_address.to_string());
+    }
+
+    let host = server_address
+        .rsplitn(2, ':')
+        .last()
+        .unwrap_or(server_address)
+        .to_string();
+
+    let mut config = ClientConfig::new();
+    if let Some(fingerprint) = fingerprint {
+        let fingerprint = hex::decode(fingerprint.replace(':', ""))?;
+        anyhow::ensure!(
+            fingerprint.len() == 32,
+            "TLS fingerprints must be SHA-256 hashes"
+        );
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(FingerprintVerifier { fingerprint }));
+    } else {
+        DNSNameRef::try_from_ascii_str(&host).map_err(|_| {
+            anyhow::anyhow!(
+                "{} isn't a domain name. Pin the server's certificate fingerprint instead",
+                host
+            )
+        })?;
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    }
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let listener = StdTcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let tunnel_addr = listener.local_addr()?;
+    let mut listener = TcpListener::from_std(listener)?;
+    log::debug!(
+        "Tunneling RPC to {} through {}",
+        server_address,
+        tunnel_addr
+    );
+
+    let server_address = server_address.to_string();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    log::warn!("Failed to accept RPC tunnel connection: {}", error);
+                    continue;
+                }
+            };
+
+            let connector = connector.clone();
+            let server_address = server_address.clone();
+            let host = host.clone();
+            tokio::spawn(async move {
+                if let Err(error) = tunnel(stream, connector, &server_address, &host).await {
+                    log::warn!("RPC tunnel to {} failed: {}", server_address, error);
+                }
+            });
+        }
+    });
+
+    Ok(tunnel_addr.to_string())
+}
+
+/// Forwards a local connection to the server over TLS
+async fn tunnel(
+    local: TcpStream,
+    connector: TlsConnector,
+    server_address: &str,
+    host: &str,
+) -> Result<(), anyhow::Error> {
+    // Pinned certificates don't depend on the name, so any valid one will do for them
+    let dns_name = DNSNameRef::try_from_ascii_str(host)
+        .or_else(|_| DNSNameRef::try_from_ascii_str("localhost"))
+        .expect("localhost is a valid domain name");
+
+    let remote = TcpStream::connect(server_address).await?;
+    let remote = connector.connect(dns_name, remote).await?;
+
+    let (mut local_reader, mut local_writer) = tokio::io::split(local);
+    let (mut remote_reader, mut remote_writer) = tokio::io::split(remote);
+    futures::future::try_join(
+        tokio::io::copy(&mut local_reader, &mut remote_writer),
+        tokio::io::copy(&mut remote_reader, &mut local_writer),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_generated_certificates() {
+        let (certificate_pem, private_key_pem) = generate_self_signed().unwrap();
+
+        let certificates = pemfile::certs(&mut BufReader::new(certificate_pem.as_bytes())).unwrap();
+        assert_eq!(certificates.len(), 1);
+        assert!(parse_private_key(&private_key_pem).is_ok());
+        assert!(parse_private_key("").is_err());
+
+        assert_eq!(fingerprint(&certificates[0].0).len(), 64);
+    }
+
+    #[test]
+    fn saves_generated_certificates() {
+        let directory = std::env::temp_dir().join(format!("tls_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let certificate_path = directory.join("rpc.crt");
+        let private_key_path = directory.join("rpc.key");
+
+        load_server_config(Some(&certificate_path), Some(&private_key_path)).unwrap();
+        let private_key_pem = fs::read_to_string(&private_key_path).unwrap();
+        assert!(parse_private_key(&private_key_pem).is_ok());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(&private_key_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // The saved certificate is loaded from then on
+        load_server_config(Some(&certificate_path), Some(&private_key_path)).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn pins_fingerprints() {
+        let (certificate_pem, _) = generate_self_signed().unwrap();
+        let certificates = pemfile::certs(&mut BufReader::new(certificate_pem.as_bytes())).unwrap();
+
+        let verify = |fingerprint: Vec<u8>| {
+            FingerprintVerifier { fingerprint }.verify_server_cert(
+                &RootCertStore::empty(),
+                &certificates,
+                DNSNameRef::try_from_ascii_str("localhost").unwrap(),
+                &[],
+            )
+        };
+
+        assert!(verify(Sha256::digest(&certificates[0].0).to_vec()).is_ok());
+        assert!(verify(vec![0; 32]).is_err());
+    }
+}
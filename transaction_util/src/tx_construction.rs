@@ -8,10 +8,18 @@ use ensure_macro::ensure;
 use ringct::{Error as RingCTError, RingCTInput, RingCTOutput};
 
 use crate::{
-    account_keys::AccountKeys, address::AddressType, derivation::Derivation, payment_id,
-    subaddress, tx_scanning, TXDestination, TXDestinationType, TXSource,
+    account_keys::AccountKeys,
+    address::{Address, AddressType},
+    derivation::Derivation,
+    payment_id, subaddress, tx_scanning, TXDestination, TXDestinationType, TXSource,
 };
 
+/// Number of block deltas the outputs of coinbase transactions are locked for
+const MINER_TX_UNLOCK_DELTA: u16 = 3;
+
+/// Maximum number of bytes that can be reserved in the extra of a coinbase transaction
+pub const MAX_EXTRA_NONCE_SIZE: usize = 255;
+
 /// Error type for transaction construction
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -43,6 +51,10 @@ pub enum Error {
     #[error("Payment ID could not be encrypted")]
     PaymentIDEncryption,
 
+    /// Returned when the fee has to be paid out of the change but there is no change destination
+    #[error("Transaction has no change destination to pay the fee from")]
+    NoChangeDestination,
+
     /// Returned when there is an error when creating the RingCT signature
     #[error(transparent)]
     RingCT(#[from] RingCTError),
@@ -383,6 +395,93 @@ pub fn construct_tx(
     ))
 }
 
+/// Constructs a transaction like `construct_tx`, paying a fee of `fee_per_byte` for each of its
+/// bytes out of the change
+///
+/// Everything not paid to the other destinations goes to the change destination, minus the fee.
+/// The transaction is constructed once to measure its size and once more to pay the fee for it.
+/// Amounts don't affect the size of a transaction, so the fee is exact
+pub fn construct_tx_with_fee(
+    sender_keys: &AccountKeys,
+    sources: &mut [TXSource],
+    destinations: &mut [TXDestination],
+    unlock_delta: u16,
+    fee_per_byte: u64,
+) -> Result<(Transaction, Vec<Scalar>)> {
+    let is_change = |destination: &TXDestination| {
+        matches!(destination.destination_type, TXDestinationType::Change(_))
+    };
+
+    let in_amount = sources.iter().map(|source| source.amount).sum::<u64>();
+    let out_amount = destinations
+        .iter()
+        .map(|destination| destination.amount)
+        .sum::<u64>();
+    ensure!(out_amount <= in_amount, Error::ExcessSpending);
+
+    let change = destinations
+        .iter_mut()
+        .find(|destination| is_change(destination))
+        .ok_or(Error::NoChangeDestination)?;
+    change.amount += in_amount - out_amount;
+
+    let (transaction, _) = construct_tx(sender_keys, sources, destinations, unlock_delta)?;
+    let fee = transaction.size() as u64 * fee_per_byte;
+
+    // The destinations were shuffled, so the change has to be found again
+    let change = destinations
+        .iter_mut()
+        .find(|destination| is_change(destination))
+        .ok_or(Error::NoChangeDestination)?;
+    ensure!(change.amount >= fee, Error::ExcessSpending);
+    change.amount -= fee;
+
+    construct_tx(sender_keys, sources, destinations, unlock_delta)
+}
+
+/// Constructs the coinbase transaction of a block at the given height, paying the given amount
+/// to the miner's address
+///
+/// This is much simpler than `construct_tx` since there are fewer cases to deal with and the
+/// RingCT signature is unnecessary. If `extra_nonce_size` isn't zero, that many bytes are
+/// reserved at the end of the transaction's extra for the miner to fill in. It must not be
+/// larger than `MAX_EXTRA_NONCE_SIZE`
+pub fn construct_miner_tx(
+    height: u64,
+    amount: u64,
+    miner_address: &Address,
+    extra_nonce_size: usize,
+) -> Transaction {
+    // Generate a random keypair for this transaction
+    let tx_keypair = KeyPair::generate();
+
+    // Generate the transaction derivation and hence the output keypair
+    let target_keypair = Derivation::from(&tx_keypair.secret_key, &miner_address.view_public_key)
+        .expect("Generated secret keys are always canonical")
+        .to_keypair(0, miner_address.spend_public_key);
+
+    let mut extra = vec![TXExtra::TxPublicKey(tx_keypair.public_key)];
+    if extra_nonce_size != 0 {
+        extra.push(TXExtra::TxNonce(TXNonce::Extra(vec![0; extra_nonce_size])));
+    }
+
+    Transaction {
+        prefix: TransactionPrefix {
+            version: 1,
+            unlock_delta: MINER_TX_UNLOCK_DELTA,
+            inputs: vec![TXIn::Gen(height)],
+            outputs: vec![TXOut {
+                amount,
+                target: TXOutTarget::ToKey {
+                    key: target_keypair.public_key,
+                },
+            }],
+            extra,
+        },
+        rct_signature: None,
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use ringct::{Commitment, DestinationCommitmentPair};
@@ -547,4 +646,80 @@ pub mod tests {
 
         assert!(construct_tx(&sender_keys, &mut sources, &mut destinations, 4).is_err());
     }
+
+    #[test]
+    fn it_pays_fees_for_the_transaction_size() {
+        let sender_keys = AccountKeys::from(KeyPair::generate().secret_key);
+        let tx_keypair = KeyPair::generate();
+
+        let mut sources = vec![create_mock_source(
+            &tx_keypair,
+            &sender_keys,
+            SubAddressIndex(0, 0),
+            10_000_000,
+            10,
+        )];
+        let mut destinations = vec![
+            TXDestination {
+                amount: 1_000_000,
+                destination_type: TXDestinationType::PayToAddress(
+                    subaddress::get_address_for_index(&sender_keys, &SubAddressIndex(1, 0)),
+                ),
+            },
+            TXDestination {
+                amount: 0,
+                destination_type: TXDestinationType::Change(SubAddressIndex(1, 0)),
+            },
+        ];
+
+        let (tx, _) =
+            construct_tx_with_fee(&sender_keys, &mut sources, &mut destinations, 4, 2).unwrap();
+        assert_eq!(tx.fee(), tx.size() as u64 * 2);
+        ringct::verify_multiple(&[tx.rct_signature.unwrap()]).unwrap();
+
+        // The fee can't be paid without change
+        let mut destinations = vec![TXDestination {
+            amount: 1_000_000,
+            destination_type: TXDestinationType::PayToAddress(subaddress::get_address_for_index(
+                &sender_keys,
+                &SubAddressIndex(1, 0),
+            )),
+        }];
+        assert!(matches!(
+            construct_tx_with_fee(&sender_keys, &mut sources, &mut destinations, 4, 2),
+            Err(Error::NoChangeDestination)
+        ));
+    }
+
+    #[test]
+    fn it_creates_miner_transactions_correctly() {
+        let miner_keys = AccountKeys::from(KeyPair::generate().secret_key);
+        let miner_address = subaddress::get_address_for_index(&miner_keys, &SubAddressIndex(0, 0));
+
+        let tx = construct_miner_tx(10, 1000, &miner_address, 8);
+
+        assert!(matches!(tx.prefix.inputs[..], [TXIn::Gen(10)]));
+        assert_eq!(tx.prefix.outputs.len(), 1);
+        assert_eq!(tx.prefix.outputs[0].amount, 1000);
+        assert_eq!(tx.fee(), 0);
+
+        let tx_public_key = match tx.prefix.extra[..] {
+            [TXExtra::TxPublicKey(key), TXExtra::TxNonce(TXNonce::Extra(ref nonce))] => {
+                assert_eq!(nonce.len(), 8);
+                key
+            }
+            _ => panic!("Unexpected extra in miner transaction"),
+        };
+
+        // The output must reach the miner
+        let TXOutTarget::ToKey { key: output_key } = tx.prefix.outputs[0].target;
+        assert!(tx_scanning::get_key_image(
+            &miner_keys,
+            &SubAddressIndex(0, 0),
+            &output_key,
+            0,
+            &[tx_public_key],
+        )
+        .is_some());
+    }
 }
@@ -44,6 +44,10 @@ impl Account {
     pub fn mark_output_as_spent(&mut self, key_image: KeyImage) {
         self.unspent_outputs.remove(&key_image.compress());
     }
+    pub fn remove_outputs_above(&mut self, height: u64) {
+        self.unspent_outputs
+            .retain(|_, output| output.block_height <= height);
+    }
 }
 
 impl Default for Account {
@@ -85,9 +85,11 @@ impl Wallet {
 
 #[cfg(test)]
 mod tests {
-    use crypto::ScalarExt;
+    use crypto::{KeyPair, ScalarExt};
+    use ringct::Commitment;
 
     use super::*;
+    use account::UnspentOutput;
 
     #[test]
     fn it_works() {
@@ -128,4 +130,27 @@ mod tests {
             "8b66a0e272063786cc769c295486552e39797c57243612047bff9845c8cc66c8"
         );
     }
+
+    #[test]
+    fn rewinding_forgets_outputs_above_the_split() {
+        let mut w = Wallet::from_spend_secret_key(KeyPair::generate().secret_key);
+        for height in 1..=2 {
+            w.checked_blocks.insert(height, Hash256::null_hash());
+            w.accounts.get_mut(&0).unwrap().add_unspent_output(
+                KeyPair::generate().public_key,
+                UnspentOutput {
+                    commitment: Commitment::commit_to_value(10),
+                    block_height: height,
+                    minor_index: 0,
+                    payment_id: None,
+                    txid: Hash256::null_hash(),
+                },
+            );
+        }
+        assert_eq!(w.get_account(0).unwrap().get_balance(), 20);
+
+        w.rewind(1);
+        assert_eq!(*w.get_last_checked_block().0, 1);
+        assert_eq!(w.get_account(0).unwrap().get_balance(), 10);
+    }
 }
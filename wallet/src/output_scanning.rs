@@ -23,6 +23,18 @@ impl Wallet {
             .max_by(|(height_1, _), (height_2, _)| height_1.cmp(height_2))
             .unwrap()
     }
+    /// Forget the blocks above the given height, along with the outputs received in them
+    ///
+    /// Used when the daemon's chain switched over to an alternative chain splitting at the
+    /// given height. The blocks of the new chain are scanned on the next refresh
+    pub fn rewind(&mut self, split_height: u64) {
+        self.checked_blocks
+            .retain(|&height, _| height <= split_height);
+
+        for account in self.accounts.values_mut() {
+            account.remove_outputs_above(split_height);
+        }
+    }
     /// Scan a given block for transactions to the current wallet
     ///
     /// First scans the coinbase transaction, then all other transactions in the block
@@ -34,10 +46,7 @@ impl Wallet {
             self.checked_blocks.iter().find(|(_, id)| id == &&block_id)
         {
             // Remove all blocks at and above the split point
-            self.checked_blocks
-                .retain(|&height, _| height <= split_height);
-
-            // TODO: Remove unspent outputs from blocks above split
+            self.rewind(split_height.saturating_sub(1));
         }
 
         // Get the block height
@@ -118,6 +127,7 @@ impl Wallet {
                                 .unwrap(),
                             ));
                         }
+                        TXNonce::Extra(_) => {}
                     }
                 }
             }